@@ -27,5 +27,6 @@ int main() {
       triangle[2].b = float(frame%120)/120.0f;
 
       submit_drawlist(triangle, 3, indexes, 3);
+      present();
   }
 }
@@ -19,3 +19,63 @@ size_t file_size(char *filename) {
 size_t read_file(char* filename, size_t offset, size_t size, uint8_t *output) {
   return SYSCALL(4, reinterpret_cast<size_t>(filename), offset, size, reinterpret_cast<size_t>(output));
 }
+
+size_t open_file(char* filename) {
+  return SYSCALL(5, reinterpret_cast<size_t>(filename));
+}
+
+size_t read_handle(size_t handle, uint8_t *output, size_t size) {
+  return SYSCALL(6, handle, reinterpret_cast<size_t>(output), size);
+}
+
+size_t seek_file(size_t handle, int offset, size_t whence) {
+  return SYSCALL(7, handle, offset, whence);
+}
+
+size_t tell_file(size_t handle) {
+  return SYSCALL(8, handle);
+}
+
+size_t file_eof(size_t handle) {
+  return SYSCALL(9, handle);
+}
+
+size_t close_file(size_t handle) {
+  return SYSCALL(10, handle);
+}
+
+size_t open_directory(char* path) {
+  return SYSCALL(11, reinterpret_cast<size_t>(path));
+}
+
+size_t read_directory(size_t handle, DirectoryEntry *entry) {
+  return SYSCALL(12, handle, reinterpret_cast<size_t>(entry));
+}
+
+size_t close_directory(size_t handle) {
+  return SYSCALL(13, handle);
+}
+
+size_t stat_path(char* path, DirectoryEntry *entry) {
+  return SYSCALL(14, reinterpret_cast<size_t>(path), reinterpret_cast<size_t>(entry));
+}
+
+size_t filename(size_t file_index, char* output, size_t size) {
+  return SYSCALL(15, file_index, reinterpret_cast<size_t>(output), size);
+}
+
+size_t drive_status() {
+  return SYSCALL(16);
+}
+
+size_t current_disc() {
+  return SYSCALL(17);
+}
+
+size_t disc_count() {
+  return SYSCALL(18);
+}
+
+size_t drive_time() {
+  return SYSCALL(19);
+}
@@ -3,6 +3,12 @@
 #include "syscall.hpp"
 #include <stdint.h>
 
+struct DirectoryEntry {
+  uint32_t size;
+  uint32_t is_dir;
+  char name[256];
+};
+
 size_t file_count();
 
 size_t filename_length(size_t file_index);
@@ -13,4 +19,36 @@ size_t file_size(char* filename);
 
 size_t read_file(char* filename, size_t offset, size_t size, uint8_t* output);
 
+size_t open_file(char* filename);
+
+size_t read_handle(size_t handle, uint8_t* output, size_t size);
+
+size_t seek_file(size_t handle, int offset, size_t whence);
+
+size_t tell_file(size_t handle);
+
+size_t file_eof(size_t handle);
+
+size_t close_file(size_t handle);
+
+size_t open_directory(char* path);
+
+size_t read_directory(size_t handle, DirectoryEntry* entry);
+
+size_t close_directory(size_t handle);
+
+size_t stat_path(char* path, DirectoryEntry* entry);
+
+size_t filename(size_t file_index, char* output, size_t size);
+
+// 0 ready, 1 tray open, 2 disc changed since the last call
+size_t drive_status();
+
+size_t current_disc();
+
+size_t disc_count();
+
+// Microseconds the drive spent on reads since the last call, 0 without accurate timing
+size_t drive_time();
+
 #endif
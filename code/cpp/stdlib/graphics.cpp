@@ -17,4 +17,8 @@ Vertex::Vertex(float x, float y, float z, float w, float r, float g, float b, fl
 
 void submit_drawlist(Vertex *vertexList, size_t vertexCount, uint16_t *indexList, size_t indexCount) {
     SYSCALL(0x160, reinterpret_cast<size_t>(vertexList), vertexCount, reinterpret_cast<size_t>(indexList), indexCount);
+}
+
+void present() {
+    SYSCALL(0x161);
 }
\ No newline at end of file
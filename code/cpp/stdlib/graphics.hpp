@@ -18,4 +18,5 @@ struct Vertex {
 };
 
 void submit_drawlist(Vertex *vertexList, size_t vertexCount, uint16_t *indexList, size_t indexCount);
+void present();
 #endif
\ No newline at end of file
@@ -0,0 +1,86 @@
+// newlib system calls (the part libgloss provides on other boards) over the emulator's
+// newlib personality, syscalls 0x200 - 0x207. Linking this file is all newlib needs for
+// printf, malloc, fopen/fread and exit to work.
+#include <errno.h>
+#include <stddef.h>
+#include <sys/stat.h>
+#include "syscall.hpp"
+
+#undef errno
+extern int errno;
+
+// The personality returns a negative errno on failure
+static int check(size_t result) {
+  int value = (int)result;
+  if(value < 0 && value > -4096) {
+    errno = -value;
+    return -1;
+  }
+  return value;
+}
+
+extern "C" {
+
+void _exit(int code) {
+  SYSCALL(0x200, code);
+  while(1);
+}
+
+int _open(const char *path, int flags, int mode) {
+  return check(SYSCALL(0x201, (size_t)path, flags));
+}
+
+int _close(int fd) {
+  if(fd < 3) return 0;
+  return check(SYSCALL(0x202, fd));
+}
+
+int _read(int fd, char *buffer, int length) {
+  if(fd == 0) return 0;
+  return check(SYSCALL(0x203, fd, (size_t)buffer, length));
+}
+
+int _write(int fd, const char *buffer, int length) {
+  return check(SYSCALL(0x204, fd, (size_t)buffer, length));
+}
+
+int _lseek(int fd, int offset, int whence) {
+  if(fd < 3) {
+    errno = ESPIPE;
+    return -1;
+  }
+  return check(SYSCALL(0x205, fd, offset, whence));
+}
+
+int _fstat(int fd, struct stat *st) {
+  if(fd < 3) {
+    st->st_mode = S_IFCHR;
+    return 0;
+  }
+  int size = check(SYSCALL(0x206, fd));
+  if(size < 0) return -1;
+  st->st_mode = S_IFREG;
+  st->st_size = size;
+  return 0;
+}
+
+int _isatty(int fd) {
+  return fd < 3;
+}
+
+void *_sbrk(ptrdiff_t increment) {
+  size_t result = SYSCALL(0x207, increment);
+  if(check(result) == -1) return (void*)-1;
+  return (void*)result;
+}
+
+int _getpid() {
+  return 1;
+}
+
+int _kill(int pid, int sig) {
+  errno = EINVAL;
+  return -1;
+}
+
+}
@@ -42,6 +42,21 @@ size_t SYSCALL(size_t syscall, size_t arg1, size_t arg2) {
   return result;  
 }
 
+size_t SYSCALL(size_t syscall, size_t arg1, size_t arg2, size_t arg3) {
+  size_t result;
+
+  asm("@ 3 parameter syscall\n"
+      "mov r7, %[syscall]\n"
+      "mov r1, %[arg1]\n"
+      "mov r2, %[arg2]\n"
+      "mov r3, %[arg3]\n"
+      "swi #0\n"
+      "mov %[output], r0\n"
+      : [output] "=r" (result)
+      : [syscall] "r" (syscall), [arg1] "r" (arg1), [arg2] "r" (arg2), [arg3] "r" (arg3)
+      : "r7", "r0", "r1", "r2", "r3");
+  return result;  
+}
 
 size_t SYSCALL(size_t syscall, size_t arg1, size_t arg2, size_t arg3, size_t arg4) {
   size_t result;
@@ -0,0 +1,6 @@
+#include "system.hpp"
+#include "syscall.hpp"
+
+void exit_emulator(size_t code) {
+  SYSCALL(0x100, code);
+}
@@ -0,0 +1,6 @@
+#ifndef __SYSTEM_HPP
+#define __SYSTEM_HPP
+#include <stddef.h>
+
+void exit_emulator(size_t code);
+#endif
@@ -0,0 +1,74 @@
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeSet;
+use std::rc::Rc;
+use unicorn::UnicornHandle;
+use unicorn::ffi::uc_hook;
+use unicorn::unicorn_const::uc_error;
+
+/// Software breakpoints implemented through a code hook
+///
+/// The hook stops the emulator when it reaches one of the addresses. Used by both
+/// the [GDB stub](crate::gdb::GdbStub) and the [debugger REPL](crate::debugger::Debugger).
+pub struct Breakpoints {
+    addresses: Rc<RefCell<BTreeSet<u64>>>,
+    hit: Rc<Cell<bool>>,
+    skip: Rc<Cell<bool>>,
+    hook: uc_hook,
+}
+
+impl Breakpoints {
+    pub fn install(emu: &mut UnicornHandle) -> Result<Breakpoints, uc_error> {
+        let addresses = Rc::new(RefCell::new(BTreeSet::new()));
+        let hit = Rc::new(Cell::new(false));
+        let skip = Rc::new(Cell::new(false));
+
+        let hook = {
+            let addresses = addresses.clone();
+            let hit = hit.clone();
+            let skip = skip.clone();
+            emu.add_code_hook(1, 0, move |mut emu, address, _size| {
+                if skip.replace(false) {
+                    return;
+                }
+                if addresses.borrow().contains(&address) {
+                    hit.set(true);
+                    emu.emu_stop().unwrap();
+                }
+            })?
+        };
+
+        Ok(Breakpoints { addresses, hit, skip, hook })
+    }
+
+    /// Returns false if there already was a breakpoint at `address`
+    pub fn insert(&self, address: u64) -> bool {
+        self.addresses.borrow_mut().insert(address)
+    }
+
+    pub fn remove(&self, address: u64) -> bool {
+        self.addresses.borrow_mut().remove(&address)
+    }
+
+    pub fn clear(&self) {
+        self.addresses.borrow_mut().clear();
+    }
+
+    pub fn list(&self) -> Vec<u64> {
+        self.addresses.borrow().iter().copied().collect()
+    }
+
+    /// Whether a breakpoint stopped the emulator since the last call
+    pub fn take_hit(&self) -> bool {
+        self.hit.replace(false)
+    }
+
+    /// Ignores the next executed instruction, which is where the CPU resumes from
+    /// and may itself be a breakpoint
+    pub fn skip_next(&self) {
+        self.skip.set(true);
+    }
+
+    pub fn remove_hook(&mut self, emu: &mut UnicornHandle) -> Result<(), uc_error> {
+        emu.remove_hook(self.hook)
+    }
+}
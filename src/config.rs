@@ -0,0 +1,396 @@
+use std::fs;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use serde::Deserialize;
+use toml::Value;
+use crate::error::EmulatorError;
+use crate::filesystem::cue::CueSheet;
+use crate::filesystem::image::{self, SECTOR_SIZE};
+use crate::gpu::{Hotkey, KEY_NAMES};
+use crate::syscalls::UnknownSyscall;
+
+/// Where things live in guest memory
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MemoryConfig {
+    /// Initial stack pointer, the stack grows down from here
+    pub stack_top: u64,
+    /// Bytes mapped below `stack_top`, the page under them is left as a guard
+    pub stack_size: u64,
+    /// Page the console output register lives in
+    pub console_address: u64,
+    /// Where dynamic allocations start, defaults to the first 4MB boundary after the executable
+    pub heap_base: Option<u64>,
+}
+
+impl Default for MemoryConfig {
+    fn default() -> Self {
+        MemoryConfig {
+            stack_top: 0x0800_0000,
+            stack_size: 0x10_0000,
+            console_address: 0xFF000,
+            heap_base: None,
+        }
+    }
+}
+
+/// Which features the machine is built with
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FeatureConfig {
+    pub console: bool,
+    pub drive: bool,
+    pub dynamic_memory: bool,
+    pub system: bool,
+    /// Without the GPU feature no window is opened
+    pub gpu: bool,
+    /// ARM semihosting, off by default
+    pub semihosting: bool,
+}
+
+impl Default for FeatureConfig {
+    fn default() -> Self {
+        FeatureConfig {
+            console: true,
+            drive: true,
+            dynamic_memory: true,
+            system: true,
+            gpu: true,
+            semihosting: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VideoConfig {
+    /// `euc` or `wgpu`, the first available backend if unset
+    pub backend: Option<String>,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Default for VideoConfig {
+    fn default() -> Self {
+        VideoConfig {
+            backend: None,
+            width: 800,
+            height: 600,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SemihostingConfig {
+    /// Host directory the guest opens files in, the disc (read-only) if unset
+    pub root: Option<String>,
+    /// Returned by SYS_GET_CMDLINE
+    pub cmdline: String,
+}
+
+impl Default for SemihostingConfig {
+    fn default() -> Self {
+        SemihostingConfig {
+            root: None,
+            cmdline: String::from("main.elf"),
+        }
+    }
+}
+
+/// Optical drive timing. Off by default, reads are instant
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DriveConfig {
+    /// Charge seek and transfer time to reads, stalling the guest for as many frames
+    pub accurate_timing: bool,
+    /// Milliseconds to seek to a sector next to the head
+    pub seek_ms: u64,
+    /// Milliseconds to seek across the whole disc
+    pub full_seek_ms: u64,
+    /// Transfer rate, 75 for a 1x CD-ROM drive
+    pub sectors_per_second: u64,
+}
+
+impl Default for DriveConfig {
+    fn default() -> Self {
+        DriveConfig {
+            accurate_timing: false,
+            seek_ms: 80,
+            full_seek_ms: 300,
+            sectors_per_second: 150,
+        }
+    }
+}
+
+/// Host window keys for the emulator hotkeys, one of [KEY_NAMES]
+///
+/// Only the hotkeys can be bound, the guest has no controller input yet (see [crate::input]).
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeyBindings {
+    pub save_state: String,
+    pub load_state: String,
+    pub reset: String,
+    /// Opens the drive tray, or inserts the next disc when it's open
+    pub swap_disc: String,
+}
+
+impl Default for KeyBindings {
+    fn default() -> Self {
+        KeyBindings {
+            save_state: String::from("F5"),
+            load_state: String::from("F9"),
+            reset: String::from("F1"),
+            swap_disc: String::from("F2"),
+        }
+    }
+}
+
+impl KeyBindings {
+    pub fn hotkeys(&self) -> [(&str, Hotkey); 4] {
+        [
+            (self.save_state.as_str(), Hotkey::SaveState),
+            (self.load_state.as_str(), Hotkey::LoadState),
+            (self.reset.as_str(), Hotkey::Reset),
+            (self.swap_disc.as_str(), Hotkey::SwapDisc),
+        ]
+    }
+}
+
+/// How a [crate::Machine] is put together
+///
+/// Usually read from a TOML file, every key is optional:
+///
+/// ```toml
+/// time_slice = 16
+/// unknown_syscall = "error"  # or "trap"
+/// overlays = ["translation", "hotfix"]
+/// patches = ["main.elf=fix.bps"]
+/// discs = ["game-disc2.iso"]
+///
+/// [memory]
+/// stack_top = 0x8000000
+/// stack_size = 0x100000
+/// console_address = 0xFF000
+///
+/// [features]
+/// gpu = false
+/// semihosting = true
+///
+/// [semihosting]
+/// root = "host-files"
+/// cmdline = "main.elf --verbose"
+///
+/// [drive]
+/// accurate_timing = true
+/// seek_ms = 80
+/// full_seek_ms = 300
+/// sectors_per_second = 150
+///
+/// [video]
+/// backend = "euc"
+/// width = 800
+/// height = 600
+///
+/// [keys]
+/// save_state = "F5"
+/// load_state = "F9"
+/// reset = "F1"
+/// swap_disc = "F2"
+///
+/// # overrides for the disc whose CRC32 is 1a2b3c4d
+/// [games.1a2b3c4d.video]
+/// width = 640
+/// height = 480
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MachineConfig {
+    pub memory: MemoryConfig,
+    pub features: FeatureConfig,
+    pub video: VideoConfig,
+    pub keys: KeyBindings,
+    pub semihosting: SemihostingConfig,
+    pub drive: DriveConfig,
+    pub unknown_syscall: UnknownSyscall,
+    /// Longest time in milliseconds the guest runs before yielding if it doesn't present a frame
+    pub time_slice: u64,
+    /// Host directories layered over the disc, their files shadow the ones of the disc. Later
+    /// directories go on top of earlier ones
+    pub overlays: Vec<String>,
+    /// Patches applied when the disc is inserted, `[target=]file[@crc32]` like `--patch`
+    pub patches: Vec<String>,
+    /// The other discs of a multi-disc game, in order after the boot disc. Overlays and
+    /// patches only go over the boot disc
+    pub discs: Vec<String>,
+}
+
+impl Default for MachineConfig {
+    fn default() -> Self {
+        MachineConfig {
+            memory: MemoryConfig::default(),
+            features: FeatureConfig::default(),
+            video: VideoConfig::default(),
+            keys: KeyBindings::default(),
+            semihosting: SemihostingConfig::default(),
+            drive: DriveConfig::default(),
+            unknown_syscall: UnknownSyscall::Error,
+            time_slice: 16,
+            overlays: Vec::new(),
+            patches: Vec::new(),
+            discs: Vec::new(),
+        }
+    }
+}
+
+/// Copies every key of `overrides` into `base`, merging tables instead of replacing them
+fn merge(base: &mut Value, overrides: Value) {
+    match (base, overrides) {
+        (Value::Table(base), Value::Table(overrides)) => {
+            for (key, value) in overrides {
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => { base.insert(key, value); }
+                }
+            }
+        }
+        (base, overrides) => *base = overrides,
+    }
+}
+
+impl MachineConfig {
+    /// Parses a configuration, applying the `[games.<disc_hash>]` overrides of the disc image at
+    /// `disc` if there are any
+    ///
+    /// The image is only hashed when the configuration has overrides.
+    pub fn parse(text: &str, disc: Option<&Path>) -> Result<MachineConfig, EmulatorError> {
+        let invalid = |e: toml::de::Error| EmulatorError::InvalidConfig(format!("{}", e));
+        let mut value: Value = text.parse().map_err(invalid)?;
+
+        let games = value.as_table_mut()
+            .and_then(|table| table.remove("games"));
+        let overrides = match (games, disc) {
+            (Some(Value::Table(mut games)), Some(disc)) if !games.is_empty() => games.remove(&disc_hash(disc)?),
+            (Some(Value::Table(_)), _) | (None, _) => None,
+            (Some(_), _) => return Err(EmulatorError::InvalidConfig(String::from("games must be a table"))),
+        };
+        if let Some(overrides) = overrides {
+            merge(&mut value, overrides);
+        }
+
+        let config: MachineConfig = value.try_into().map_err(invalid)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path, disc: Option<&Path>) -> Result<MachineConfig, EmulatorError> {
+        Self::parse(fs::read_to_string(path)?.as_str(), disc)
+    }
+
+    /// Checks the values deserialization can't, for configurations built without [parse]
+    ///
+    /// [parse]: MachineConfig::parse
+    pub fn validate(&self) -> Result<(), EmulatorError> {
+        for (key, _) in self.keys.hotkeys() {
+            if !KEY_NAMES.contains(&key) {
+                return Err(EmulatorError::InvalidConfig(format!("unknown key {}, expected one of {}", key, KEY_NAMES.join(", "))));
+            }
+        }
+        if self.drive.sectors_per_second == 0 || self.drive.full_seek_ms < self.drive.seek_ms {
+            return Err(EmulatorError::InvalidConfig(String::from("sectors_per_second can't be 0 and full_seek_ms can't be shorter than seek_ms")));
+        }
+        if self.video.width == 0 || self.video.height == 0 {
+            return Err(EmulatorError::InvalidConfig(String::from("resolution can't be empty")));
+        }
+        let memory = &self.memory;
+        if memory.stack_top % 0x1000 != 0 || memory.stack_size % 0x1000 != 0 {
+            return Err(EmulatorError::InvalidConfig(String::from("stack_top and stack_size must be page aligned")));
+        }
+        if memory.stack_size == 0 || memory.stack_size.checked_add(0x1000).map_or(true, |size| size > memory.stack_top) {
+            return Err(EmulatorError::InvalidConfig(String::from("the stack and its guard page must fit below stack_top")));
+        }
+        if memory.console_address % 0x1000 != 0 {
+            return Err(EmulatorError::InvalidConfig(String::from("console_address must be page aligned")));
+        }
+        Ok(())
+    }
+}
+
+/// CRC32 of a disc image as 8 lowercase hex digits, the key of its `[games]` overrides
+///
+/// A cue sheet is hashed as the bytes of its data track, what dump databases list for it.
+pub fn disc_hash(path: &Path) -> Result<String, EmulatorError> {
+    let mut header = Vec::new();
+    File::open(path)?.take(SECTOR_SIZE).read_to_end(&mut header)?;
+    let (path, range) = if image::is_cue_sheet(&header) {
+        let sheet = CueSheet::parse(path)?;
+        let (track, range) = sheet.data_track()?;
+        (track.file.clone(), range)
+    } else {
+        (path.to_path_buf(), 0..fs::metadata(path)?.len())
+    };
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(range.start))?;
+    let mut file = file.take(range.end - range.start);
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buffer = vec![0u8; 1 << 20];
+    loop {
+        let count = file.read(&mut buffer)?;
+        if count == 0 {
+            break;
+        }
+        hasher.update(&buffer[..count]);
+    }
+    Ok(format!("{:08x}", hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn game_overrides_merge() {
+        let disc = std::env::temp_dir().join(format!("armchine-config-disc-{}.iso", std::process::id()));
+        fs::write(&disc, b"not really a disc").unwrap();
+        let hash = disc_hash(&disc).unwrap();
+        assert_eq!(hash, format!("{:08x}", crc32fast::hash(b"not really a disc")));
+
+        let text = format!("[video]\nwidth = 1024\nheight = 768\n\n[games.{}.video]\nwidth = 640\n\n[games.00000000]\ntime_slice = 1\n", hash);
+        let config = MachineConfig::parse(&text, Some(&disc)).unwrap();
+        assert_eq!(config.video.width, 640);
+        assert_eq!(config.video.height, 768);
+        assert_eq!(config.time_slice, 16);
+
+        let config = MachineConfig::parse(&text, None).unwrap();
+        assert_eq!(config.video.width, 1024);
+        fs::remove_file(&disc).unwrap();
+    }
+
+    #[test]
+    fn disc_is_only_hashed_for_overrides() {
+        let missing = Path::new("/nonexistent/armchine-config-disc.iso");
+        assert!(MachineConfig::parse("time_slice = 8", Some(missing)).is_ok());
+        assert!(MachineConfig::parse("[games.1a2b3c4d]\ntime_slice = 8", Some(missing)).is_err());
+    }
+
+    #[test]
+    fn typos_are_rejected() {
+        for text in ["time_slcie = 8", "[video]\nwidht = 640", "[keys]\nsave = \"F5\""] {
+            assert!(matches!(MachineConfig::parse(text, None), Err(EmulatorError::InvalidConfig(_))), "{}", text);
+        }
+
+        let disc = std::env::temp_dir().join(format!("armchine-config-typo-{}.iso", std::process::id()));
+        fs::write(&disc, b"").unwrap();
+        let text = format!("[games.{}.drive]\nseek = 1", disc_hash(&disc).unwrap());
+        assert!(matches!(MachineConfig::parse(&text, Some(&disc)), Err(EmulatorError::InvalidConfig(_))));
+        fs::remove_file(&disc).unwrap();
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        assert!(MachineConfig::parse("[keys]\nreset = \"F13\"", None).is_err());
+        assert!(MachineConfig::parse("[keys]\nreset = \"F3\"", None).is_ok());
+    }
+}
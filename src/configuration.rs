@@ -1,26 +1,151 @@
-use crate::{console, dynmemory, EmulatorFeature, filesystem, gpu};
+use std::path::Path;
+use armchine_rs::MachineConfig;
+use armchine_rs::error::EmulatorError;
+use armchine_rs::syscalls::UnknownSyscall;
 use clap::Parser;
 use clap;
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about)]
 pub struct Arguments {
+    /// Disc image to boot (ISO, BIN/CUE, raw sector dump or CSO), or a directory served as the disc
     #[clap(long)]
     pub iso: String,
 
+    /// Layer this host directory over the disc, its files shadow the disc's. Can be repeated,
+    /// later overlays go on top
+    #[clap(long)]
+    pub overlay: Vec<String>,
+
+    /// Another disc of a multi-disc game, swapped in with the swap disc hotkey (F2). Can be
+    /// repeated, discs are numbered in order after the boot disc in --iso
+    #[clap(long)]
+    pub disc: Vec<String>,
+
+    /// Charge seek and transfer time to disc reads, stalling the guest like a real drive would.
+    /// The timings are in the [drive] section of the configuration
+    #[clap(long)]
+    pub accurate_drive: bool,
+
+    /// Apply an IPS, BPS or VCDIFF patch to the disc image, or to a file on the disc with
+    /// <path>=<patch> (e.g. main.elf=fix.bps). A trailing @<crc32> refuses to patch anything
+    /// else than the file with that CRC32. Can be repeated, patches apply in order
+    #[clap(long)]
+    pub patch: Vec<String>,
+
     #[clap(short, long)]
     pub debug: bool,
 
     #[clap(long)]
-    pub gpu_backend: Option<String>
+    pub gpu_backend: Option<String>,
+
+    /// Run without creating the GPU feature (and thus without opening a window)
+    #[clap(long)]
+    pub headless: bool,
+
+    /// Stop after the guest presented this many frames
+    #[clap(long)]
+    pub max_frames: Option<u64>,
+
+    /// Stop after (roughly) this many executed instructions
+    #[clap(long)]
+    pub max_instructions: Option<u64>,
+
+    /// Restore this save state before running
+    #[clap(long)]
+    pub load_state: Option<String>,
+
+    /// Where the save state hotkey (F5) writes to, defaults to <iso>.state. F9 loads it back
+    #[clap(long)]
+    pub save_state: Option<String>,
+
+    /// Wait for a GDB remote connection on this local port before running
+    #[clap(long)]
+    pub gdb: Option<u16>,
+
+    /// Pause at the entry point and on crashes with an interactive debugger on the terminal
+    #[clap(long, conflicts_with = "gdb")]
+    pub debugger: bool,
+
+    /// Write an execution trace to this file
+    #[clap(long)]
+    pub trace: Option<String>,
+
+    /// Trace basic blocks instead of single instructions. Much faster, but no disassembly or registers
+    #[clap(long)]
+    pub trace_blocks: bool,
+
+    /// Only trace addresses in this hex range (e.g. 0x8000-0x8100). Can be repeated
+    #[clap(long)]
+    pub trace_range: Vec<String>,
+
+    /// Only trace this function from the ELF symbol table. Can be repeated
+    #[clap(long)]
+    pub trace_function: Vec<String>,
+
+    /// Profile the guest and write folded stacks (for flamegraph.pl/inferno) to this file
+    #[clap(long)]
+    pub profile: Option<String>,
+
+    /// Also write the crash report to this file when the guest crashes
+    #[clap(long)]
+    pub crash_report: Option<String>,
+
+    /// Longest time in milliseconds the guest runs before yielding to the host if it doesn't present a frame
+    #[clap(long)]
+    pub time_slice: Option<u64>,
+
+    /// What to do with syscalls no feature handles: `error` returns -1 in R0, `trap` stops the guest
+    #[clap(long)]
+    pub unknown_syscall: Option<String>,
+
+    /// Machine configuration file, defaults to armchine.toml if there is one. Options given on
+    /// the command line override it
+    #[clap(long)]
+    pub config: Option<String>,
 }
 
-pub fn get_features(args: &Arguments, mem_sz: u64) -> Vec<Box<dyn EmulatorFeature>> {
-    let mut features = Vec::<Box<dyn EmulatorFeature>>::new();
-    features.push(Box::new(console::ConsoleIO::new()));
-    features.push(Box::new(filesystem::EmulatorDrive::new(String::from(&args.iso))));
-    features.push(Box::new(dynmemory::DynamicMemoryAllocations::new(mem_sz)));
-    #[cfg(feature = "gpu-feature")]
-        features.push(gpu::create_feature(&args.gpu_backend));
-    features
+const DEFAULT_CONFIG: &str = "armchine.toml";
+
+fn unknown_syscall(name: &str) -> Result<UnknownSyscall, EmulatorError> {
+    match name {
+        "error" => Ok(UnknownSyscall::Error),
+        "trap" => Ok(UnknownSyscall::Trap),
+        other => Err(EmulatorError::InvalidConfig(format!("unknown --unknown-syscall behaviour {}, expected error or trap", other))),
+    }
+}
+
+/// Reads the configuration file, with the overrides for the disc in `--iso`, and applies the
+/// command line options on top of it
+pub fn machine_config(args: &Arguments) -> Result<MachineConfig, EmulatorError> {
+    let path = args.config.clone()
+        .or_else(|| Path::new(DEFAULT_CONFIG).exists().then(|| String::from(DEFAULT_CONFIG)));
+    let mut config = match path {
+        Some(path) => {
+            // A directory has no image to hash, so no per-game overrides
+            let disc = Some(Path::new(&args.iso)).filter(|iso| !iso.is_dir());
+            MachineConfig::from_file(path.as_ref(), disc)?
+        }
+        None => MachineConfig::default(),
+    };
+
+    if args.headless {
+        config.features.gpu = false;
+    }
+    if let Some(backend) = &args.gpu_backend {
+        config.video.backend = Some(backend.clone());
+    }
+    if let Some(time_slice) = args.time_slice {
+        config.time_slice = time_slice;
+    }
+    config.overlays.extend(args.overlay.iter().cloned());
+    config.patches.extend(args.patch.iter().cloned());
+    config.discs.extend(args.disc.iter().cloned());
+    if args.accurate_drive {
+        config.drive.accurate_timing = true;
+    }
+    if let Some(name) = &args.unknown_syscall {
+        config.unknown_syscall = unknown_syscall(name)?;
+    }
+    Ok(config)
 }
@@ -4,43 +4,67 @@ use libc::size_t;
 use unicorn::unicorn_const::{HookType, Permission};
 use std::any::Any;
 use unicorn::ffi::uc_hook;
-use crate::features::EmulatorFeature;
+use crate::features::{EmulatorFeature, FeatureDescriptor};
+use crate::error::EmulatorError;
+use std::io::Write;
+use unicorn::RegisterARM;
+use crate::newlib;
+use crate::syscalls::{read_guest_memory, SyscallDispatcher};
 
 /// Console Text IO
 ///
 /// This feature provides memory hooks to write to emulator stdout
 pub struct ConsoleIO {
     hook: uc_hook,
+    address: u64,
 }
 
 impl ConsoleIO {
-    pub(crate) fn new() -> ConsoleIO {
-        ConsoleIO { hook: null_mut() }
+    /// `address` is the page holding the output register, see [crate::config::MemoryConfig]
+    pub(crate) fn new(address: u64) -> ConsoleIO {
+        ConsoleIO { hook: null_mut(), address }
     }
 }
 
 /// | Memory address | Parameters | Description |
 /// | -------------- | ---------- | ----------- |
-/// | 0xFF000 | byte | Writes byte to stdout |
+/// | 0xFF000 (configurable) | byte | Writes byte to stdout |
+///
+/// | Syscall | Parameters | Description |
+/// | ------- | ---------- | ----------- |
+/// | 0x204 | int: descriptor, void*: data, int: length | newlib `write` to stdout (1) or stderr (2), returns the length |
 impl EmulatorFeature for ConsoleIO {
-    fn init(&mut self, emulator: &mut UnicornHandle) -> Result<(), String> {
-        emulator.mem_map(0xFF000, 4096 as size_t, Permission::ALL).unwrap();
-        match emulator.add_mem_hook(HookType::MEM_ALL, 0xFF000, 0xFF001, |_emu, _memtype, _idx, _size, value| {
+    fn init(&mut self, emulator: &mut UnicornHandle, syscalls: &mut SyscallDispatcher) -> Result<(), EmulatorError> {
+        syscalls.register(&self.name(), newlib::syscall(newlib::WRITE), |em, _syscall| {
+            let descriptor = em.reg_read(RegisterARM::R1 as i32)?;
+            let address = em.reg_read(RegisterARM::R2 as i32)?;
+            let length = em.reg_read(RegisterARM::R3 as i32)? as usize;
+            let result = read_guest_memory(em, address, length)
+                .map_err(newlib::errno)
+                .and_then(|data| {
+                    let written = match descriptor {
+                        1 => std::io::stdout().write_all(&data).and_then(|_| std::io::stdout().flush()),
+                        2 => std::io::stderr().write_all(&data),
+                        _ => return Err(newlib::EBADF),
+                    };
+                    written.map(|_| length as u32).map_err(|_| newlib::EIO)
+                });
+            newlib::set_result(em, result)
+        })?;
+
+
+        emulator.mem_map(self.address, 4096 as size_t, Permission::ALL)?;
+        self.hook = emulator.add_mem_hook(HookType::MEM_ALL, self.address, self.address + 1, |_emu, _memtype, _idx, _size, value| {
             print!("{}", (value as u8) as char);
-        }) {
-            Ok(r) => {
-                self.hook = r;
-                Ok(())
-            }
-            Err(err) => { Err(format!("{:?}", err)) }
-        }
+        })?;
+        Ok(())
     }
 
-    fn stop(&mut self, emulator: &mut UnicornHandle) -> Result<(), String> {
-        emulator.mem_unmap(0xFF000, 4096).unwrap();
-        let r = emulator.remove_hook(self.hook).map_err(|e| format!("{:?}", e));
+    fn stop(&mut self, emulator: &mut UnicornHandle) -> Result<(), EmulatorError> {
+        emulator.mem_unmap(self.address, 4096)?;
+        let r = emulator.remove_hook(self.hook);
         self.hook = null_mut();
-        r
+        Ok(r?)
     }
 
     fn as_any(&mut self) -> &mut dyn Any {
@@ -50,4 +74,8 @@ impl EmulatorFeature for ConsoleIO {
     fn name(&self) -> String {
         "ConsoleIO".parse().unwrap()
     }
+
+    fn descriptor(&self) -> FeatureDescriptor {
+        FeatureDescriptor { syscalls: vec![newlib::syscall(newlib::WRITE)], mmio: vec![self.address..self.address + 4096] }
+    }
 }
@@ -0,0 +1,98 @@
+use std::cell::Cell;
+use std::fmt::Write;
+use std::ops::Range;
+use std::rc::Rc;
+use unicorn::{RegisterARM, UnicornHandle};
+use unicorn::ffi::uc_hook;
+use unicorn::unicorn_const::{HookType, MemType, uc_error};
+use crate::debugger::backtrace;
+use crate::emulator::{CORE_REGISTERS, is_stack_overflow};
+use crate::symbols::SymbolTable;
+
+/// An access to unmapped or protected memory
+#[derive(Debug, Copy, Clone)]
+pub struct Fault {
+    pub kind: MemType,
+    pub address: u64,
+    pub size: usize,
+}
+
+/// Remembers the last invalid memory access through a MEM_INVALID hook
+///
+/// `emu_start` only reports the kind of error, this is where the faulting address comes from.
+pub struct FaultRecorder {
+    hook: uc_hook,
+    last: Rc<Cell<Option<Fault>>>,
+}
+
+impl FaultRecorder {
+    pub fn install(emu: &mut UnicornHandle) -> Result<FaultRecorder, uc_error> {
+        let last = Rc::new(Cell::new(None));
+        let hook_last = last.clone();
+        let hook = emu.add_mem_hook(HookType::MEM_INVALID, 1, 0, move |_emu, kind, address, size, _value| {
+            hook_last.set(Some(Fault { kind, address, size }));
+        })?;
+        Ok(FaultRecorder { hook, last })
+    }
+
+    /// The invalid access recorded since the last call, if any
+    pub fn take(&self) -> Option<Fault> {
+        self.last.take()
+    }
+
+    pub fn stop(&mut self, emu: &mut UnicornHandle) -> Result<(), uc_error> {
+        emu.remove_hook(self.hook)
+    }
+}
+
+/// Builds a human readable report of a guest crash
+///
+/// Contains the error, the faulting access (if it was a memory error), every register,
+/// symbolised PC and LR, a backtrace and a hexdump of the stack around SP. Faults caused by
+/// the stack growing out of `stack` are reported as a stack overflow.
+pub fn crash_report(emu: &UnicornHandle, error: uc_error, fault: Option<Fault>, stack: &Range<u64>, symbols: &SymbolTable) -> String {
+    let mut report = String::new();
+    let register = |register: RegisterARM| emu.reg_read(register as i32).unwrap_or(0);
+
+    let sp = register(RegisterARM::SP);
+    if fault.map_or(false, |fault| is_stack_overflow(stack, sp, fault.address)) {
+        writeln!(report, "Stack overflow at PC={}", symbols.describe(register(RegisterARM::PC))).unwrap();
+    }
+    writeln!(report, "Guest crashed: {:?}", error).unwrap();
+    if let Some(fault) = fault {
+        writeln!(report, "Faulting access: {:?} of {} bytes at {:#010x}", fault.kind, fault.size, fault.address).unwrap();
+    }
+    writeln!(report, "PC: {}", symbols.describe(register(RegisterARM::PC))).unwrap();
+    writeln!(report, "LR: {}", symbols.describe(register(RegisterARM::LR))).unwrap();
+
+    writeln!(report, "\nRegisters:").unwrap();
+    for (i, (name, reg)) in CORE_REGISTERS.iter().enumerate() {
+        write!(report, "{:>5} {:#010x}", name, register(*reg)).unwrap();
+        if i % 4 == 3 {
+            writeln!(report).unwrap();
+        }
+    }
+    writeln!(report, " spsr {:#010x}", register(RegisterARM::SPSR)).unwrap();
+
+    writeln!(report, "\nBacktrace:").unwrap();
+    for (i, address) in backtrace(emu, symbols, 256).iter().enumerate() {
+        writeln!(report, "#{:<3} {}", i, symbols.describe(*address)).unwrap();
+    }
+
+    writeln!(report, "\nStack:").unwrap();
+    let start = sp.saturating_sub(64) & !0xF;
+    for row in (start..start + 256).step_by(16) {
+        let mut bytes = [0u8; 16];
+        if emu.mem_read(row, &mut bytes).is_err() {
+            writeln!(report, "{:#010x}: <unmapped>", row).unwrap();
+            continue;
+        }
+        let hex: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        let ascii: String = bytes.iter()
+            .map(|b| if b.is_ascii_graphic() || *b == b' ' { *b as char } else { '.' })
+            .collect();
+        let marker = if row <= sp && sp < row + 16 { "<- sp" } else { "" };
+        writeln!(report, "{:#010x}: {}  {} {}", row, hex.join(" "), ascii, marker).unwrap();
+    }
+    report
+}
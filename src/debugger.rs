@@ -0,0 +1,266 @@
+use std::io::{BufRead, Write};
+use std::rc::Rc;
+use capstone::Capstone;
+use unicorn::{RegisterARM, UnicornHandle};
+use unicorn::unicorn_const::uc_error;
+use crate::breakpoints::Breakpoints;
+use crate::emulator;
+use crate::emulator::{is_call, CORE_REGISTERS};
+use crate::symbols::SymbolTable;
+
+const HELP: &str = "\
+break <symbol|addr>   stop when execution reaches the address
+delete <symbol|addr>  remove a breakpoint
+step                  execute one instruction
+next                  execute one instruction, stepping over calls
+continue              run until a breakpoint or a crash
+regs                  print the registers
+x/<n> <symbol|addr>   dump n words of memory
+disas [symbol|addr]   disassemble around the address (default: pc)
+bt                    print a backtrace
+quit                  stop the emulator";
+
+/// Most words `x/<n>` dumps at once
+const MAX_DUMP_WORDS: u64 = 0x1000;
+
+/// Return addresses of the current call stack, innermost first
+///
+/// Starts with PC and LR and then scans up to `depth` words up from SP for values that point
+/// right after a call instruction inside a known function. Code built without frame pointers
+/// leaves nothing better to walk, so stale return addresses can show up.
+pub fn backtrace(emu: &UnicornHandle, symbols: &SymbolTable, depth: usize) -> Vec<u64> {
+    let pc = emu.reg_read(RegisterARM::PC as i32).unwrap_or(0);
+    let lr = emu.reg_read(RegisterARM::LR as i32).unwrap_or(0) & !1;
+    let sp = emu.reg_read(RegisterARM::SP as i32).unwrap_or(0);
+
+    let in_function = |address: u64| symbols.lookup(address).map_or(false, |(s, _)| s.is_function);
+    let after_call = |address: u64| {
+        let mut word = [0u8; 4];
+        address >= 4 && emu.mem_read(address - 4, &mut word).is_ok() && is_call(u32::from_le_bytes(word))
+    };
+
+    let mut frames = vec![pc];
+    if lr != pc && after_call(lr) {
+        frames.push(lr);
+    }
+    for i in 0..depth as u64 {
+        let mut word = [0u8; 4];
+        if emu.mem_read(sp + i * 4, &mut word).is_err() {
+            break;
+        }
+        let address = u32::from_le_bytes(word) as u64;
+        if in_function(address) && after_call(address) && frames.last() != Some(&address) {
+            frames.push(address);
+        }
+    }
+    frames
+}
+
+/// Interactive terminal debugger
+///
+/// Pauses the main loop at the entry point, after a breakpoint or step, and on guest crashes,
+/// then reads commands from stdin until told to continue. Type `help` for the command list.
+pub struct Debugger {
+    symbols: Rc<SymbolTable>,
+    breakpoints: Breakpoints,
+    disassembler: Capstone,
+    paused: bool,
+    stepping: bool,
+    step_over: Option<u64>,
+    last_command: String,
+}
+
+impl Debugger {
+    pub fn new(emu: &mut UnicornHandle, symbols: Rc<SymbolTable>) -> Result<Debugger, uc_error> {
+        Ok(Debugger {
+            symbols,
+            breakpoints: Breakpoints::install(emu)?,
+            disassembler: emulator::create_disassembler(),
+            paused: true,
+            stepping: false,
+            step_over: None,
+            last_command: String::new(),
+        })
+    }
+
+    pub fn instruction_count(&self) -> usize {
+        if self.stepping { 1 } else { 0 }
+    }
+
+    /// Reads commands while paused. Returns false if the emulator has to stop
+    pub fn prompt(&mut self, emu: &mut UnicornHandle) -> bool {
+        if self.paused {
+            self.print_location(emu);
+        }
+        let stdin = std::io::stdin();
+        while self.paused {
+            print!("(armchine) ");
+            std::io::stdout().flush().unwrap();
+
+            let mut line = String::new();
+            if stdin.lock().read_line(&mut line).unwrap_or(0) == 0 {
+                return false;
+            }
+            let mut line = line.trim().to_string();
+            if line.is_empty() {
+                line = self.last_command.clone();
+            }
+            self.last_command = line.clone();
+
+            if !self.execute(emu, line.as_str()) {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Pauses after an `emu_start` call if a step, breakpoint or crash requires it
+    ///
+    /// Returns true if the debugger is now paused.
+    pub fn after_run(&mut self, emu: &mut UnicornHandle, result: Result<(), uc_error>) -> bool {
+        let breakpoint_hit = self.breakpoints.take_hit();
+        if let Err(error) = result {
+            let pc = emu.reg_read(RegisterARM::PC as i32).unwrap_or(0);
+            println!("failed because {:?} at {}", error, self.symbols.describe(pc));
+        } else if !self.stepping && !breakpoint_hit {
+            return false;
+        }
+
+        if let Some(address) = self.step_over.take() {
+            self.breakpoints.remove(address);
+        }
+        self.stepping = false;
+        self.paused = true;
+        true
+    }
+
+    fn resolve(&self, emu: &UnicornHandle, argument: &str) -> Option<u64> {
+        if let Some(hex) = argument.strip_prefix("0x") {
+            return u64::from_str_radix(hex, 16).ok();
+        }
+        if let Ok(address) = argument.parse::<u64>() {
+            return Some(address);
+        }
+        if let Some((_, register)) = CORE_REGISTERS.iter().find(|(name, _)| *name == argument) {
+            return emu.reg_read(*register as i32).ok();
+        }
+        self.symbols.find(argument).map(|s| s.address)
+    }
+
+    fn resume(&mut self, stepping: bool) {
+        self.stepping = stepping;
+        self.breakpoints.skip_next();
+        self.paused = false;
+    }
+
+    /// Returns false when the emulator has to stop
+    fn execute(&mut self, emu: &mut UnicornHandle, line: &str) -> bool {
+        let mut words = line.split_whitespace();
+        let command = words.next().unwrap_or("");
+        let argument = words.next();
+
+        match command {
+            "break" | "b" | "delete" | "d" => {
+                match argument.map(|a| (a, self.resolve(emu, a))) {
+                    Some((_, Some(address))) if command.starts_with('b') => {
+                        self.breakpoints.insert(address);
+                        println!("breakpoint at {}", self.symbols.describe(address));
+                    }
+                    Some((_, Some(address))) => {
+                        if !self.breakpoints.remove(address) {
+                            println!("no breakpoint at {}", self.symbols.describe(address));
+                        }
+                    }
+                    Some((argument, None)) => println!("unknown symbol or address {}", argument),
+                    None => {
+                        for address in self.breakpoints.list() {
+                            println!("breakpoint at {}", self.symbols.describe(address));
+                        }
+                    }
+                }
+            }
+            "step" | "s" => self.resume(true),
+            "next" | "n" => {
+                let pc = emu.reg_read(RegisterARM::PC as i32).unwrap();
+                let mut word = [0u8; 4];
+                if emu.mem_read(pc, &mut word).is_ok() && is_call(u32::from_le_bytes(word)) {
+                    if self.breakpoints.insert(pc + 4) {
+                        self.step_over = Some(pc + 4);
+                    }
+                    self.resume(false);
+                } else {
+                    self.resume(true);
+                }
+            }
+            "continue" | "c" => self.resume(false),
+            "regs" | "r" => self.print_registers(emu),
+            "disas" => {
+                let pc = emu.reg_read(RegisterARM::PC as i32).unwrap();
+                let address = argument.and_then(|a| self.resolve(emu, a)).unwrap_or(pc);
+                self.print_disassembly(emu, address, 10);
+            }
+            "bt" => {
+                for (i, address) in backtrace(emu, &self.symbols, 256).iter().enumerate() {
+                    println!("#{} {}", i, self.symbols.describe(*address));
+                }
+            }
+            "quit" | "q" => return false,
+            "help" | "h" => println!("{}", HELP),
+            _ if command.starts_with("x") => {
+                let count = command.strip_prefix("x/").and_then(|n| n.parse::<u64>().ok()).unwrap_or(1).min(MAX_DUMP_WORDS);
+                match argument.and_then(|a| self.resolve(emu, a)) {
+                    Some(address) => self.print_memory(emu, address, count),
+                    None => println!("usage: x/<n> <symbol|addr>"),
+                }
+            }
+            _ => println!("unknown command {}, try help", command),
+        }
+        true
+    }
+
+    fn print_location(&self, emu: &UnicornHandle) {
+        let pc = emu.reg_read(RegisterARM::PC as i32).unwrap_or(0);
+        self.print_disassembly(emu, pc, 1);
+    }
+
+    fn print_registers(&self, emu: &UnicornHandle) {
+        for (name, register) in CORE_REGISTERS {
+            let value = emu.reg_read(register as i32).unwrap_or(0);
+            println!("{:>4} {:#010x} {}", name, value, self.symbols.describe(value));
+        }
+    }
+
+    fn print_memory(&self, emu: &UnicornHandle, address: u64, count: u64) {
+        let count = count.min(MAX_DUMP_WORDS);
+        for row in 0..count.div_ceil(4) {
+            let row_address = match address.checked_add(row * 16) {
+                Some(row_address) => row_address,
+                None => break,
+            };
+            print!("{:#010x}:", row_address);
+            for i in 0..(count - row * 4).min(4) {
+                let mut word = [0u8; 4];
+                let read = row_address.checked_add(i * 4).map(|address| emu.mem_read(address, &mut word));
+                match read {
+                    Some(Ok(_)) => print!(" {:#010x}", u32::from_le_bytes(word)),
+                    _ => print!(" ??????????"),
+                }
+            }
+            println!();
+        }
+    }
+
+    fn print_disassembly(&self, emu: &UnicornHandle, address: u64, count: usize) {
+        let code = match emu.mem_read_as_vec(address, count * 4) {
+            Ok(code) => code,
+            Err(error) => {
+                println!("can't read {:#x}: {:?}", address, error);
+                return;
+            }
+        };
+        let instructions = self.disassembler.disasm_count(code.as_slice(), address, count).unwrap();
+        for i in instructions.iter() {
+            println!("{}: {} {}", self.symbols.describe(i.address()), i.mnemonic().unwrap_or(""), i.op_str().unwrap_or(""));
+        }
+    }
+}
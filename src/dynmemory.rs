@@ -1,11 +1,16 @@
 use std::alloc::alloc;
 use std::any::Any;
-use std::ptr::null_mut;
+use std::ops::Range;
 use libc::size_t;
 use unicorn::{RegisterARM, UnicornHandle};
-use unicorn::ffi::uc_hook;
 use unicorn::unicorn_const::Permission;
-use crate::features::EmulatorFeature;
+use crate::features::{EmulatorFeature, FeatureDescriptor};
+use crate::error::EmulatorError;
+use crate::syscalls::{SyscallDispatcher, SyscallError};
+use crate::savestate::{StateReader, StateWriter};
+use crate::newlib;
+
+const SYSCALLS: Range<u32> = 0x60..0x80;
 
 /// Allows dynamic allocation of memory
 ///
@@ -13,8 +18,11 @@ use crate::features::EmulatorFeature;
 /// memory for usage in the emulated system
 pub struct DynamicMemoryAllocations {
     memory_base: u64,
-    hook: uc_hook,
     allocations: Vec<(u32, u32)>,
+    /// Start and current value of the `sbrk` program break, once the guest asked for it
+    program_break: Option<(u32, u32)>,
+    /// Bytes mapped from the start of the program break, kept apart from `allocations`
+    heap_mapped: u32,
 }
 
 impl DynamicMemoryAllocations {
@@ -24,11 +32,87 @@ impl DynamicMemoryAllocations {
             align += 1;
         }
         let membase = align << 22;
+        Self::with_base(membase)
+    }
+
+    /// Allocates upwards from `memory_base` instead of the first 4MB boundary after the executable
+    pub fn with_base(memory_base: u64) -> DynamicMemoryAllocations {
         DynamicMemoryAllocations {
-            memory_base: membase,
-            hook: null_mut(),
+            memory_base,
             allocations: Vec::new(),
+            program_break: None,
+            heap_mapped: 0,
+        }
+    }
+
+    /// Where the next block is mapped: above the highest allocation and the program break, or
+    /// at `memory_base`
+    fn next_base(&self) -> u64 {
+        let allocations_end = self.allocations.iter().map(|(base, size)| *base as u64 + *size as u64).max();
+        let heap_end = self.program_break
+            .map(|(start, current)| (start as u64 + self.heap_mapped as u64).max(current as u64));
+        let base = allocations_end.into_iter().chain(heap_end).max().unwrap_or(self.memory_base);
+        (base + 0xFFF) & !0xFFF
+    }
+
+    /// Maps a block of at least `requested` bytes for syscall 0x60 and returns its address
+    fn allocate(&mut self, em: &mut UnicornHandle, requested: u32) -> Result<u64, SyscallError> {
+        if requested == 0 {
+            return Err(SyscallError::InvalidArgument);
+        }
+        let allocation_size = align(requested, 12);
+        if allocation_size < requested {
+            return Err(SyscallError::OutOfMemory);
+        }
+
+        let base = self.next_base();
+        if base + allocation_size as u64 > u32::MAX as u64 {
+            return Err(SyscallError::OutOfMemory);
         }
+
+        em.mem_map(base, allocation_size as size_t, Permission::ALL)
+            .map_err(|_| SyscallError::OutOfMemory)?;
+        self.allocations.push((base as u32, allocation_size));
+        Ok(base)
+    }
+
+    /// Moves the program break by `increment` bytes and returns the previous one
+    ///
+    /// The break starts at the next free block and grows in place, which fails with ENOMEM
+    /// once a block from syscall 0x60 sits right above it. Blocks from syscall 0x60 are always
+    /// mapped above the break, never inside the heap.
+    fn sbrk(&mut self, em: &mut UnicornHandle, increment: i32) -> Result<u32, i32> {
+        let (start, current) = match self.program_break {
+            Some(program_break) => program_break,
+            None => {
+                let start = self.next_base() as u32;
+                (start, start)
+            }
+        };
+        let new = (current as i64 + increment as i64).max(start as i64);
+        if new > u32::MAX as i64 {
+            return Err(newlib::ENOMEM);
+        }
+        let new = new as u32;
+
+        let needed = align(new - start, 12);
+        if needed < new - start {
+            return Err(newlib::ENOMEM);
+        }
+        if needed > self.heap_mapped {
+            let end = start as u64 + self.heap_mapped as u64;
+            let grown_end = start as u64 + needed as u64;
+            let blocked = self.allocations.iter()
+                .any(|(base, size)| (*base as u64) < grown_end && end < *base as u64 + *size as u64);
+            if blocked {
+                return Err(newlib::ENOMEM);
+            }
+            em.mem_map(end, (needed - self.heap_mapped) as size_t, Permission::ALL)
+                .map_err(|_| newlib::ENOMEM)?;
+            self.heap_mapped = needed;
+        }
+        self.program_break = Some((start, new));
+        Ok(current)
     }
 }
 
@@ -42,40 +126,44 @@ fn align(value: u32, align: u32) -> u32 {
 
 /// | Syscall | Parameters | Description |
 /// | ------- | ---------- | ----------- |
-/// | 0x60 | size_t: allocation size | Allocates a memory block of provided size (aligned to 4096) above the executable memory_base or highest allocation, also aligned to 4096 |
+/// | 0x60 | size_t: allocation size | Allocates a memory block of provided size (aligned to 4096) above the executable memory_base or highest allocation, also aligned to 4096. Returns -2 for empty allocations and -7 once the address space is exhausted |
+/// | 0x207 | int: increment | newlib `sbrk`: moves the program break and returns the previous one, -ENOMEM if it can't grow |
 impl EmulatorFeature for DynamicMemoryAllocations {
-    fn init(&mut self, emulator: &mut UnicornHandle) -> Result<(), String> {
-        let membase = self.memory_base;
-        let vptr: *mut Vec<(u32, u32)> = &mut self.allocations;
-        let hook = emulator.add_intr_hook(move |mut em, _syscall| unsafe {
-            let vector = &mut *vptr;
-            let syscall = em.reg_read_i32(RegisterARM::R7 as i32).unwrap();
-            if syscall == 0x60 {
-                let mut allocation_size = em.reg_read_i32(RegisterARM::R1 as i32).unwrap();
-                allocation_size = align(allocation_size as u32, 12) as i32;
-
-                let mut base =
-                    if (vector).len() > 0 {
-                        let (b, sz) = vector.iter().max_by_key(|(base, _sz)| base).unwrap();
-                        (b + sz) as u64
-                    } else { membase };
-                base = align(base as u32, 12) as u64;
-
-                em.mem_map(base, allocation_size as size_t, Permission::ALL).unwrap();
-                vector.push((base as u32, allocation_size as u32));
-                em.reg_write(RegisterARM::R0 as i32, base).unwrap();
-                // println!("{:#x} -> {:#x}", base, base+allocation_size as u64);
+    fn init(&mut self, _emulator: &mut UnicornHandle, syscalls: &mut SyscallDispatcher) -> Result<(), EmulatorError> {
+        let dynptr: *mut DynamicMemoryAllocations = self;
+        syscalls.register(&self.name(), newlib::syscall(newlib::SBRK), move |em, _syscall| unsafe {
+            let increment = em.reg_read_i32(RegisterARM::R1 as i32)?;
+            let result = (*dynptr).sbrk(em, increment);
+            newlib::set_result(em, result)
+        })?;
+
+        syscalls.register(&self.name(), SYSCALLS, move |em, syscall| unsafe {
+            if syscall != 0x60 {
+                return Err(SyscallError::Unknown);
             }
-        });
-        self.hook = hook.unwrap();
-        Ok(())
+            let requested = em.reg_read(RegisterARM::R1 as i32)? as u32;
+            let base = (*dynptr).allocate(em, requested)?;
+            em.reg_write(RegisterARM::R0 as i32, base)?;
+            // println!("{:#x} -> {:#x}", base, base+allocation_size as u64);
+            Ok(())
+        })
     }
 
-    fn stop(&mut self, _emulator: &mut UnicornHandle) -> Result<(), String> {
-        _emulator.remove_hook(self.hook).unwrap();
-        self.hook = null_mut();
-        self.allocations.iter().for_each(|(addr, size)| _emulator.mem_unmap(*addr as u64, *size as size_t).unwrap());
+    fn stop(&mut self, _emulator: &mut UnicornHandle) -> Result<(), EmulatorError> {
+        self.reset(_emulator)
+    }
+
+    /// Unmaps every allocated block
+    fn reset(&mut self, emulator: &mut UnicornHandle) -> Result<(), EmulatorError> {
+        for (addr, size) in &self.allocations {
+            emulator.mem_unmap(*addr as u64, *size as size_t)?;
+        }
         self.allocations.clear();
+        if let (Some((start, _)), true) = (self.program_break, self.heap_mapped > 0) {
+            emulator.mem_unmap(start as u64, self.heap_mapped as size_t)?;
+        }
+        self.program_break = None;
+        self.heap_mapped = 0;
         Ok(())
     }
 
@@ -86,4 +174,71 @@ impl EmulatorFeature for DynamicMemoryAllocations {
     fn name(&self) -> String {
         "DynamicMemory".to_string()
     }
-}
\ No newline at end of file
+
+    fn descriptor(&self) -> FeatureDescriptor {
+        FeatureDescriptor { syscalls: vec![SYSCALLS, newlib::syscall(newlib::SBRK)], mmio: Vec::new() }
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut writer = StateWriter::new();
+        writer.write_u32(self.allocations.len() as u32);
+        for (base, size) in &self.allocations {
+            writer.write_u32(*base);
+            writer.write_u32(*size);
+        }
+        let (start, current) = self.program_break.unwrap_or((0, 0));
+        writer.write_u32(start);
+        writer.write_u32(current);
+        writer.write_u32(self.heap_mapped);
+        writer.into_bytes()
+    }
+
+    /// The allocated blocks themselves are remapped along with the rest of the snapshot memory,
+    /// this only brings the allocation list back in sync with them
+    fn load_state(&mut self, _emulator: &mut UnicornHandle, state: &[u8]) -> Result<(), EmulatorError> {
+        let mut reader = StateReader::new(state);
+        let count = reader.read_u32()?;
+        let mut allocations = Vec::new();
+        for _ in 0..count {
+            allocations.push((reader.read_u32()?, reader.read_u32()?));
+        }
+        self.allocations = allocations;
+        self.program_break = match (reader.read_u32()?, reader.read_u32()?) {
+            (0, 0) => None,
+            program_break => Some(program_break),
+        };
+        self.heap_mapped = reader.read_u32()?;
+        Ok(())
+    }
+}
+#[cfg(test)]
+mod tests {
+    use crate::emulator;
+    use super::*;
+
+    #[test]
+    fn blocks_stay_out_of_the_heap() {
+        let mut unicorn = emulator::create_emulator();
+        let mut emu = unicorn.borrow();
+        let mut memory = DynamicMemoryAllocations::with_base(0x40_0000);
+
+        assert_eq!(memory.sbrk(&mut emu, 0), Ok(0x40_0000));
+        let block = memory.allocate(&mut emu, 0x100).unwrap();
+        assert_eq!(block, 0x40_0000);
+        // The block sits right where the heap would grow, so it can't
+        assert_eq!(memory.sbrk(&mut emu, 0x100), Err(newlib::ENOMEM));
+        assert_eq!(memory.sbrk(&mut emu, 0), Ok(0x40_0000));
+    }
+
+    #[test]
+    fn blocks_go_above_the_break() {
+        let mut unicorn = emulator::create_emulator();
+        let mut emu = unicorn.borrow();
+        let mut memory = DynamicMemoryAllocations::with_base(0x40_0000);
+
+        assert_eq!(memory.sbrk(&mut emu, 0x1800), Ok(0x40_0000));
+        assert_eq!(memory.allocate(&mut emu, 1).unwrap(), 0x40_2000);
+        assert_eq!(memory.sbrk(&mut emu, 0x100), Ok(0x40_1800));
+        assert_eq!(memory.sbrk(&mut emu, 0x1000), Err(newlib::ENOMEM));
+    }
+}
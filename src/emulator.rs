@@ -5,20 +5,49 @@ use unicorn::unicorn_const::{HookType, Mode, Permission, uc_error};
 use std::ptr::null_mut;
 use unicorn::unicorn_const::Arch::ARM;
 use std::fs;
-use xmas_elf::{ElfFile, header, sections};
-use std::cmp::max;
+use std::path::Path;
+use std::ops::Range;
+use xmas_elf::{ElfFile, header, program, sections};
+use std::cmp::{max, min};
 use xmas_elf::symbol_table::{Entry, Entry32};
 use unicorn::ffi::uc_hook;
 use std::borrow::Borrow;
-use std::cell::RefCell;
-use std::collections::HashMap;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap};
 use std::marker::PhantomData;
 use std::rc::Rc;
 use xmas_elf::dynamic::Tag::Hash;
 use capstone::arch::arm::ArchMode;
 use capstone::arch::BuildsCapstone;
-use crate::{EmulatorFeature, GPUFeature};
+use capstone::Capstone;
+use crate::EmulatorFeature;
+use crate::features::FrameRequests;
 use crate::filesystem::Drive;
+use crate::savestate::SaveState;
+use crate::symbols::SymbolTable;
+use crate::syscalls::SyscallDispatcher;
+use crate::error::EmulatorError;
+
+/// General purpose registers plus CPSR, with their usual names
+pub const CORE_REGISTERS: [(&str, RegisterARM); 17] = [
+    ("r0", RegisterARM::R0),
+    ("r1", RegisterARM::R1),
+    ("r2", RegisterARM::R2),
+    ("r3", RegisterARM::R3),
+    ("r4", RegisterARM::R4),
+    ("r5", RegisterARM::R5),
+    ("r6", RegisterARM::R6),
+    ("r7", RegisterARM::R7),
+    ("r8", RegisterARM::R8),
+    ("r9", RegisterARM::R9),
+    ("r10", RegisterARM::R10),
+    ("r11", RegisterARM::R11),
+    ("r12", RegisterARM::R12),
+    ("sp", RegisterARM::SP),
+    ("lr", RegisterARM::LR),
+    ("pc", RegisterARM::PC),
+    ("cpsr", RegisterARM::CPSR),
+];
 
 pub fn create_emulator() -> Unicorn {
     let cpu_mode = Mode::ARM946 | Mode::LITTLE_ENDIAN;
@@ -27,49 +56,177 @@ pub fn create_emulator() -> Unicorn {
     unicorn
 }
 
-pub fn load_executable(emu: &mut UnicornHandle, file_io: &Drive) -> Result<(u64, u64), ()> {
-    let file_content: Vec<u8> = file_io.read_file("./main.elf").unwrap();
-    let binary_blob: &[u8] = file_content.borrow();
+const PAGE_SHIFT: u64 = 12;
+const PAGE_SIZE: u64 = 1 << PAGE_SHIFT;
+/// End of the 32-bit address space, no segment can go past it
+const ADDRESS_SPACE_END: u64 = 1 << 32;
+
+fn segment_permission(flags: program::Flags) -> Permission {
+    let mut permission = Permission::NONE;
+    if flags.is_read() {
+        permission |= Permission::READ;
+    }
+    if flags.is_write() {
+        permission |= Permission::WRITE;
+    }
+    if flags.is_execute() {
+        permission |= Permission::EXEC;
+    }
+    permission
+}
 
-    let elf_file = ElfFile::new(binary_blob).unwrap();
+fn map_pages(emu: &mut UnicornHandle, first_page: u64, page_count: u64, permission: Permission) -> Result<(), EmulatorError> {
+    Ok(emu.mem_map(first_page << PAGE_SHIFT, (page_count << PAGE_SHIFT) as size_t, permission)?)
+}
 
-    header::sanity_check(&elf_file).unwrap();
+/// Loads `main.elf` from the drive into emulator memory
+///
+/// Every PT_LOAD segment is mapped with its own R/W/X permissions (pages shared by two segments
+/// get the union of both), and the `mem_size - file_size` tail of each segment is zero-filled.
+/// The entry point is the `_start` symbol if there is one, `e_entry` otherwise.
+///
+/// Returns the end of the highest segment, the entry point and the executable's symbols.
+pub fn load_executable(emu: &mut UnicornHandle, file_io: &Drive) -> Result<(u64, u64, SymbolTable), EmulatorError> {
+    let file_content: Vec<u8> = file_io.read_file("./main.elf")?;
+    let binary_blob: &[u8] = file_content.borrow();
 
+    let elf_file = ElfFile::new(binary_blob).map_err(EmulatorError::InvalidElf)?;
 
-    let mut mem_sz = 0;
+    header::sanity_check(&elf_file).map_err(EmulatorError::InvalidElf)?;
 
-    let mut prog_iter = elf_file.program_iter();
-    prog_iter.next();
-    for ph in prog_iter {
-        mem_sz = max(mem_sz, ph.virtual_addr() + ph.mem_size());
+    let mut segments = Vec::new();
+    for ph in elf_file.program_iter() {
+        match ph.get_type().map_err(EmulatorError::InvalidElf)? {
+            program::Type::Load => {}
+            _ => continue,
+        }
+        let file_end = ph.offset().checked_add(ph.file_size());
+        let segment_end = ph.virtual_addr().checked_add(ph.mem_size());
+        if ph.file_size() > ph.mem_size()
+            || file_end.map_or(true, |end| end > binary_blob.len() as u64)
+            || segment_end.map_or(true, |end| end > ADDRESS_SPACE_END) {
+            return Err(EmulatorError::MalformedSegment(ph.virtual_addr()));
+        }
+        segments.push(ph);
     }
 
-    let mut align = mem_sz >> 12;
-    align = if (align << 12) < mem_sz { (align + 1) << 12 } else { align << 12 };
+    if segments.is_empty() {
+        return Err(EmulatorError::InvalidElf("no PT_LOAD segments"));
+    }
 
-    emu.mem_map(0 as u64, align as libc::size_t, Permission::ALL).expect("couldn't map memory");
+    let mut mem_sz = 0;
+    let mut pages = BTreeMap::<u64, Permission>::new();
+    for ph in &segments {
+        let malformed = || EmulatorError::MalformedSegment(ph.virtual_addr());
+        let segment_end = ph.virtual_addr().checked_add(ph.mem_size()).ok_or_else(malformed)?;
+        mem_sz = max(mem_sz, segment_end);
 
-    let mut prog_iter = elf_file.program_iter();
-    prog_iter.next();
+        let permission = segment_permission(ph.flags());
+        let first_page = ph.virtual_addr() >> PAGE_SHIFT;
+        let last_page = segment_end.checked_add(PAGE_SIZE - 1).ok_or_else(malformed)? >> PAGE_SHIFT;
+        for page in first_page..last_page {
+            *pages.entry(page).or_insert(Permission::NONE) |= permission;
+        }
+    }
 
-    for ph in prog_iter {
+    let mut run: Option<(u64, u64, Permission)> = None;
+    for (&page, &permission) in &pages {
+        run = match run {
+            Some((first, count, perms)) if first + count == page && perms == permission => {
+                Some((first, count + 1, perms))
+            }
+            Some((first, count, perms)) => {
+                map_pages(emu, first, count, perms)?;
+                Some((page, 1, permission))
+            }
+            None => Some((page, 1, permission)),
+        };
+    }
+    if let Some((first, count, perms)) = run {
+        map_pages(emu, first, count, perms)?;
+    }
+
+    for ph in &segments {
         let header_data = &binary_blob[
             ph.offset() as usize..(ph.offset() + ph.file_size()) as usize];
 
-        emu.mem_write(ph.virtual_addr(), header_data).unwrap();
+        emu.mem_write(ph.virtual_addr(), header_data)?;
+
+        let bss_size = ph.mem_size() - ph.file_size();
+        if bss_size > 0 {
+            let bss_start = ph.virtual_addr().checked_add(ph.file_size())
+                .ok_or(EmulatorError::MalformedSegment(ph.virtual_addr()))?;
+            let zeroes = vec![0u8; bss_size as usize];
+            emu.mem_write(bss_start, zeroes.as_slice())?;
+        }
     }
 
-    let symbol_header = elf_file.find_section_by_name(".symtab").unwrap();
-    let symbol_data = symbol_header.get_data(&elf_file).unwrap();
+    let symbols = SymbolTable::from_elf(&elf_file);
+    let main_idx = symbols.find("_start")
+        .map(|s| s.address)
+        .unwrap_or_else(|| elf_file.header.pt2.entry_point());
 
-    let main_idx = if let sections::SectionData::SymbolTable32(data) = symbol_data {
-        let main_entry: &Entry32 = data.iter().find(|e| e.get_name(&elf_file).unwrap().eq("_start")).unwrap();
+    Ok((mem_sz, main_idx, symbols))
+}
 
-        main_entry.value()
-    } else {
-        return Err(());
+/// Maps the `size` bytes below `top` as the stack, with a guard page under it
+///
+/// The guard page is mapped without any permission, so nothing else (dynamic allocations...)
+/// can be mapped there and the guest faults as soon as the stack grows into it instead of
+/// overwriting whatever lies below. Returns the stack region, guard page excluded.
+pub fn map_stack(emu: &mut UnicornHandle, top: u64, size: u64) -> Result<Range<u64>, EmulatorError> {
+    let start = top.checked_sub(size)
+        .and_then(|start| start.checked_sub(PAGE_SIZE).map(|guard| (guard, start)));
+    let (guard, stack) = match start {
+        Some((guard, start)) => (guard..start, start..top),
+        None => return Err(EmulatorError::InvalidConfig(format!(
+            "stack of {:#x} bytes and its guard page don't fit below {:#x}", size, top))),
     };
-    Ok((mem_sz, main_idx))
+    if let Some(region) = emu.mem_regions()?.iter().find(|r| r.begin < top && guard.start <= r.end) {
+        return Err(EmulatorError::InvalidConfig(format!(
+            "stack {:#x}..{:#x} overlaps the executable at {:#x}..{:#x}",
+            guard.start, top, region.begin, region.end + 1)));
+    }
+    emu.mem_map(guard.start, PAGE_SIZE as size_t, Permission::NONE)?;
+    emu.mem_map(stack.start, size as size_t, Permission::READ | Permission::WRITE)?;
+    Ok(stack)
+}
+
+/// Whether a crash is the stack growing out of `stack`: either the fault hit the guard page,
+/// or SP already went below the stack and the fault is in the frame it allocated there
+pub fn is_stack_overflow(stack: &Range<u64>, sp: u64, fault_address: u64) -> bool {
+    let guard = stack.start.saturating_sub(PAGE_SIZE)..stack.start;
+    guard.contains(&fault_address) || (sp < stack.start && sp <= fault_address && fault_address < stack.start)
+}
+
+pub fn create_disassembler() -> Capstone {
+    capstone::Capstone::new().arm().mode(ArchMode::Arm).build().unwrap()
+}
+
+/// Whether the ARM instruction `word` is a BL/BLX, i.e. a call that returns to the next instruction
+pub fn is_call(word: u32) -> bool {
+    (word & 0x0F00_0000) == 0x0B00_0000
+        || (word & 0xFE00_0000) == 0xFA00_0000
+        || (word & 0x0FFF_FFF0) == 0x012F_FF30
+}
+
+/// Whether the ARM instruction `word` is one of the usual function returns:
+/// BX LR, MOV PC, LR, POP {..., PC} or LDR PC, [SP], #4
+pub fn is_return(word: u32) -> bool {
+    (word & 0x0FFF_FFFF) == 0x012F_FF1E
+        || (word & 0x0FFF_FFFF) == 0x01A0_F00E
+        || (word & 0x0FFF_8000) == 0x08BD_8000
+        || (word & 0x0FFF_FFFF) == 0x049D_F004
+}
+
+/// Whether the ARM instruction `word` is a `swi`
+pub fn is_syscall(word: u32) -> bool {
+    (word & 0x0F00_0000) == 0x0F00_0000
+}
+
+/// Whether the ARM instruction `word` is a `bkpt`
+pub fn is_breakpoint(word: u32) -> bool {
+    (word & 0x0FF0_00F0) == 0x0120_0070
 }
 
 pub fn print_disassembly(unicorn_handle: &mut UnicornHandle, mem_sz: u64, main_idx: u64, e: Result<(), uc_error>) {
@@ -78,36 +235,79 @@ pub fn print_disassembly(unicorn_handle: &mut UnicornHandle, mem_sz: u64, main_i
         println!("failed because {:?}", error);
         println!("failed at {:#x}", pc);
     }
-    let capstone = capstone::Capstone::new().arm().mode(ArchMode::Arm).build().unwrap();
-    let instructions = capstone.disasm_all(unicorn_handle.mem_read_as_vec(main_idx, (mem_sz - main_idx) as usize).unwrap().as_slice(), pc as u64).unwrap();
+    let capstone = create_disassembler();
+    // Segments are mapped separately now, so only dump the region the entry point lives in
+    let code_end = unicorn_handle.mem_regions().unwrap().iter()
+        .find(|r| r.begin <= main_idx && main_idx <= r.end)
+        .map_or(mem_sz, |r| min(mem_sz, r.end + 1));
+    let instructions = capstone.disasm_all(unicorn_handle.mem_read_as_vec(main_idx, (code_end - main_idx) as usize).unwrap().as_slice(), pc as u64).unwrap();
     for i in instructions.iter() {
         println!("{:#x}: {} {}", i.address(), i.mnemonic().unwrap(), i.op_str().unwrap());
     }
 }
 
-pub fn video_update(features: &mut Vec<Box<dyn EmulatorFeature>>, must_loop: &mut bool) {
+/// Runs `on_frame` on every feature and collects what they ask the main loop to do
+pub fn frame_all_features(unicorn_handle: &mut UnicornHandle, features: &mut Vec<Box<dyn EmulatorFeature>>) -> Result<FrameRequests, EmulatorError> {
+    let mut requests = FrameRequests::default();
     for feat in &mut *features {
-        if feat.name().eq("GPUFeature") {
-            let feat = feat.as_any().downcast_mut::<GPUFeature>().unwrap();
-            let t1 = std::time::Instant::now();
-            feat.update();
-            *must_loop = feat.is_open();
-            let t2 = std::time::Instant::now();
-            let dt = t2.duration_since(t1).as_millis();
-
-            print!("Rendering and update time: {};", dt);
+        feat.on_frame(unicorn_handle, &mut requests)?;
+    }
+    Ok(requests)
+}
+
+/// Counts executed instructions and stops the emulator once `max_instructions` is reached
+///
+/// NOTE: unicorn only stops after the current block, so the count may overshoot a little
+pub fn add_instruction_budget(unicorn_handle: &mut UnicornHandle, max_instructions: u64) -> Rc<Cell<u64>> {
+    let executed = Rc::new(Cell::new(0u64));
+    let counter = executed.clone();
+    unicorn_handle.add_code_hook(1, 0, move |mut emu, _address, _size| {
+        counter.set(counter.get() + 1);
+        if counter.get() >= max_instructions {
+            emu.emu_stop().unwrap();
         }
+    }).unwrap();
+    executed
+}
+
+/// Refuses features reserving the same memory, overlapping syscalls are caught when registered
+fn check_reservations(features: &Vec<Box<dyn EmulatorFeature>>) -> Result<(), EmulatorError> {
+    let mut reserved: Vec<(String, Range<u64>)> = Vec::new();
+    for feat in features {
+        for range in feat.descriptor().mmio {
+            if let Some((other, other_range)) = reserved.iter()
+                .find(|(_, r)| r.start < range.end && range.start < r.end) {
+                return Err(EmulatorError::MmioConflict {
+                    owner: feat.name(),
+                    range,
+                    other: other.clone(),
+                    other_range: other_range.clone(),
+                });
+            }
+            reserved.push((feat.name(), range));
+        }
+    }
+    Ok(())
+}
+
+pub fn initialize_all_features(mut unicorn_handle: &mut UnicornHandle, features: &mut Vec<Box<dyn EmulatorFeature>>, syscalls: &mut SyscallDispatcher) -> Result<(), EmulatorError> {
+    check_reservations(features)?;
+    for feat in &mut *features {
+        feat.init(&mut unicorn_handle, syscalls)?;
     }
+    syscalls.install(&mut unicorn_handle)
 }
 
-pub fn initialize_all_features(mut unicorn_handle: &mut UnicornHandle, features: &mut Vec<Box<dyn EmulatorFeature>>) {
+pub fn reset_all_features(unicorn_handle: &mut UnicornHandle, features: &mut Vec<Box<dyn EmulatorFeature>>) -> Result<(), EmulatorError> {
     for feat in &mut *features {
-        feat.init(&mut unicorn_handle).unwrap();
+        feat.reset(unicorn_handle)?;
     }
+    Ok(())
 }
 
-pub fn stop_all_features(mut unicorn_handle: &mut UnicornHandle, features: &mut Vec<Box<dyn EmulatorFeature>>) {
+pub fn stop_all_features(mut unicorn_handle: &mut UnicornHandle, features: &mut Vec<Box<dyn EmulatorFeature>>) -> Result<(), EmulatorError> {
     for mut feat in features {
-        feat.stop(&mut unicorn_handle).unwrap();
+        feat.stop(&mut unicorn_handle)?;
     }
+    Ok(())
 }
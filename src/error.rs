@@ -0,0 +1,92 @@
+use std::fmt;
+use std::ops::Range;
+use unicorn::unicorn_const::uc_error;
+use crate::syscalls::TrapInstruction;
+
+/// Errors of the emulator itself: loading the disc and executable, setting up features and
+/// save states
+///
+/// Errors caused by the guest passing bad syscall arguments are [crate::syscalls::SyscallError]s
+/// instead, they're returned to the guest rather than stopping the emulator.
+#[derive(Debug)]
+pub enum EmulatorError {
+    /// Unicorn refused an operation (mapping memory, installing a hook...)
+    Unicorn(uc_error),
+    /// Reading or writing a host file failed
+    Io(std::io::Error),
+    /// The disc image can't be parsed
+    InvalidImage(String),
+    /// The path doesn't exist on the drive or isn't a file
+    FileNotFound(String),
+    /// The ELF headers are malformed or not something we can run
+    InvalidElf(&'static str),
+    /// A PT_LOAD segment references bytes outside of the file, or is bigger on file than in memory
+    MalformedSegment(u64),
+    /// A feature registered syscalls already reserved by another feature
+    SyscallConflict {
+        owner: String,
+        range: Range<u32>,
+        other: String,
+        other_range: Range<u32>,
+    },
+    /// A feature registered an SVC or BKPT instruction already registered by another feature
+    TrapConflict {
+        owner: String,
+        instruction: TrapInstruction,
+        other: String,
+    },
+    /// A feature reserved memory already reserved by another feature
+    MmioConflict {
+        owner: String,
+        range: Range<u64>,
+        other: String,
+        other_range: Range<u64>,
+    },
+    /// The configuration file can't be parsed or has invalid values
+    InvalidConfig(String),
+    /// A save state is truncated, corrupt or doesn't match this machine
+    InvalidState(String),
+    /// A patch is malformed, or was made for another file than the one it's applied to
+    InvalidPatch(String),
+}
+
+impl fmt::Display for EmulatorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EmulatorError::Unicorn(error) => write!(f, "unicorn error {:?}", error),
+            EmulatorError::Io(error) => write!(f, "{}", error),
+            EmulatorError::InvalidImage(reason) => write!(f, "invalid disc image: {}", reason),
+            EmulatorError::FileNotFound(path) => write!(f, "path was not a file: {}", path),
+            EmulatorError::InvalidElf(reason) => write!(f, "invalid executable: {}", reason),
+            EmulatorError::MalformedSegment(address) => write!(f, "malformed segment at {:#x}", address),
+            EmulatorError::SyscallConflict { owner, range, other, other_range } => {
+                write!(f, "{} syscalls {:#x}..{:#x} overlap with {} syscalls {:#x}..{:#x}",
+                       owner, range.start, range.end, other, other_range.start, other_range.end)
+            }
+            EmulatorError::TrapConflict { owner, instruction, other } => {
+                write!(f, "{} and {} both handle {}", owner, other, instruction)
+            }
+            EmulatorError::MmioConflict { owner, range, other, other_range } => {
+                write!(f, "{} memory {:#x}..{:#x} overlaps with {} memory {:#x}..{:#x}",
+                       owner, range.start, range.end, other, other_range.start, other_range.end)
+            }
+            EmulatorError::InvalidConfig(reason) => write!(f, "invalid configuration: {}", reason),
+            EmulatorError::InvalidState(reason) => write!(f, "invalid save state: {}", reason),
+            EmulatorError::InvalidPatch(reason) => write!(f, "invalid patch: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for EmulatorError {}
+
+impl From<uc_error> for EmulatorError {
+    fn from(error: uc_error) -> Self {
+        EmulatorError::Unicorn(error)
+    }
+}
+
+impl From<std::io::Error> for EmulatorError {
+    fn from(error: std::io::Error) -> Self {
+        EmulatorError::Io(error)
+    }
+}
@@ -1,5 +1,33 @@
 use unicorn::UnicornHandle;
 use std::any::Any;
+use std::ops::Range;
+use crate::error::EmulatorError;
+use crate::syscalls::SyscallDispatcher;
+
+/// Syscall ranges and memory regions a feature reserves, see [EmulatorFeature::descriptor]
+pub struct FeatureDescriptor {
+    pub syscalls: Vec<Range<u32>>,
+    pub mmio: Vec<Range<u64>>,
+}
+
+/// What features ask the main loop to do at the end of a frame
+#[derive(Debug, Default)]
+pub struct FrameRequests {
+    /// The host window was closed
+    pub quit: bool,
+    /// The guest called the exit syscall with this code
+    pub exit_code: Option<i32>,
+    pub save_state: bool,
+    pub load_state: bool,
+    pub reset: bool,
+    /// Open the drive tray, or insert the next disc if it's open
+    pub swap_disc: bool,
+    /// Frames the guest waits on the drive before running again
+    pub stall_frames: u64,
+    /// The guest ended this frame with the present syscall, it didn't just run out of time
+    /// slice or wait on the drive
+    pub presented: bool,
+}
 
 /// Modularized (and possibly optional) features of the emulator
 ///
@@ -7,27 +35,85 @@ use std::any::Any;
 /// features to the emulated system through syscalls or mapped memory regions
 ///
 /// ```
-/// feature.init(&mut emulator);
+/// feature.init(&mut emulator, &mut syscalls);
+/// feature.on_frame(&mut emulator, &mut requests);
+/// feature.reset(&mut emulator);
 /// feature.stop(&mut emulator);
 /// ```
 ///
-/// NOTE: Each syscall (of any feature) also suspends emulator and updates
-/// the graphics backend if one is enabled. This may lead to waiting for vsync
+/// Features don't hook interrupts themselves, they register handlers for their syscall range
+/// with the [crate::syscalls::SyscallDispatcher] passed to `init`. The ranges and memory regions
+/// they reserve are listed by `descriptor`, overlapping reservations are refused at startup.
+///
+/// Features that keep state outside of emulator memory (bookkeeping, pending draw lists...)
+/// override `save_state` and `load_state` so it can be part of a [crate::savestate::SaveState].
+///
+/// NOTE: Syscalls run without suspending the emulator. It only yields to the host (which then
+/// calls `on_frame` on every feature, updating the graphics backend which may wait for vsync)
+/// on the present syscall `0x161`, on exit, or when the `--time-slice` budget runs out
 ///
 /// | Feature | Reserved Memory Blocks | Reserved Syscalls |
 /// | ------- | ---------------------- | ----------------- |
-/// | [crate::filesystem::EmulatorDrive] | None | 0x0 → 0x10 |
-/// | [crate::dynmemory::DynamicMemoryAllocations] | None | 0x60 → 0x80 |
+/// | [crate::filesystem::EmulatorDrive] | None | 0x0 → 0x14, 0x201 → 0x204, 0x205 → 0x207 |
+/// | [crate::dynmemory::DynamicMemoryAllocations] | None | 0x60 → 0x80, 0x207 |
+/// | [crate::system::SystemControl] | None | 0x100 → 0x110, 0x200 |
 /// | [crate::gpu::feature::GPUFeature] | None | 0x160 → 0x180 |
-/// | [crate::console::ConsoleIO] | 0xFF000 → 0x100000 | None |
+/// | [crate::console::ConsoleIO] | 0xFF000 → 0x100000 | 0x204 |
+/// | [crate::semihosting::Semihosting] (optional) | None | None, it traps `svc #0x123456`, `svc #0xab` and `bkpt #0xab` |
+///
+/// Upper bounds are exclusive. Syscalls outside of every range return -1 in R0, or stop the
+/// emulator if it is configured to trap on them.
 ///
 /// Syscalls are implemented through the `swi #0` instruction. The syscall number is
 /// taken from the R7 register. Parameters are loaded from the R1 through R6(?) registers, with the
 /// result stored in the R0 register. No standard is defined as of yet for more than 6 parameters.
 /// Structures/objects/things bigger than register size are passed in as pointers.
+///
+/// Syscalls 0x200 → 0x208 are the [newlib personality](crate::newlib), shared between the
+/// features it is layered on. They return a negative errno instead of the codes below.
+///
+/// Failed syscalls return a negative [crate::syscalls::SyscallError] code in R0:
+///
+/// | Code | Error |
+/// | ---- | ----- |
+/// | -1 | Unknown syscall |
+/// | -2 | Invalid argument (index or count out of range) |
+/// | -3 | Bad address (pointer to unmapped memory) |
+/// | -4 | File not found |
+/// | -5 | String isn't valid UTF-8 |
+/// | -6 | Drive read error |
+/// | -7 | Out of memory |
+/// | -8 | Bad handle (file not open) |
+/// | -9 | No disc (drive tray open) |
+/// | -10 | Disc changed (check the drive status) |
 pub trait EmulatorFeature {
-    fn init(&mut self, emulator: &mut UnicornHandle) -> Result<(), String>;
-    fn stop(&mut self, emulator: &mut UnicornHandle) -> Result<(), String>;
+    fn init(&mut self, emulator: &mut UnicornHandle, syscalls: &mut SyscallDispatcher) -> Result<(), EmulatorError>;
+    fn stop(&mut self, emulator: &mut UnicornHandle) -> Result<(), EmulatorError>;
     fn as_any(&mut self) -> &mut dyn Any;
     fn name(&self) -> String;
+
+    /// Syscall ranges and memory regions owned by the feature
+    fn descriptor(&self) -> FeatureDescriptor {
+        FeatureDescriptor { syscalls: Vec::new(), mmio: Vec::new() }
+    }
+
+    /// Goes back to the power-on state. Emulator memory and registers are reset by the caller
+    fn reset(&mut self, _emulator: &mut UnicornHandle) -> Result<(), EmulatorError> {
+        Ok(())
+    }
+
+    /// Called by the main loop every time the emulator yields to the host
+    fn on_frame(&mut self, _emulator: &mut UnicornHandle, _requests: &mut FrameRequests) -> Result<(), EmulatorError> {
+        Ok(())
+    }
+
+    /// Serialises the feature state that isn't stored in emulator memory or registers
+    fn save_state(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    /// Restores the output of `save_state`. Emulator memory and registers are already restored
+    fn load_state(&mut self, _emulator: &mut UnicornHandle, _state: &[u8]) -> Result<(), EmulatorError> {
+        Ok(())
+    }
 }
@@ -0,0 +1,67 @@
+use crate::error::EmulatorError;
+
+/// A file or directory of a drive listing
+#[derive(Debug, Clone)]
+pub struct DriveEntry {
+    pub name: String,
+    /// 0 for directories
+    pub size: u32,
+    pub is_dir: bool,
+    /// First sector of the file on the disc, `None` for directories and for backends without
+    /// real sectors
+    pub sector: Option<u64>,
+}
+
+/// Where a [super::Drive] reads its files from
+///
+/// Paths are `/`-separated and relative to the root of the disc. Names are compared
+/// case-insensitively, like on an ISO9660 disc, and backends are read-only.
+pub trait DriveBackend {
+    /// The file or directory at `path`, an empty path is the root directory
+    fn stat(&self, path: &str) -> Result<DriveEntry, EmulatorError>;
+    /// Entries of the directory at `path`, without `.` and `..`
+    fn list_directory(&self, path: &str) -> Result<Vec<DriveEntry>, EmulatorError>;
+    /// Looks `path` up once, for reading it repeatedly
+    fn open(&self, path: &str) -> Result<Box<dyn DriveFile>, EmulatorError>;
+}
+
+/// A file opened on a [DriveBackend]
+pub trait DriveFile {
+    fn size(&self) -> u32;
+    /// Reads up to `count` bytes from `offset`, fewer (or none) near the end of the file
+    fn read_at(&self, offset: u32, count: u32) -> Result<Vec<u8>, EmulatorError>;
+    /// First sector of the file on the disc, see [DriveEntry::sector]
+    fn sector(&self) -> Option<u64> {
+        None
+    }
+}
+
+/// The names in `path`, skipping empty and `.` components so `./main.elf`, `/main.elf` and
+/// `main.elf` are the same file
+pub(crate) fn components(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|name| !name.is_empty() && *name != ".")
+}
+
+/// Whether `other` is the path whose components are `path`, comparing names with [same_name]
+pub(crate) fn same_path(path: &[String], other: &str) -> bool {
+    let other: Vec<&str> = components(other).collect();
+    path.len() == other.len() && path.iter().zip(other).all(|(a, b)| same_name(a, b))
+}
+
+/// Compares names the way ISO9660 intends: case-insensitively and without the `;1` version
+/// suffix
+pub(crate) fn same_name(identifier: &str, name: &str) -> bool {
+    strip_version(identifier).eq_ignore_ascii_case(strip_version(name))
+}
+
+/// `path` normalized so paths [same_path] considers equal are equal, for keying maps
+pub(crate) fn path_key(path: &str) -> String {
+    components(path)
+        .map(|name| strip_version(name).to_ascii_lowercase())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+pub(crate) fn strip_version(identifier: &str) -> &str {
+    identifier.split(';').next().unwrap_or(identifier)
+}
@@ -0,0 +1,178 @@
+use std::io::{self, Read, Seek, SeekFrom};
+use flate2::read::DeflateDecoder;
+use crate::error::EmulatorError;
+use super::image::seek_position;
+
+pub const MAGIC: &[u8] = b"CISO";
+
+/// Index entries with this bit set point to a block stored uncompressed
+const PLAIN_BLOCK: u32 = 0x8000_0000;
+
+/// Largest block size accepted, real images use 2048 bytes
+const MAX_BLOCK_SIZE: u64 = 1 << 24;
+
+/// A CISO (`.cso`) image: the disc cut in fixed-size blocks, each deflated on its own
+///
+/// An index of block offsets follows the 24-byte header, so any block can be decompressed
+/// without touching the others. The last block read is kept, sequential reads decompress each
+/// block once.
+pub struct CompressedImage<T: Read + Seek> {
+    inner: T,
+    total_bytes: u64,
+    block_size: u64,
+    /// Shift applied to the offsets of the index
+    align: u8,
+    /// One more entry than there are blocks, the last one is where the last block ends
+    index: Vec<u32>,
+    cached: Option<(u64, Vec<u8>)>,
+    position: u64,
+}
+
+fn invalid_cso(reason: &str) -> EmulatorError {
+    EmulatorError::InvalidImage(format!("compressed image: {}", reason))
+}
+
+fn corrupt_block(block: u64) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("compressed image block {} is corrupt", block))
+}
+
+impl<T: Read + Seek> CompressedImage<T> {
+    pub fn new(mut inner: T) -> Result<CompressedImage<T>, EmulatorError> {
+        let mut header = [0u8; 24];
+        inner.seek(SeekFrom::Start(0))?;
+        inner.read_exact(&mut header)?;
+        if &header[..4] != MAGIC {
+            return Err(invalid_cso("bad magic"));
+        }
+        let header_size = u32::from_le_bytes(header[4..8].try_into().unwrap()) as u64;
+        let total_bytes = u64::from_le_bytes(header[8..16].try_into().unwrap());
+        let block_size = u32::from_le_bytes(header[16..20].try_into().unwrap()) as u64;
+        let version = header[20];
+        let align = header[21];
+        if version > 1 {
+            return Err(invalid_cso("only version 0 and 1 are supported"));
+        }
+        if block_size == 0 || block_size > MAX_BLOCK_SIZE || align >= 32 {
+            return Err(invalid_cso("bad block size or alignment"));
+        }
+
+        let blocks = total_bytes / block_size + (total_bytes % block_size != 0) as u64;
+        // Version 0 writers leave the header size at 0
+        let index_start = header_size.max(24);
+        let index_end = blocks.checked_add(1)
+            .and_then(|entries| entries.checked_mul(4))
+            .and_then(|size| size.checked_add(index_start));
+        let file_size = inner.seek(SeekFrom::End(0))?;
+        let index_end = match index_end {
+            Some(end) if end <= file_size => end,
+            _ => return Err(invalid_cso("the block index doesn't fit in the file")),
+        };
+        inner.seek(SeekFrom::Start(index_start))?;
+        let mut entries = vec![0u8; (index_end - index_start) as usize];
+        inner.read_exact(&mut entries)?;
+        let index = entries.chunks_exact(4)
+            .map(|entry| u32::from_le_bytes(entry.try_into().unwrap()))
+            .collect();
+        Ok(CompressedImage { inner, total_bytes, block_size, align, index, cached: None, position: 0 })
+    }
+
+    fn block_offset(&self, entry: u32) -> u64 {
+        ((entry & !PLAIN_BLOCK) as u64) << self.align
+    }
+
+    /// Reads and decompresses `block`, or returns it from the cache
+    fn block(&mut self, block: u64) -> io::Result<&[u8]> {
+        if self.cached.as_ref().map(|(cached, _)| *cached) != Some(block) {
+            let entry = self.index[block as usize];
+            let start = self.block_offset(entry);
+            let end = self.block_offset(self.index[block as usize + 1]);
+            let stored = end.checked_sub(start).ok_or_else(|| corrupt_block(block))?;
+            let size = self.block_size.min(self.total_bytes - block * self.block_size) as usize;
+
+            self.inner.seek(SeekFrom::Start(start))?;
+            let mut data = Vec::with_capacity(size);
+            if entry & PLAIN_BLOCK != 0 {
+                self.inner.by_ref().take(size as u64).read_to_end(&mut data)?;
+            } else {
+                DeflateDecoder::new(self.inner.by_ref().take(stored)).take(size as u64).read_to_end(&mut data)?;
+            }
+            if data.len() != size {
+                return Err(corrupt_block(block));
+            }
+            self.cached = Some((block, data));
+        }
+        Ok(self.cached.as_ref().map(|(_, data)| data.as_slice()).unwrap())
+    }
+}
+
+impl<T: Read + Seek> Read for CompressedImage<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.total_bytes {
+            return Ok(0);
+        }
+        let block = self.position / self.block_size;
+        let offset = (self.position % self.block_size) as usize;
+        let data = self.block(block)?;
+        let count = buf.len().min(data.len() - offset);
+        buf[..count].copy_from_slice(&data[offset..offset + count]);
+        self.position += count as u64;
+        Ok(count)
+    }
+}
+
+impl<T: Read + Seek> Seek for CompressedImage<T> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.position = seek_position(pos, self.position, self.total_bytes)?;
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Write};
+    use flate2::write::DeflateEncoder;
+    use flate2::Compression;
+    use super::*;
+
+    fn header(total_bytes: u64, block_size: u32) -> Vec<u8> {
+        let mut image = MAGIC.to_vec();
+        image.extend_from_slice(&24u32.to_le_bytes());
+        image.extend_from_slice(&total_bytes.to_le_bytes());
+        image.extend_from_slice(&block_size.to_le_bytes());
+        image.extend_from_slice(&[1, 0, 0, 0]);
+        image
+    }
+
+    #[test]
+    fn plain_and_deflated_blocks() {
+        let data: Vec<u8> = (0..28u8).collect();
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::best());
+        encoder.write_all(&data[16..]).unwrap();
+        let deflated = encoder.finish().unwrap();
+
+        let mut image = header(data.len() as u64, 16);
+        let first = 24 + 3 * 4;
+        for entry in [first | PLAIN_BLOCK, first + 16, first + 16 + deflated.len() as u32] {
+            image.extend_from_slice(&entry.to_le_bytes());
+        }
+        image.extend_from_slice(&data[..16]);
+        image.extend_from_slice(&deflated);
+
+        let mut cso = CompressedImage::new(Cursor::new(image)).unwrap();
+        let mut read = Vec::new();
+        cso.read_to_end(&mut read).unwrap();
+        assert_eq!(read, data);
+
+        cso.seek(SeekFrom::Start(14)).unwrap();
+        let mut straddling = [0u8; 4];
+        cso.read_exact(&mut straddling).unwrap();
+        assert_eq!(straddling, [14, 15, 16, 17]);
+    }
+
+    #[test]
+    fn index_larger_than_the_file() {
+        let mut image = header(u64::MAX, 1);
+        image.extend_from_slice(&[0; 8]);
+        assert!(CompressedImage::new(Cursor::new(image)).is_err());
+    }
+}
@@ -0,0 +1,194 @@
+use std::fs::{self, File};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use crate::error::EmulatorError;
+use super::image::{DiscImage, RawSectors, SectorLayout, RAW_SECTOR_SIZE, SECTOR_SIZE};
+
+/// What a track holds, from its `TRACK` line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrackMode {
+    Audio,
+    /// Mode1 data, `MODE1/2048` (cooked) or `MODE1/2352` (raw)
+    Mode1 { raw: bool },
+    /// Mode2 Form1 data, `MODE2/2336` or `MODE2/2352`
+    Mode2 { raw: bool },
+}
+
+impl TrackMode {
+    fn parse(mode: &str) -> Option<TrackMode> {
+        match mode.to_ascii_uppercase().as_str() {
+            "AUDIO" => Some(TrackMode::Audio),
+            "MODE1/2048" => Some(TrackMode::Mode1 { raw: false }),
+            "MODE1/2352" => Some(TrackMode::Mode1 { raw: true }),
+            "MODE2/2336" => Some(TrackMode::Mode2 { raw: false }),
+            "MODE2/2352" => Some(TrackMode::Mode2 { raw: true }),
+            _ => None,
+        }
+    }
+
+    fn layout(&self) -> SectorLayout {
+        match self {
+            TrackMode::Audio => SectorLayout { size: RAW_SECTOR_SIZE, data_offset: 0 },
+            TrackMode::Mode1 { raw: false } => SectorLayout { size: SECTOR_SIZE, data_offset: 0 },
+            TrackMode::Mode1 { raw: true } => SectorLayout { size: RAW_SECTOR_SIZE, data_offset: 16 },
+            TrackMode::Mode2 { raw: false } => SectorLayout { size: 2336, data_offset: 8 },
+            TrackMode::Mode2 { raw: true } => SectorLayout { size: RAW_SECTOR_SIZE, data_offset: 24 },
+        }
+    }
+}
+
+/// A track of a cue sheet
+#[derive(Debug, Clone)]
+pub struct Track {
+    pub number: u32,
+    pub mode: TrackMode,
+    /// The BIN file holding it
+    pub file: PathBuf,
+    /// Its `INDEX 01`, in sectors from the start of `file`
+    pub start: u64,
+}
+
+/// A cue sheet and the tracks it describes
+///
+/// Only what locating the tracks needs is read: `FILE`, `TRACK` and `INDEX 01`. Pregaps,
+/// CD-TEXT and the other commands are skipped.
+#[derive(Debug, Clone)]
+pub struct CueSheet {
+    pub tracks: Vec<Track>,
+}
+
+fn invalid_cue(line: usize, reason: &str) -> EmulatorError {
+    EmulatorError::InvalidImage(format!("cue sheet line {}: {}", line + 1, reason))
+}
+
+/// `mm:ss:ff` to a sector count, 75 frames a second
+fn parse_msf(msf: &str) -> Option<u64> {
+    let mut parts = msf.split(':').map(|part| part.parse::<u64>().ok());
+    let (minutes, seconds, frames) = (parts.next()??, parts.next()??, parts.next()??);
+    if parts.next().is_some() || seconds >= 60 || frames >= 75 {
+        return None;
+    }
+    minutes.checked_mul(60)?.checked_add(seconds)?.checked_mul(75)?.checked_add(frames)
+}
+
+/// The file name of a `FILE` line, quoted or not, without the file type
+fn parse_file_name(arguments: &str) -> Option<&str> {
+    let arguments = arguments.trim();
+    if let Some(quoted) = arguments.strip_prefix('"') {
+        quoted.split('"').next()
+    } else {
+        arguments.split_whitespace().next()
+    }
+}
+
+impl CueSheet {
+    /// Reads the cue sheet at `path`, BIN files are relative to its directory
+    pub fn parse(path: &Path) -> Result<CueSheet, EmulatorError> {
+        let directory = path.parent().unwrap_or_else(|| Path::new("."));
+        Self::parse_text(&fs::read_to_string(path)?, directory)
+    }
+
+    fn parse_text(text: &str, directory: &Path) -> Result<CueSheet, EmulatorError> {
+        let mut tracks = Vec::new();
+        let mut file: Option<PathBuf> = None;
+        let mut pending: Option<(u32, TrackMode)> = None;
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim().trim_start_matches('\u{feff}');
+            let (command, arguments) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            match command.to_ascii_uppercase().as_str() {
+                "FILE" => {
+                    let name = parse_file_name(arguments).ok_or_else(|| invalid_cue(index, "FILE without a name"))?;
+                    file = Some(directory.join(name));
+                }
+                "TRACK" => {
+                    let mut arguments = arguments.split_whitespace();
+                    let number = arguments.next().and_then(|number| number.parse().ok())
+                        .ok_or_else(|| invalid_cue(index, "bad track number"))?;
+                    let mode = arguments.next().and_then(TrackMode::parse)
+                        .ok_or_else(|| invalid_cue(index, "unsupported track mode"))?;
+                    pending = Some((number, mode));
+                }
+                "INDEX" => {
+                    let mut arguments = arguments.split_whitespace();
+                    if arguments.next() != Some("01") {
+                        continue;
+                    }
+                    let start = arguments.next().and_then(parse_msf)
+                        .ok_or_else(|| invalid_cue(index, "bad INDEX position"))?;
+                    let (number, mode) = pending.take().ok_or_else(|| invalid_cue(index, "INDEX outside of a track"))?;
+                    let file = file.clone().ok_or_else(|| invalid_cue(index, "TRACK before any FILE"))?;
+                    tracks.push(Track { number, mode, file, start });
+                }
+                _ => {}
+            }
+        }
+        Ok(CueSheet { tracks })
+    }
+
+    /// The first data track, where the filesystem of the disc is, and the range of bytes it
+    /// takes in its BIN file
+    ///
+    /// The track ends where the next track of the same file starts, or at the end of the file.
+    pub fn data_track(&self) -> Result<(&Track, Range<u64>), EmulatorError> {
+        let (position, track) = self.tracks.iter().enumerate()
+            .find(|(_, track)| track.mode != TrackMode::Audio)
+            .ok_or_else(|| EmulatorError::InvalidImage(String::from("the cue sheet has no data track")))?;
+        let size = track.mode.layout().size;
+        let out_of_bounds = || EmulatorError::InvalidImage(format!("track {} starts past the end of any disc", track.number));
+        let start = track.start.checked_mul(size).ok_or_else(out_of_bounds)?;
+        let end = match self.tracks.get(position + 1).filter(|next| next.file == track.file) {
+            Some(next) => next.start.checked_mul(size).ok_or_else(out_of_bounds)?,
+            None => fs::metadata(&track.file)?.len(),
+        };
+        Ok((track, start..end.max(start)))
+    }
+
+    /// The data of the [data track](CueSheet::data_track)
+    pub fn open_data_track(&self) -> Result<Box<dyn DiscImage>, EmulatorError> {
+        let (track, range) = self.data_track()?;
+        let file = File::open(&track.file)?;
+        Ok(Box::new(RawSectors::new(file, range.start, range.end - range.start, track.mode.layout())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pregaps_and_several_files() {
+        let text = "\u{feff}FILE \"Game (Track 1).bin\" BINARY\n\
+            TRACK 01 MODE2/2352\n\
+            INDEX 01 00:00:00\n\
+            TRACK 02 AUDIO\n\
+            INDEX 00 10:00:00\n\
+            INDEX 01 10:02:00\n\
+            FILE music.bin BINARY\n\
+            TRACK 03 AUDIO\n\
+            PREGAP 00:02:00\n\
+            INDEX 00 00:00:00\n\
+            INDEX 01 00:01:74\n";
+        let sheet = CueSheet::parse_text(text, Path::new("discs")).unwrap();
+        let tracks: Vec<_> = sheet.tracks.iter()
+            .map(|track| (track.number, track.mode, track.file.clone(), track.start))
+            .collect();
+        assert_eq!(tracks, vec![
+            (1, TrackMode::Mode2 { raw: true }, Path::new("discs").join("Game (Track 1).bin"), 0),
+            (2, TrackMode::Audio, Path::new("discs").join("Game (Track 1).bin"), (10 * 60 + 2) * 75),
+            (3, TrackMode::Audio, Path::new("discs").join("music.bin"), 75 + 74),
+        ]);
+    }
+
+    #[test]
+    fn bad_positions() {
+        for position in ["00:60:00", "00:00:75", "99999999999999999:00:00", "00:00", "00:00:00:00"] {
+            let text = format!("FILE a.bin BINARY\nTRACK 01 MODE1/2048\nINDEX 01 {}\n", position);
+            assert!(CueSheet::parse_text(&text, Path::new(".")).is_err(), "{}", position);
+        }
+    }
+
+    #[test]
+    fn track_before_file() {
+        assert!(CueSheet::parse_text("TRACK 01 MODE1/2048\nINDEX 01 00:00:00\n", Path::new(".")).is_err());
+    }
+}
@@ -0,0 +1,176 @@
+use std::cell::RefCell;
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use crate::error::EmulatorError;
+use super::base::{components, same_name, DriveBackend, DriveEntry, DriveFile};
+
+/// A directory of the host served as the disc, for iterating on a game without building an
+/// image every time
+///
+/// Lookups ignore case like on a disc, `..` can't leave the root and neither can symbolic
+/// links. Only regular files and directories are visible, and nothing is ever opened for
+/// writing.
+///
+/// Host files have no sectors, the [Drive](super::Drive) lays them out one after the other
+/// in listing order for the drive timing, the way mastering tools would write them.
+pub struct DirectoryBackend {
+    root: PathBuf,
+}
+
+impl DirectoryBackend {
+    pub fn new(path: &Path) -> Result<DirectoryBackend, EmulatorError> {
+        let root = path.canonicalize()?;
+        if !root.is_dir() {
+            return Err(EmulatorError::InvalidImage(format!("{} isn't a directory", path.display())));
+        }
+        Ok(DirectoryBackend { root })
+    }
+
+    /// The host path of `path`, resolving each component case-insensitively
+    fn lookup(&self, path: &str) -> Result<PathBuf, EmulatorError> {
+        let not_found = || EmulatorError::FileNotFound(path.to_string());
+        let mut host_path = self.root.clone();
+        for name in components(path) {
+            if name == ".." {
+                if host_path == self.root {
+                    return Err(not_found());
+                }
+                host_path.pop();
+                continue;
+            }
+            let exact = host_path.join(name);
+            host_path = if exact.exists() {
+                exact
+            } else {
+                fs::read_dir(&host_path).map_err(|_| not_found())?
+                    .filter_map(|child| child.ok())
+                    .find(|child| same_name(&child.file_name().to_string_lossy(), name))
+                    .map(|child| child.path())
+                    .ok_or_else(not_found)?
+            };
+        }
+        let resolved = host_path.canonicalize().map_err(|_| not_found())?;
+        if !resolved.starts_with(&self.root) {
+            return Err(not_found());
+        }
+        Ok(resolved)
+    }
+
+    fn entry(host_path: &Path) -> Option<DriveEntry> {
+        let metadata = fs::metadata(host_path).ok()?;
+        let name = host_path.file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_else(|| String::from("."));
+        if metadata.is_dir() {
+            Some(DriveEntry { name, size: 0, is_dir: true, sector: None })
+        } else if metadata.is_file() && metadata.len() <= u32::MAX as u64 {
+            Some(DriveEntry { name, size: metadata.len() as u32, is_dir: false, sector: None })
+        } else {
+            None
+        }
+    }
+}
+
+impl DriveBackend for DirectoryBackend {
+    fn stat(&self, path: &str) -> Result<DriveEntry, EmulatorError> {
+        Self::entry(&self.lookup(path)?).ok_or_else(|| EmulatorError::FileNotFound(path.to_string()))
+    }
+
+    fn list_directory(&self, path: &str) -> Result<Vec<DriveEntry>, EmulatorError> {
+        let directory = self.lookup(path)?;
+        if !directory.is_dir() {
+            return Err(EmulatorError::FileNotFound(path.to_string()));
+        }
+        let mut entries = Vec::new();
+        for child in fs::read_dir(&directory)? {
+            let child = child?.path();
+            let inside = child.canonicalize().map(|resolved| resolved.starts_with(&self.root)).unwrap_or(false);
+            if let Some(entry) = Self::entry(&child).filter(|_| inside) {
+                entries.push(entry);
+            }
+        }
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(entries)
+    }
+
+    fn open(&self, path: &str) -> Result<Box<dyn DriveFile>, EmulatorError> {
+        let host_path = self.lookup(path)?;
+        match Self::entry(&host_path) {
+            Some(entry) if !entry.is_dir => Ok(Box::new(HostFile {
+                file: RefCell::new(File::open(host_path)?),
+                size: entry.size,
+            })),
+            _ => Err(EmulatorError::FileNotFound(path.to_string())),
+        }
+    }
+}
+
+/// A file of the host opened read-only. Its size is the one it had when it was opened
+struct HostFile {
+    file: RefCell<File>,
+    size: u32,
+}
+
+impl DriveFile for HostFile {
+    fn size(&self) -> u32 {
+        self.size
+    }
+
+    fn read_at(&self, offset: u32, count: u32) -> Result<Vec<u8>, EmulatorError> {
+        let count = count.min(self.size.saturating_sub(offset));
+        let mut file = self.file.borrow_mut();
+        file.seek(SeekFrom::Start(offset as u64))?;
+        let mut bytes = Vec::with_capacity(count as usize);
+        file.by_ref().take(count as u64).read_to_end(&mut bytes)?;
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A root with `sub/file` and `x` in it, and a file `x` next to it that must stay out of reach
+    fn directories(name: &str) -> PathBuf {
+        let base = std::env::temp_dir().join(format!("armchine-directory-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&base);
+        fs::create_dir_all(base.join("root").join("sub")).unwrap();
+        fs::write(base.join("root").join("sub").join("file"), b"inside").unwrap();
+        fs::write(base.join("root").join("x"), b"root").unwrap();
+        fs::write(base.join("x"), b"outside").unwrap();
+        base
+    }
+
+    fn not_found<T>(result: Result<T, EmulatorError>) -> bool {
+        matches!(result, Err(EmulatorError::FileNotFound(_)))
+    }
+
+    #[test]
+    fn parent_components_stay_inside() {
+        let base = directories("parents");
+        let backend = DirectoryBackend::new(&base.join("root")).unwrap();
+        assert!(not_found(backend.stat("../x")));
+        assert!(not_found(backend.stat("sub/../../x")));
+        assert!(not_found(backend.open("../x")));
+        assert_eq!(backend.open("sub/../X").unwrap().read_at(0, 16).unwrap(), b"root");
+        assert_eq!(backend.open("SUB/FILE").unwrap().read_at(0, 16).unwrap(), b"inside");
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_stay_inside() {
+        use std::os::unix::fs::symlink;
+        let base = directories("symlinks");
+        symlink(base.join("x"), base.join("root").join("out")).unwrap();
+        symlink(&base, base.join("root").join("up")).unwrap();
+        let backend = DirectoryBackend::new(&base.join("root")).unwrap();
+        assert!(not_found(backend.stat("out")));
+        assert!(not_found(backend.open("out")));
+        assert!(not_found(backend.open("up/x")));
+        let names: Vec<String> = backend.list_directory("").unwrap().into_iter().map(|entry| entry.name).collect();
+        assert_eq!(names, vec!["sub", "x"]);
+        fs::remove_dir_all(base).unwrap();
+    }
+}
@@ -0,0 +1,169 @@
+use std::fs::{self, File};
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+use std::path::Path;
+use crate::error::EmulatorError;
+use super::{cso, cue, patch};
+use super::patch::Patch;
+
+/// The 2048-byte data sectors of a disc, read as one stream by the ISO9660 parser
+pub trait DiscImage: Read + Seek {}
+
+impl<T: Read + Seek> DiscImage for T {}
+
+/// Size of the data part of a sector, what ISO9660 addresses
+pub const SECTOR_SIZE: u64 = 2048;
+/// Size of a whole sector of a raw dump, headers and error correction included
+pub const RAW_SECTOR_SIZE: u64 = 2352;
+
+/// Starts every raw sector
+const SYNC: [u8; 12] = [0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00];
+
+/// Keywords a cue sheet can start with
+const CUE_KEYWORDS: [&str; 6] = ["FILE", "REM", "CATALOG", "TITLE", "PERFORMER", "CDTEXTFILE"];
+
+/// Opens the disc image at `path`, telling its format from its first bytes:
+///
+/// - a `CISO` magic is a [block-compressed image](cso::CompressedImage)
+/// - the sync pattern of a raw sector is a dump of 2352-byte sectors, Mode1 or Mode2 Form1
+/// - text starting with a cue sheet keyword is a [cue sheet](cue::CueSheet), the disc is its
+///   first data track
+/// - anything else is a plain ISO9660 image
+pub fn open(path: &Path) -> Result<Box<dyn DiscImage>, EmulatorError> {
+    detect(File::open(path)?, path)
+}
+
+/// Opens the disc image at `path` after applying `patches` to the file, in order
+///
+/// The patched image is kept in memory. Cue sheets can't be patched, the BIN file of their
+/// data track can be booted and patched directly instead.
+pub fn open_patched(path: &Path, patches: &[Patch]) -> Result<Box<dyn DiscImage>, EmulatorError> {
+    if patches.is_empty() {
+        return open(path);
+    }
+    let image = patch::apply_all(patches, fs::read(path)?)?;
+    if is_cue_sheet(&image[..image.len().min(SECTOR_SIZE as usize)]) {
+        return Err(EmulatorError::InvalidPatch(String::from("cue sheets can't be patched, boot and patch their BIN file")));
+    }
+    detect(Cursor::new(image), path)
+}
+
+fn detect<T: Read + Seek + 'static>(mut file: T, path: &Path) -> Result<Box<dyn DiscImage>, EmulatorError> {
+    let mut header = Vec::new();
+    file.by_ref().take(SECTOR_SIZE).read_to_end(&mut header)?;
+    file.seek(SeekFrom::Start(0))?;
+
+    if header.starts_with(cso::MAGIC) {
+        Ok(Box::new(cso::CompressedImage::new(file)?))
+    } else if header.starts_with(&SYNC) {
+        let layout = sector_layout(&header)?;
+        let length = file.seek(SeekFrom::End(0))?;
+        Ok(Box::new(RawSectors::new(file, 0, length, layout)))
+    } else if is_cue_sheet(&header) {
+        cue::CueSheet::parse(path)?.open_data_track()
+    } else {
+        Ok(Box::new(file))
+    }
+}
+
+/// Whether `header`, the start of an image, is the start of a cue sheet
+pub(crate) fn is_cue_sheet(header: &[u8]) -> bool {
+    let text = String::from_utf8_lossy(header);
+    let text = text.trim_start_matches('\u{feff}').trim_start();
+    CUE_KEYWORDS.iter().any(|keyword| text.starts_with(keyword))
+}
+
+/// Submode bit of the Mode2 subheader set on Form2 sectors
+const SUBMODE_FORM2: u8 = 0x20;
+
+/// Where the 2048 data bytes are in the raw sectors of a dump, given by the header of its first
+/// sector
+fn sector_layout(sector: &[u8]) -> Result<SectorLayout, EmulatorError> {
+    let mode = sector.get(15).copied().unwrap_or(0);
+    match mode {
+        1 => Ok(SectorLayout { size: RAW_SECTOR_SIZE, data_offset: 16 }),
+        // Form1 only, after the 8-byte subheader
+        2 if sector.get(18).map_or(false, |submode| submode & SUBMODE_FORM2 == 0) => {
+            Ok(SectorLayout { size: RAW_SECTOR_SIZE, data_offset: 24 })
+        }
+        2 => Err(EmulatorError::InvalidImage(String::from("Mode2 Form2 sectors hold no filesystem"))),
+        _ => Err(EmulatorError::InvalidImage(format!("unsupported sector mode {}", mode))),
+    }
+}
+
+/// Size of the sectors of a track and where their data starts
+#[derive(Debug, Clone, Copy)]
+pub struct SectorLayout {
+    pub size: u64,
+    pub data_offset: u64,
+}
+
+/// The data of a run of sectors, without their headers and error correction
+pub struct RawSectors<T: Read + Seek> {
+    inner: T,
+    /// Byte offset of the first sector in `inner`
+    start: u64,
+    sectors: u64,
+    layout: SectorLayout,
+    position: u64,
+}
+
+impl<T: Read + Seek> RawSectors<T> {
+    /// Sectors of `inner` from byte `start`, up to `length` bytes
+    pub fn new(inner: T, start: u64, length: u64, layout: SectorLayout) -> RawSectors<T> {
+        RawSectors { inner, start, sectors: length / layout.size, layout, position: 0 }
+    }
+
+    fn len(&self) -> u64 {
+        self.sectors * SECTOR_SIZE
+    }
+}
+
+impl<T: Read + Seek> Read for RawSectors<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.len() {
+            return Ok(0);
+        }
+        let sector = self.position / SECTOR_SIZE;
+        let offset = self.position % SECTOR_SIZE;
+        let count = (buf.len() as u64).min(SECTOR_SIZE - offset) as usize;
+        let address = self.start + sector * self.layout.size + self.layout.data_offset + offset;
+        self.inner.seek(SeekFrom::Start(address))?;
+        let read = self.inner.read(&mut buf[..count])?;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl<T: Read + Seek> Seek for RawSectors<T> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.position = seek_position(pos, self.position, self.len())?;
+        Ok(self.position)
+    }
+}
+
+/// The position `pos` moves to in a stream of `len` bytes currently at `position`
+pub(crate) fn seek_position(pos: SeekFrom, position: u64, len: u64) -> io::Result<u64> {
+    let target = match pos {
+        SeekFrom::Start(offset) => Some(offset),
+        SeekFrom::Current(offset) => (position as i64).checked_add(offset).filter(|p| *p >= 0).map(|p| p as u64),
+        SeekFrom::End(offset) => (len as i64).checked_add(offset).filter(|p| *p >= 0).map(|p| p as u64),
+    };
+    target.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek before the start of the image"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seek_positions() {
+        assert_eq!(seek_position(SeekFrom::Start(5), 2, 10).unwrap(), 5);
+        assert_eq!(seek_position(SeekFrom::Current(-2), 2, 10).unwrap(), 0);
+        assert_eq!(seek_position(SeekFrom::Current(3), 2, 10).unwrap(), 5);
+        assert_eq!(seek_position(SeekFrom::End(-4), 2, 10).unwrap(), 6);
+        // Past the end is allowed, reads there return nothing
+        assert_eq!(seek_position(SeekFrom::End(4), 2, 10).unwrap(), 14);
+        assert!(seek_position(SeekFrom::Current(-3), 2, 10).is_err());
+        assert!(seek_position(SeekFrom::End(-11), 2, 10).is_err());
+    }
+}
@@ -0,0 +1,116 @@
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use iso9660::{DirectoryEntry, ISO9660, ISOFile};
+use crate::error::EmulatorError;
+use super::base::{components, same_name, strip_version, DriveBackend, DriveEntry, DriveFile};
+use super::image::{self, DiscImage};
+use super::patch::Patch;
+
+fn invalid_image(error: impl std::fmt::Debug) -> EmulatorError {
+    EmulatorError::InvalidImage(format!("{:?}", error))
+}
+
+fn drive_entry(entry: &DirectoryEntry<Box<dyn DiscImage>>) -> DriveEntry {
+    match entry {
+        DirectoryEntry::Directory(dir) => DriveEntry { name: dir.identifier.clone(), size: 0, is_dir: true, sector: None },
+        DirectoryEntry::File(file) => DriveEntry {
+            name: strip_version(&file.identifier).to_string(),
+            size: file.size(),
+            is_dir: false,
+            sector: Some(entry.header().extent_loc as u64),
+        },
+    }
+}
+
+/// An ISO9660 filesystem, in any of the image formats [image::open] recognizes
+pub struct IsoBackend {
+    archive: ISO9660<Box<dyn DiscImage>>,
+}
+
+impl IsoBackend {
+    /// Opens the image at `path`, applying `patches` to the image file first
+    pub fn new(path: &Path, patches: &[Patch]) -> Result<IsoBackend, EmulatorError> {
+        let archive = ISO9660::new(image::open_patched(path, patches)?).map_err(invalid_image)?;
+        Ok(IsoBackend { archive })
+    }
+
+    fn lookup(&self, path: &str) -> Result<DirectoryEntry<Box<dyn DiscImage>>, EmulatorError> {
+        let not_found = || EmulatorError::FileNotFound(path.to_string());
+        let mut entry = DirectoryEntry::Directory(self.archive.root.clone());
+        for name in components(path) {
+            let directory = match entry {
+                DirectoryEntry::Directory(directory) => directory,
+                DirectoryEntry::File(_) => return Err(not_found()),
+            };
+            let mut found = None;
+            for child in directory.contents() {
+                let child = child.map_err(invalid_image)?;
+                let identifier = match &child {
+                    DirectoryEntry::Directory(dir) => &dir.identifier,
+                    DirectoryEntry::File(file) => &file.identifier,
+                };
+                if same_name(identifier, name) {
+                    found = Some(child);
+                    break;
+                }
+            }
+            entry = found.ok_or_else(not_found)?;
+        }
+        Ok(entry)
+    }
+}
+
+impl DriveBackend for IsoBackend {
+    fn stat(&self, path: &str) -> Result<DriveEntry, EmulatorError> {
+        Ok(drive_entry(&self.lookup(path)?))
+    }
+
+    fn list_directory(&self, path: &str) -> Result<Vec<DriveEntry>, EmulatorError> {
+        let directory = match self.lookup(path)? {
+            DirectoryEntry::Directory(directory) => directory,
+            DirectoryEntry::File(_) => return Err(EmulatorError::FileNotFound(path.to_string())),
+        };
+        let mut entries = Vec::new();
+        for child in directory.contents() {
+            let entry = drive_entry(&child.map_err(invalid_image)?);
+            if entry.name != "." && entry.name != ".." {
+                entries.push(entry);
+            }
+        }
+        Ok(entries)
+    }
+
+    fn open(&self, path: &str) -> Result<Box<dyn DriveFile>, EmulatorError> {
+        let entry = self.lookup(path)?;
+        let sector = entry.header().extent_loc as u64;
+        match entry {
+            DirectoryEntry::File(file) => Ok(Box::new(IsoFile { file, sector })),
+            DirectoryEntry::Directory(_) => Err(EmulatorError::FileNotFound(path.to_string())),
+        }
+    }
+}
+
+/// Keeps the directory entry so reads seek straight to their offset
+struct IsoFile {
+    file: ISOFile<Box<dyn DiscImage>>,
+    sector: u64,
+}
+
+impl DriveFile for IsoFile {
+    fn size(&self) -> u32 {
+        self.file.size()
+    }
+
+    fn read_at(&self, offset: u32, count: u32) -> Result<Vec<u8>, EmulatorError> {
+        let count = count.min(self.size().saturating_sub(offset));
+        let mut reader = self.file.read();
+        reader.seek(SeekFrom::Start(offset as u64))?;
+        let mut bytes = vec![0u8; count as usize];
+        reader.read_exact(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn sector(&self) -> Option<u64> {
+        Some(self.sector)
+    }
+}
@@ -0,0 +1,770 @@
+use std::any::Any;
+use std::borrow::{Borrow, BorrowMut};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::ops::{Index, Range};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use capstone::arch::tms320c64x::Tms320c64xMemDisplayType::Register;
+use unicorn::{RegisterARM, UnicornHandle};
+use crate::config::DriveConfig;
+use crate::features::{EmulatorFeature, FeatureDescriptor, FrameRequests};
+use crate::error::EmulatorError;
+use crate::newlib;
+use crate::savestate::{StateReader, StateWriter};
+use crate::syscalls::{read_guest_string, SyscallDispatcher, SyscallError, SyscallResult};
+
+mod base;
+pub mod image;
+pub mod cue;
+pub mod cso;
+pub mod iso;
+pub mod directory;
+pub mod overlay;
+pub mod patch;
+pub mod patched;
+pub mod timing;
+
+pub use base::{DriveBackend, DriveEntry, DriveFile};
+use timing::DriveTiming;
+
+/// What goes over a disc when it's inserted, for modding and fixing it without remastering it
+#[derive(Debug, Clone, Default)]
+pub struct DiscLayers {
+    /// Host directories whose files shadow the disc's, see [overlay::OverlayBackend]. The last
+    /// one is on top
+    pub overlays: Vec<PathBuf>,
+    /// Applied in order, to the image file or to files on the disc, see [patch::PatchSpec]
+    pub patches: Vec<patch::PatchSpec>,
+}
+
+/// Sectors before the first file: the system area and the volume descriptors
+const FIRST_FILE_SECTOR: u64 = 18;
+
+/// The files of a disc, read through the [DriveBackend] matching what was inserted
+///
+/// For the drive timing, files of a disc image are where its directory entries say. Files
+/// with no sectors (from a host directory or an overlay) are laid out one after the other in
+/// listing order after the last file that has some, the way mastering tools would write them.
+pub struct Drive {
+    backend: Box<dyn DriveBackend>,
+    file_listing: Vec<String>,
+    /// First sector of the files without real sectors, by [base::path_key]
+    synthetic_sectors: HashMap<String, u64>,
+    /// Sectors the files of the disc span
+    sector_count: u64,
+}
+
+impl Drive {
+    /// Opens the disc at `path`: an image in one of the formats [image::open] detects, or a
+    /// directory of the host served as one
+    pub fn new(path: &Path) -> Result<Drive, EmulatorError> {
+        Self::with_layers(path, &DiscLayers::default())
+    }
+
+    /// Opens the disc at `path` with `layers` over it
+    ///
+    /// Patches of the whole image apply first, then the overlays go over the disc, and the
+    /// patches of single files apply to what the overlays let through.
+    pub fn with_layers(path: &Path, layers: &DiscLayers) -> Result<Drive, EmulatorError> {
+        let mut image_patches = Vec::new();
+        let mut file_patches = Vec::new();
+        for spec in &layers.patches {
+            match &spec.target {
+                Some(target) => file_patches.push((target.clone(), spec.load()?)),
+                None => image_patches.push(spec.load()?),
+            }
+        }
+
+        let mut backend: Box<dyn DriveBackend> = if path.is_dir() {
+            if !image_patches.is_empty() {
+                return Err(EmulatorError::InvalidPatch(String::from("a directory can't be patched as a whole, give the patch a target file")));
+            }
+            Box::new(directory::DirectoryBackend::new(path)?)
+        } else {
+            Box::new(iso::IsoBackend::new(path, &image_patches)?)
+        };
+        if !layers.overlays.is_empty() {
+            let mut overlays = Vec::<Box<dyn DriveBackend>>::new();
+            for overlay in &layers.overlays {
+                overlays.push(Box::new(directory::DirectoryBackend::new(overlay)?));
+            }
+            backend = Box::new(overlay::OverlayBackend::new(backend, overlays));
+        }
+        if !file_patches.is_empty() {
+            backend = Box::new(patched::PatchedBackend::new(backend, file_patches)?);
+        }
+        Self::with_backend(backend)
+    }
+
+    pub fn with_backend(backend: Box<dyn DriveBackend>) -> Result<Drive, EmulatorError> {
+        let mut listing = Vec::new();
+        let mut files = Vec::new();
+        let root = backend.stat("")?;
+        Self::traverse_directory(&mut listing, &mut files, backend.as_ref(), "", root.name)?;
+
+        let sectors = |entry: &DriveEntry| (entry.size as u64 + image::SECTOR_SIZE - 1) / image::SECTOR_SIZE;
+        let mut sector_count = files.iter()
+            .filter_map(|(_, entry)| Some(entry.sector? + sectors(entry)))
+            .fold(FIRST_FILE_SECTOR, u64::max);
+        let mut synthetic_sectors = HashMap::new();
+        for (path, entry) in files.iter().filter(|(_, entry)| entry.sector.is_none()) {
+            synthetic_sectors.insert(base::path_key(path), sector_count);
+            sector_count += sectors(entry);
+        }
+
+        let drive = Drive {
+            backend,
+            file_listing: listing,
+            synthetic_sectors,
+            sector_count,
+        };
+        Ok(drive)
+    }
+
+    pub fn get_listing(&self) -> &Vec<String> {
+        &self.file_listing
+    }
+
+    /// Collects the paths of the files under `path`, and their entries in listing order
+    fn traverse_directory(vec: &mut Vec<String>, files: &mut Vec<(String, DriveEntry)>, backend: &dyn DriveBackend, path: &str, abs_dir: String) -> Result<(), EmulatorError> {
+        for entry in backend.list_directory(path)? {
+            let child = format!("{}/{}", path, entry.name);
+            let abs_child = format!("{}/{}", abs_dir, entry.name);
+            if entry.is_dir {
+                Self::traverse_directory(vec, files, backend, child.as_str(), abs_child)?;
+            } else {
+                files.push((child, entry));
+                vec.push(abs_child);
+            }
+        };
+        Ok(())
+    }
+
+    /// First sector of `file`, opened from `path`, see [Drive] for files with no real sectors
+    pub fn sector(&self, path: &str, file: &dyn DriveFile) -> Option<u64> {
+        file.sector().or_else(|| self.synthetic_sectors.get(&base::path_key(path)).copied())
+    }
+
+    /// Sectors the files of the disc span
+    pub fn sector_count(&self) -> u64 {
+        self.sector_count
+    }
+
+    pub fn read_file(&self, path: &str) -> Result<Vec<u8>, EmulatorError> {
+        let file = self.open(path)?;
+        file.read_at(0, file.size())
+    }
+
+    pub fn read_file_region(&self, path: &str, index: u32, count: u32) -> Result<Vec<u8>, EmulatorError> {
+        self.open(path)?.read_at(index, count)
+    }
+
+    /// Looks `path` up once, for reading it repeatedly
+    pub fn open(&self, path: &str) -> Result<Box<dyn DriveFile>, EmulatorError> {
+        self.backend.open(path)
+    }
+
+    pub fn file_size(&self, path: &str) -> Result<u32, EmulatorError> {
+        Ok(self.open(path)?.size())
+    }
+
+    /// The file or directory at `path`
+    pub fn stat(&self, path: &str) -> Result<DriveEntry, EmulatorError> {
+        self.backend.stat(path)
+    }
+
+    /// Entries of the directory at `path`, without `.` and `..`
+    pub fn list_directory(&self, path: &str) -> Result<Vec<DriveEntry>, EmulatorError> {
+        self.backend.list_directory(path)
+    }
+}
+
+const SYSCALLS: Range<u32> = 0x0..0x14;
+const NEWLIB_SYSCALLS: Range<u32> = newlib::OPEN..newlib::WRITE;
+const NEWLIB_FILE_SYSCALLS: Range<u32> = newlib::LSEEK..newlib::SBRK;
+
+/// Handles start after the newlib console descriptors, both share the handle table
+const FIRST_HANDLE: u32 = newlib::FIRST_FILE;
+
+/// A file in the handle table, opened by the handle syscalls or the newlib personality
+struct OpenFile {
+    path: String,
+    file: Box<dyn DriveFile>,
+    /// First sector on the disc, for the drive timing
+    sector: Option<u64>,
+    cursor: u32,
+}
+
+impl OpenFile {
+    fn new(drive: &Drive, path: String, cursor: u32) -> Result<OpenFile, EmulatorError> {
+        let file = drive.open(path.as_str())?;
+        let sector = drive.sector(path.as_str(), file.as_ref());
+        Ok(OpenFile { path, file, sector, cursor })
+    }
+
+    /// Moves the cursor `offset` bytes from the start (0), the cursor (1) or the end (2) of the
+    /// file. It can go past the end, reads there return nothing
+    fn seek(&mut self, offset: i32, whence: u32) -> Result<u32, SyscallError> {
+        let origin = match whence {
+            0 => 0,
+            1 => self.cursor as i64,
+            2 => self.file.size() as i64,
+            _ => return Err(SyscallError::InvalidArgument),
+        };
+        let cursor = origin + offset as i64;
+        if cursor < 0 || cursor > u32::MAX as i64 {
+            return Err(SyscallError::InvalidArgument);
+        }
+        self.cursor = cursor as u32;
+        Ok(self.cursor)
+    }
+
+    /// Reads up to `count` bytes from the cursor into guest memory at `address`
+    fn read(&mut self, em: &mut UnicornHandle, address: u64, count: u32) -> Result<u32, SyscallError> {
+        let bytes = self.file.read_at(self.cursor, count)?;
+        em.mem_write(address, bytes.as_slice())?;
+        self.cursor += bytes.len() as u32;
+        Ok(bytes.len() as u32)
+    }
+}
+
+/// A directory in the handle table, read one entry at a time
+struct OpenDirectory {
+    path: String,
+    entries: Vec<DriveEntry>,
+    index: usize,
+}
+
+/// Size of the name field of the entries written to the guest, NUL terminator included
+const ENTRY_NAME_SIZE: usize = 256;
+
+/// Writes `entry` to the guest as `struct { uint32_t size; uint32_t is_dir; char name[256]; }`,
+/// truncating the name if it doesn't fit
+fn write_entry(em: &mut UnicornHandle, address: u64, entry: &DriveEntry) -> Result<(), SyscallError> {
+    let mut bytes = Vec::with_capacity(8 + ENTRY_NAME_SIZE);
+    bytes.extend_from_slice(&entry.size.to_le_bytes());
+    bytes.extend_from_slice(&(entry.is_dir as u32).to_le_bytes());
+    let name = entry.name.as_bytes();
+    bytes.extend_from_slice(&name[..name.len().min(ENTRY_NAME_SIZE - 1)]);
+    bytes.resize(8 + ENTRY_NAME_SIZE, 0);
+    em.mem_write(address, bytes.as_slice())?;
+    Ok(())
+}
+
+/// Allows read-only access to a disc image, or to a directory of the host
+///
+/// This feature provides syscalls to read files from a disc-like drive. Multi-disc games get
+/// every disc up front, the host swaps them by opening the tray and inserting another one.
+pub struct EmulatorDrive {
+    /// The boot disc first
+    discs: Vec<String>,
+    layers: DiscLayers,
+    /// `None` until init and while the tray is open
+    drive: Option<Drive>,
+    /// Index of the disc in the drive, or of the last one while the tray is open
+    disc: usize,
+    tray_open: bool,
+    /// A disc was inserted since the guest last checked the drive status
+    changed: bool,
+    /// `None` when reads are instant
+    timing: Option<DriveTiming>,
+    files: HashMap<u32, OpenFile>,
+    directories: HashMap<u32, OpenDirectory>,
+}
+
+impl EmulatorDrive {
+    pub fn new(path: String) -> EmulatorDrive {
+        Self::with_layers(path, DiscLayers::default())
+    }
+
+    /// A drive with overlays and patches over the disc
+    pub fn with_layers(path: String, layers: DiscLayers) -> EmulatorDrive {
+        Self::with_discs(vec![path], layers)
+    }
+
+    /// A drive for a multi-disc game, booting from the first of `discs`. `layers` only go over
+    /// the boot disc
+    pub fn with_discs(discs: Vec<String>, layers: DiscLayers) -> EmulatorDrive {
+        EmulatorDrive {
+            discs,
+            layers,
+            drive: None,
+            disc: 0,
+            tray_open: false,
+            changed: false,
+            timing: None,
+            files: HashMap::new(),
+            directories: HashMap::new(),
+        }
+    }
+
+    /// Charges seek and transfer time to reads if `config` asks for accurate timing
+    pub fn with_timing(mut self, config: DriveConfig) -> EmulatorDrive {
+        self.timing = config.accurate_timing.then(|| DriveTiming::new(config));
+        self
+    }
+
+    pub fn disc_count(&self) -> usize {
+        self.discs.len()
+    }
+
+    /// Index of the disc in the drive, `None` while the tray is open
+    pub fn current_disc(&self) -> Option<usize> {
+        (!self.tray_open).then(|| self.disc)
+    }
+
+    fn load_disc(&self, index: usize) -> Result<Drive, EmulatorError> {
+        let path = self.discs.get(index)
+            .ok_or_else(|| EmulatorError::FileNotFound(format!("disc {}", index + 1)))?;
+        match index {
+            0 => Drive::with_layers(path.as_ref(), &self.layers),
+            _ => Drive::new(path.as_ref()),
+        }
+    }
+
+    /// Ejects the disc. Open files and directories are closed, reads fail with
+    /// [SyscallError::NoDisc] until a disc is inserted
+    pub fn open_tray(&mut self) {
+        self.drive = None;
+        self.tray_open = true;
+        self.files.clear();
+        self.directories.clear();
+    }
+
+    /// Inserts disc `index` and closes the tray. Reads fail with [SyscallError::DiscChanged]
+    /// until the guest checks the drive status
+    pub fn insert_disc(&mut self, index: usize) -> Result<(), EmulatorError> {
+        let drive = self.load_disc(index)?;
+        self.open_tray();
+        self.drive = Some(drive);
+        self.disc = index;
+        self.tray_open = false;
+        self.changed = true;
+        Ok(())
+    }
+
+    /// What the swap hotkey does: opens the tray if it's closed, otherwise inserts the disc
+    /// after the last one. Returns the disc inserted, if any
+    pub fn swap_disc(&mut self) -> Result<Option<usize>, EmulatorError> {
+        if !self.tray_open {
+            self.open_tray();
+            return Ok(None);
+        }
+        let next = (self.disc + 1) % self.discs.len();
+        self.insert_disc(next)?;
+        Ok(Some(next))
+    }
+
+    fn drive(&self) -> Result<&Drive, SyscallError> {
+        if self.changed {
+            return Err(SyscallError::DiscChanged);
+        }
+        self.drive.as_ref().ok_or(SyscallError::NoDisc)
+    }
+
+    /// Files and directories share the handle numbers
+    fn next_handle(&self) -> Result<u32, SyscallError> {
+        (FIRST_HANDLE..)
+            .find(|h| !self.files.contains_key(h) && !self.directories.contains_key(h))
+            .ok_or(SyscallError::OutOfMemory)
+    }
+
+    fn open(&mut self, path: String) -> Result<u32, SyscallError> {
+        let file = OpenFile::new(self.drive()?, path, 0)?;
+        let handle = self.next_handle()?;
+        self.files.insert(handle, file);
+        Ok(handle)
+    }
+
+    fn open_directory(&mut self, path: String) -> Result<u32, SyscallError> {
+        let entries = self.drive()?.list_directory(path.as_str())?;
+        let handle = self.next_handle()?;
+        self.directories.insert(handle, OpenDirectory { path, entries, index: 0 });
+        Ok(handle)
+    }
+
+    /// Charges reading `count` bytes from `offset` in the file starting at `sector` to the
+    /// drive timing
+    fn charge(&mut self, sector: Option<u64>, offset: u32, count: u32) {
+        if let (Some(timing), Some(drive), Some(sector)) = (self.timing.as_mut(), self.drive.as_ref(), sector) {
+            let first = offset as u64 / image::SECTOR_SIZE;
+            let last = (offset as u64 + count as u64 + image::SECTOR_SIZE - 1) / image::SECTOR_SIZE;
+            timing.read(sector + first, last.saturating_sub(first), drive.sector_count());
+        }
+    }
+
+    fn read_handle(&mut self, em: &mut UnicornHandle, handle: u32, address: u64, count: u32) -> Result<u32, SyscallError> {
+        let file = self.file(handle)?;
+        let (sector, offset) = (file.sector, file.cursor);
+        let read = file.read(em, address, count)?;
+        self.charge(sector, offset, read);
+        Ok(read)
+    }
+
+    fn file(&mut self, handle: u32) -> Result<&mut OpenFile, SyscallError> {
+        self.drive()?;
+        self.files.get_mut(&handle).ok_or(SyscallError::BadHandle)
+    }
+
+    fn handle_call(&mut self, em: &mut UnicornHandle, syscall: u32) -> SyscallResult {
+        let handle = em.reg_read(RegisterARM::R1 as i32)? as u32;
+        let result = match syscall {
+            0x5 => {
+                let path = Self::read_string_from_r1(em)?;
+                self.open(path)?
+            }
+            0x6 => {
+                let output_addr = em.reg_read(RegisterARM::R2 as i32)?;
+                let count = em.reg_read(RegisterARM::R3 as i32)? as u32;
+                self.read_handle(em, handle, output_addr, count)?
+            }
+            0x7 => {
+                let offset = em.reg_read_i32(RegisterARM::R2 as i32)?;
+                let whence = em.reg_read(RegisterARM::R3 as i32)? as u32;
+                self.file(handle)?.seek(offset, whence)?
+            }
+            0x8 => self.file(handle)?.cursor,
+            0x9 => {
+                let file = self.file(handle)?;
+                (file.cursor >= file.file.size()) as u32
+            }
+            0xA => {
+                self.files.remove(&handle).ok_or(SyscallError::BadHandle)?;
+                0
+            }
+            0xB => {
+                let path = Self::read_string_from_r1(em)?;
+                self.open_directory(path)?
+            }
+            0xC => {
+                let output_addr = em.reg_read(RegisterARM::R2 as i32)?;
+                self.drive()?;
+                let directory = self.directories.get_mut(&handle).ok_or(SyscallError::BadHandle)?;
+                match directory.entries.get(directory.index) {
+                    Some(entry) => {
+                        write_entry(em, output_addr, entry)?;
+                        directory.index += 1;
+                        1
+                    }
+                    None => 0,
+                }
+            }
+            0xD => {
+                self.directories.remove(&handle).ok_or(SyscallError::BadHandle)?;
+                0
+            }
+            0xE => {
+                let path = Self::read_string_from_r1(em)?;
+                let output_addr = em.reg_read(RegisterARM::R2 as i32)?;
+                let entry = self.drive()?.stat(path.as_str())?;
+                write_entry(em, output_addr, &entry)?;
+                0
+            }
+            0x10 => {
+                let status = match (self.tray_open, self.changed) {
+                    (true, _) => 1,
+                    (false, true) => 2,
+                    (false, false) => 0,
+                };
+                self.changed = false;
+                status
+            }
+            0x11 => self.current_disc().ok_or(SyscallError::NoDisc)? as u32,
+            0x12 => self.disc_count() as u32,
+            0x13 => self.timing.as_mut().map_or(0, |timing| timing.take_reported().min(u32::MAX as u64) as u32),
+            0xF => {
+                let name = self.drive()?.get_listing().get(handle as usize).ok_or(SyscallError::InvalidArgument)?.clone();
+                let output_addr = em.reg_read(RegisterARM::R2 as i32)?;
+                let size = em.reg_read(RegisterARM::R3 as i32)? as usize;
+                if name.len() >= size {
+                    return Err(SyscallError::InvalidArgument);
+                }
+                let mut bytes = name.into_bytes();
+                bytes.push(0);
+                em.mem_write(output_addr, bytes.as_slice())?;
+                bytes.len() as u32 - 1
+            }
+            _ => return Err(SyscallError::Unknown),
+        };
+        em.reg_write(RegisterARM::R0 as i32, result as u64)?;
+        Ok(())
+    }
+
+    fn newlib_call(&mut self, em: &mut UnicornHandle, syscall: u32) -> Result<u32, i32> {
+        let argument = |register: RegisterARM| em.reg_read(register as i32).map_err(|_| newlib::EFAULT);
+        let descriptor = argument(RegisterARM::R1)? as u32;
+        let result = match syscall {
+            newlib::OPEN => {
+                let path = read_guest_string(em, argument(RegisterARM::R1)?).map_err(newlib::errno)?;
+                // O_WRONLY and O_RDWR, the disc is read-only
+                if argument(RegisterARM::R2)? & 0x3 != 0 {
+                    return Err(newlib::EROFS);
+                }
+                self.open(path)
+            }
+            newlib::CLOSE => self.files.remove(&descriptor).map(|_| 0).ok_or(SyscallError::BadHandle),
+            newlib::READ => {
+                let output_addr = argument(RegisterARM::R2)?;
+                let count = argument(RegisterARM::R3)? as u32;
+                self.read_handle(em, descriptor, output_addr, count)
+            }
+            // the console can't seek
+            newlib::LSEEK if descriptor < newlib::FIRST_FILE => return Err(newlib::ESPIPE),
+            newlib::LSEEK => {
+                let offset = argument(RegisterARM::R2)? as u32 as i32;
+                let whence = argument(RegisterARM::R3)? as u32;
+                self.file(descriptor).and_then(|file| file.seek(offset, whence))
+            }
+            newlib::FSIZE => self.file(descriptor).map(|file| file.file.size()),
+            _ => Err(SyscallError::Unknown),
+        };
+        result.map_err(newlib::errno)
+    }
+
+    fn file_count(drive: &Drive, em: &mut UnicornHandle) -> SyscallResult {
+        let length = drive.get_listing().len() as u32;
+        em.reg_write(RegisterARM::R0 as i32, length as u64)?;
+        Ok(())
+    }
+
+    fn listing_entry<'a>(drive: &'a Drive, em: &UnicornHandle) -> Result<&'a String, SyscallError> {
+        let index = em.reg_read(RegisterARM::R1 as i32)? as usize;
+        drive.get_listing().get(index).ok_or(SyscallError::InvalidArgument)
+    }
+
+    fn filename_len(drive: &Drive, em: &mut UnicornHandle) -> SyscallResult {
+        let filelen = Self::listing_entry(drive, em)?.len();
+
+        em.reg_write(RegisterARM::R0 as i32, filelen as u64)?;
+        Ok(())
+    }
+
+    fn filename_index(drive: &Drive, em: &mut UnicornHandle) -> SyscallResult {
+        let file = Self::listing_entry(drive, em)?;
+
+        let strindex = em.reg_read(RegisterARM::R2 as i32)? as usize;
+        let character = *file.as_bytes().get(strindex).ok_or(SyscallError::InvalidArgument)?;
+        em.reg_write(RegisterARM::R0 as i32, character.into())?;
+        Ok(())
+    }
+
+    fn file_size(drive: &Drive, em: &mut UnicornHandle) -> SyscallResult {
+        let filepath = Self::read_string_from_r1(em)?;
+        let filesize = drive.file_size(filepath.as_str())?;
+        em.reg_write(RegisterARM::R0 as i32, filesize as u64)?;
+        Ok(())
+    }
+
+    fn read_string_from_r1(em: &mut UnicornHandle) -> Result<String, SyscallError> {
+        let string_address = em.reg_read(RegisterARM::R1 as i32)?;
+        read_guest_string(em, string_address)
+    }
+
+    /// Returns the first sector of the file, the offset and the byte count read, for the drive
+    /// timing
+    fn read_file(drive: &Drive, mut em: &mut UnicornHandle) -> Result<(Option<u64>, u32, u32), SyscallError> {
+        let filepath = Self::read_string_from_r1(&mut em)?;
+
+        let file_offset = em.reg_read(RegisterARM::R2 as i32)?;
+        let file_size = em.reg_read(RegisterARM::R3 as i32)?;
+
+        let file = drive.open(filepath.as_str())?;
+        let file_bytes = file.read_at(file_offset as u32, file_size as u32)?;
+        let sector = drive.sector(filepath.as_str(), file.as_ref());
+
+        let output_addr = em.reg_read(RegisterARM::R4 as i32)?;
+
+        em.mem_write(output_addr, file_bytes.as_slice())?;
+        em.reg_write(RegisterARM::R0 as i32, file_bytes.len() as u64)?;
+        Ok((sector, file_offset as u32, file_bytes.len() as u32))
+    }
+}
+
+/// | Syscall | Parameters | Description |
+/// | ------- | ---------- | ----------- |
+/// | 0x0 | No parameters | File count in drive |
+/// | 0x1 | int: index of file | Filename length of file i in drive |
+/// | 0x2 | int: index of file, int: index in filename | Filename character n of file i in drive |
+/// | 0x3 | char*: address to filepath string | File size of file in the address |
+/// | 0x4 | char*: address to filepath string, int: offset in file, int: byte count, uint8_t*: output address | Read (offset, offset+c) bytes from file at filepath into the output address, returns the number of bytes read |
+/// | 0x5 | char*: address to filepath string | Opens the file, returns its handle |
+/// | 0x6 | int: handle, uint8_t*: output address, int: byte count | Reads up to c bytes from the cursor into the output address and advances the cursor, returns the number of bytes read (0 at the end of the file) |
+/// | 0x7 | int: handle, int: offset, int: whence | Moves the cursor offset bytes from the start (0), the cursor (1) or the end (2) of the file, returns the new cursor |
+/// | 0x8 | int: handle | Cursor of the file |
+/// | 0x9 | int: handle | 1 if the cursor is at the end of the file, 0 otherwise |
+/// | 0xA | int: handle | Closes the file |
+/// | 0xB | char*: address to directory path | Opens the directory, returns its handle |
+/// | 0xC | int: handle, entry*: output address | Writes the next entry of the directory to the output address, returns 1, or 0 once every entry was read |
+/// | 0xD | int: handle | Closes the directory |
+/// | 0xE | char*: address to path, entry*: output address | Writes the entry of the file or directory at path to the output address |
+/// | 0xF | int: index of file, char*: output address, int: buffer size | Copies the whole filename of file i in drive, NUL-terminated, returns its length |
+/// | 0x10 | No parameters | Drive status: 0 ready, 1 tray open, 2 disc changed since the last call |
+/// | 0x11 | No parameters | Number of the disc in the drive, 0 for the boot disc |
+/// | 0x12 | No parameters | Number of discs of the game |
+/// | 0x13 | No parameters | Microseconds of drive time charged to reads since the last call, 0 without accurate timing |
+///
+/// Entries are `struct { uint32_t size; uint32_t is_dir; char name[256]; }`, with a size of 0
+/// for directories. Path lookups ignore case and the `;1` version suffix, as ISO9660 intends.
+///
+/// Indexes past the listing or the filename (or a buffer too small for it) return -2, paths
+/// that don't exist on the drive -4 and handles that aren't open -8. Open files keep their
+/// directory entry, so reading through a handle seeks straight to the cursor instead of looking
+/// the path up again.
+///
+/// While the tray is open every access to the drive returns -9. Once another disc is inserted
+/// they return -10 until the guest checks the status with 0x10. Files and directories opened on
+/// the previous disc are closed either way.
+///
+/// With accurate timing on (see [crate::config::DriveConfig]), reads through 0x4, 0x6 and the
+/// newlib `read` are charged seek and transfer time from the distance between the sectors
+/// read and where the head is. The guest is stalled for that many frames after it presents its
+/// next frame, so loading screens take as long as on a real drive.
+///
+/// The newlib personality opens files into the same handle table, with descriptors starting
+/// at 3. Its failures return a negative errno:
+///
+/// | Syscall | Parameters | Description |
+/// | ------- | ---------- | ----------- |
+/// | 0x201 | char*: path, int: flags | `open`, read-only. Returns the descriptor |
+/// | 0x202 | int: descriptor | `close` |
+/// | 0x203 | int: descriptor, void*: buffer, int: length | `read` from the cursor, returns the number of bytes read (0 at the end of the file) |
+/// | 0x205 | int: descriptor, int: offset, int: whence | `lseek`, returns the new cursor. Fails with `ESPIPE` on the console descriptors 0-2 |
+/// | 0x206 | int: descriptor | File size, for `fstat` |
+impl EmulatorFeature for EmulatorDrive {
+    fn init(&mut self, _emulator: &mut UnicornHandle, syscalls: &mut SyscallDispatcher) -> Result<(), EmulatorError> {
+        self.drive = Some(self.load_disc(self.disc)?);
+        let driveptr: *mut EmulatorDrive = self;
+        let syscall = move |em: &mut UnicornHandle, syscall: u32| unsafe {
+            match syscall {
+                0 => Self::file_count((*driveptr).drive()?, em),
+                1 => Self::filename_len((*driveptr).drive()?, em),
+                2 => Self::filename_index((*driveptr).drive()?, em),
+                3 => Self::file_size((*driveptr).drive()?, em),
+                4 => {
+                    let (sector, offset, count) = Self::read_file((*driveptr).drive()?, em)?;
+                    (*driveptr).charge(sector, offset, count);
+                    Ok(())
+                }
+                _ => (*driveptr).handle_call(em, syscall),
+            }
+        };
+        syscalls.register(&self.name(), SYSCALLS, syscall)?;
+
+        let newlib_syscall = move |em: &mut UnicornHandle, syscall: u32| unsafe {
+            let result = (*driveptr).newlib_call(em, syscall);
+            newlib::set_result(em, result)
+        };
+        syscalls.register(&self.name(), NEWLIB_SYSCALLS, newlib_syscall)?;
+        syscalls.register(&self.name(), NEWLIB_FILE_SYSCALLS, newlib_syscall)
+    }
+
+    fn stop(&mut self, emulator: &mut UnicornHandle) -> Result<(), EmulatorError> {
+        self.reset(emulator)
+    }
+
+    /// Closes every file and directory. The disc stays in the drive
+    fn reset(&mut self, _emulator: &mut UnicornHandle) -> Result<(), EmulatorError> {
+        self.files.clear();
+        self.directories.clear();
+        self.changed = false;
+        if let Some(timing) = &mut self.timing {
+            timing.reset();
+        }
+        Ok(())
+    }
+
+    /// Stalls the guest for the frames its reads took
+    fn on_frame(&mut self, _emulator: &mut UnicornHandle, requests: &mut FrameRequests) -> Result<(), EmulatorError> {
+        if let Some(timing) = &mut self.timing {
+            requests.stall_frames += timing.take_frames();
+        }
+        Ok(())
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut writer = StateWriter::new();
+        writer.write_u32(self.disc as u32);
+        writer.write_u32(self.tray_open as u32);
+        writer.write_u32(self.changed as u32);
+        writer.write_u32(self.files.len() as u32);
+        for (handle, file) in &self.files {
+            writer.write_u32(*handle);
+            writer.write_bytes(file.path.as_bytes());
+            writer.write_u32(file.cursor);
+        }
+        writer.write_u32(self.directories.len() as u32);
+        for (handle, directory) in &self.directories {
+            writer.write_u32(*handle);
+            writer.write_bytes(directory.path.as_bytes());
+            writer.write_u32(directory.index as u32);
+        }
+        let (head, pending) = self.timing.as_ref().map_or((0, 0), |timing| timing.state());
+        writer.write_u64(head);
+        writer.write_u64(pending);
+        writer.into_bytes()
+    }
+
+    /// The disc of the state is inserted (or the tray opened) if it isn't already, and open
+    /// files and directories are opened again from it
+    fn load_state(&mut self, _emulator: &mut UnicornHandle, state: &[u8]) -> Result<(), EmulatorError> {
+        let mut reader = StateReader::new(state);
+        let disc = reader.read_u32()? as usize;
+        let tray_open = reader.read_u32()? != 0;
+        let changed = reader.read_u32()? != 0;
+        if disc >= self.discs.len() {
+            return Err(EmulatorError::InvalidState(format!("disc {} isn't available", disc + 1)));
+        }
+        if tray_open {
+            self.open_tray();
+            self.disc = disc;
+        } else if self.tray_open || self.disc != disc {
+            self.insert_disc(disc)?;
+        }
+        self.changed = changed;
+
+        let drive = self.drive.as_ref();
+        let no_disc = || EmulatorError::InvalidState(String::from("files are open with no disc in the drive"));
+        let count = reader.read_u32()?;
+        let mut files = HashMap::new();
+        for _ in 0..count {
+            let handle = reader.read_u32()?;
+            let path = String::from_utf8(reader.read_bytes()?.to_vec())
+                .map_err(|_| EmulatorError::InvalidState(String::from("file path isn't UTF-8")))?;
+            let cursor = reader.read_u32()?;
+            files.insert(handle, OpenFile::new(drive.ok_or_else(no_disc)?, path, cursor)?);
+        }
+        let count = reader.read_u32()?;
+        let mut directories = HashMap::new();
+        for _ in 0..count {
+            let handle = reader.read_u32()?;
+            let path = String::from_utf8(reader.read_bytes()?.to_vec())
+                .map_err(|_| EmulatorError::InvalidState(String::from("directory path isn't UTF-8")))?;
+            let index = reader.read_u32()? as usize;
+            let entries = drive.ok_or_else(no_disc)?.list_directory(path.as_str())?;
+            directories.insert(handle, OpenDirectory { path, entries, index });
+        }
+        self.files = files;
+        self.directories = directories;
+        let (head, pending) = (reader.read_u64()?, reader.read_u64()?);
+        if let Some(timing) = &mut self.timing {
+            timing.restore(head, pending);
+        }
+        Ok(())
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn name(&self) -> String {
+        "EmulatorDrive".parse().unwrap()
+    }
+
+    fn descriptor(&self) -> FeatureDescriptor {
+        FeatureDescriptor { syscalls: vec![SYSCALLS, NEWLIB_SYSCALLS, NEWLIB_FILE_SYSCALLS], mmio: Vec::new() }
+    }
+}
\ No newline at end of file
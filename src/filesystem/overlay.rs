@@ -0,0 +1,170 @@
+use crate::error::EmulatorError;
+use super::base::{same_name, DriveBackend, DriveEntry, DriveFile};
+
+/// Backends stacked on top of each other, for patching a disc without remastering it
+///
+/// A path is looked up from the top layer down, so files of the upper layers shadow the files
+/// with the same (case-insensitive) path below them. Directories are merged, in the order of
+/// the base with the entries only the overlays have after it.
+///
+/// Files of a disc layer keep their sectors. Files from host directories have none, the
+/// [Drive](super::Drive) lays them out after the last file of the disc for the drive timing,
+/// as if they had been appended to it.
+pub struct OverlayBackend {
+    /// Top layer first
+    layers: Vec<Box<dyn DriveBackend>>,
+}
+
+impl OverlayBackend {
+    /// `overlays` go over `base` in order, the last one is on top
+    pub fn new(base: Box<dyn DriveBackend>, overlays: Vec<Box<dyn DriveBackend>>) -> OverlayBackend {
+        let mut layers = overlays;
+        layers.reverse();
+        layers.push(base);
+        OverlayBackend { layers }
+    }
+
+    /// Calls `operation` on each layer from the top, until one of them has the path
+    ///
+    /// Only [EmulatorError::FileNotFound] falls through to the layer below, any other error
+    /// is returned as is.
+    fn first<T>(&self, path: &str, operation: impl Fn(&dyn DriveBackend) -> Result<T, EmulatorError>) -> Result<T, EmulatorError> {
+        for layer in &self.layers {
+            match operation(layer.as_ref()) {
+                Err(EmulatorError::FileNotFound(_)) => continue,
+                result => return result,
+            }
+        }
+        Err(EmulatorError::FileNotFound(path.to_string()))
+    }
+}
+
+impl DriveBackend for OverlayBackend {
+    fn stat(&self, path: &str) -> Result<DriveEntry, EmulatorError> {
+        self.first(path, |layer| layer.stat(path))
+    }
+
+    fn list_directory(&self, path: &str) -> Result<Vec<DriveEntry>, EmulatorError> {
+        // The layers from the top down to the first one with a file at `path`, which hides the
+        // directories below it
+        let mut merged = Vec::new();
+        for layer in &self.layers {
+            match layer.stat(path) {
+                Ok(entry) if entry.is_dir => merged.push(layer),
+                Ok(_) => break,
+                Err(EmulatorError::FileNotFound(_)) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        if merged.is_empty() {
+            return Err(EmulatorError::FileNotFound(path.to_string()));
+        }
+        // From the base up, so the base keeps its order and the entries only the overlays have
+        // come after it. Entries of an upper layer replace the ones they shadow in place
+        let mut entries: Vec<DriveEntry> = Vec::new();
+        for layer in merged.into_iter().rev() {
+            for entry in layer.list_directory(path)? {
+                match entries.iter_mut().find(|shadowed| same_name(&shadowed.name, &entry.name)) {
+                    Some(shadowed) => *shadowed = entry,
+                    None => entries.push(entry),
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    fn open(&self, path: &str) -> Result<Box<dyn DriveFile>, EmulatorError> {
+        self.first(path, |layer| layer.open(path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::base::components;
+    use super::*;
+
+    /// Files and directories by path, listed in the order given
+    struct MemoryBackend {
+        entries: Vec<(&'static str, u32, bool)>,
+        broken: bool,
+    }
+
+    fn layer(entries: Vec<(&'static str, u32, bool)>) -> Box<dyn DriveBackend> {
+        Box::new(MemoryBackend { entries, broken: false })
+    }
+
+    fn drive_entry(path: &str, size: u32, is_dir: bool) -> DriveEntry {
+        let name = path.rsplit('/').next().unwrap_or(path).to_string();
+        DriveEntry { name, size, is_dir, sector: None }
+    }
+
+    impl DriveBackend for MemoryBackend {
+        fn stat(&self, path: &str) -> Result<DriveEntry, EmulatorError> {
+            if self.broken {
+                return Err(EmulatorError::InvalidImage(String::from("broken")));
+            }
+            if components(path).next().is_none() {
+                return Ok(drive_entry("", 0, true));
+            }
+            self.entries.iter()
+                .find(|(entry, _, _)| entry.eq_ignore_ascii_case(path))
+                .map(|(entry, size, is_dir)| drive_entry(entry, *size, *is_dir))
+                .ok_or_else(|| EmulatorError::FileNotFound(path.to_string()))
+        }
+
+        fn list_directory(&self, path: &str) -> Result<Vec<DriveEntry>, EmulatorError> {
+            if !self.stat(path)?.is_dir {
+                return Err(EmulatorError::FileNotFound(path.to_string()));
+            }
+            Ok(self.entries.iter()
+                .filter(|(entry, _, _)| entry.rsplit_once('/').map_or("", |(parent, _)| parent).eq_ignore_ascii_case(path))
+                .map(|(entry, size, is_dir)| drive_entry(entry, *size, *is_dir))
+                .collect())
+        }
+
+        fn open(&self, path: &str) -> Result<Box<dyn DriveFile>, EmulatorError> {
+            Err(EmulatorError::FileNotFound(path.to_string()))
+        }
+    }
+
+    fn names(entries: &[DriveEntry]) -> Vec<(&str, u32)> {
+        entries.iter().map(|entry| (entry.name.as_str(), entry.size)).collect()
+    }
+
+    #[test]
+    fn listings_keep_the_base_order() {
+        let base = layer(vec![("b", 1, false), ("a", 1, false), ("dir", 0, true)]);
+        let overlay = layer(vec![("c", 2, false), ("A", 2, false)]);
+        let top = layer(vec![("d", 3, false), ("C", 3, false)]);
+        let backend = OverlayBackend::new(base, vec![overlay, top]);
+        assert_eq!(names(&backend.list_directory("").unwrap()), vec![("b", 1), ("A", 2), ("dir", 0), ("C", 3), ("d", 3)]);
+    }
+
+    #[test]
+    fn only_missing_files_fall_through() {
+        let base = || layer(vec![("a", 1, false)]);
+        let backend = OverlayBackend::new(base(), vec![layer(vec![])]);
+        assert_eq!(backend.stat("a").unwrap().size, 1);
+
+        let broken = Box::new(MemoryBackend { entries: vec![], broken: true });
+        let backend = OverlayBackend::new(base(), vec![broken]);
+        assert!(matches!(backend.stat("a"), Err(EmulatorError::InvalidImage(_))));
+        assert!(matches!(backend.list_directory(""), Err(EmulatorError::InvalidImage(_))));
+        assert!(matches!(backend.stat("missing"), Err(EmulatorError::InvalidImage(_))));
+
+        let backend = OverlayBackend::new(base(), vec![layer(vec![])]);
+        assert!(matches!(backend.stat("missing"), Err(EmulatorError::FileNotFound(_))));
+    }
+
+    #[test]
+    fn files_hide_the_directories_below() {
+        let base = || layer(vec![("d", 0, true), ("d/x", 1, false)]);
+        let file = || layer(vec![("d", 1, false)]);
+
+        let backend = OverlayBackend::new(base(), vec![file(), layer(vec![("d", 0, true), ("d/y", 1, false)])]);
+        assert_eq!(names(&backend.list_directory("d").unwrap()), vec![("y", 1)]);
+
+        let backend = OverlayBackend::new(base(), vec![file()]);
+        assert!(matches!(backend.list_directory("d"), Err(EmulatorError::FileNotFound(_))));
+    }
+}
@@ -0,0 +1,186 @@
+pub const MAGIC: &[u8] = b"BPS1";
+
+/// Source, target and patch CRC32s
+const FOOTER_SIZE: usize = 12;
+
+struct Reader<'a> {
+    patch: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn byte(&mut self) -> Result<u8, &'static str> {
+        let byte = *self.patch.get(self.position).ok_or("truncated patch")?;
+        self.position += 1;
+        Ok(byte)
+    }
+
+    fn bytes(&mut self, count: usize) -> Result<&'a [u8], &'static str> {
+        let end = self.position.checked_add(count).ok_or("truncated patch")?;
+        let bytes = self.patch.get(self.position..end).ok_or("truncated patch")?;
+        self.position = end;
+        Ok(bytes)
+    }
+
+    /// BPS variable-length number, 7 bits a byte with the last byte flagged
+    fn number(&mut self) -> Result<u64, &'static str> {
+        let mut value = 0u64;
+        let mut shift = 1u64;
+        loop {
+            let byte = self.byte()?;
+            value = value.checked_add((byte & 0x7F) as u64 * shift).ok_or("number overflow")?;
+            if byte & 0x80 != 0 {
+                return Ok(value);
+            }
+            shift = shift.checked_mul(128).ok_or("number overflow")?;
+            value = value.checked_add(shift).ok_or("number overflow")?;
+        }
+    }
+
+    fn offset(&mut self, base: usize) -> Result<usize, &'static str> {
+        let data = self.number()?;
+        let distance = (data >> 1) as usize;
+        let offset = if data & 1 != 0 { base.checked_sub(distance) } else { base.checked_add(distance) };
+        offset.ok_or("relative offset out of bounds")
+    }
+}
+
+fn crc_at(patch: &[u8], position: usize) -> u32 {
+    u32::from_le_bytes(patch[position..position + 4].try_into().unwrap())
+}
+
+/// BPS: copies from the source, the patch or the target written so far, with the CRC32 of the
+/// source, target and patch in its footer
+pub fn apply(patch: &[u8], source: &[u8]) -> Result<Vec<u8>, &'static str> {
+    if patch.len() < MAGIC.len() + FOOTER_SIZE {
+        return Err("truncated patch");
+    }
+    let footer = patch.len() - FOOTER_SIZE;
+    if crc32fast::hash(&patch[..patch.len() - 4]) != crc_at(patch, footer + 8) {
+        return Err("the patch is corrupt");
+    }
+    if crc32fast::hash(source) != crc_at(patch, footer) {
+        return Err("made for another file, the source CRC32 doesn't match");
+    }
+
+    let mut reader = Reader { patch: &patch[..footer], position: MAGIC.len() };
+    let source_size = reader.number()? as usize;
+    let target_size = reader.number()? as usize;
+    let metadata_size = reader.number()? as usize;
+    reader.bytes(metadata_size)?;
+    if source_size != source.len() {
+        return Err("made for another file, the source size doesn't match");
+    }
+    if target_size > super::MAX_OUTPUT {
+        return Err("the target would be larger than 4GB");
+    }
+
+    // The size comes from the patch, don't trust it for more than the patch could produce
+    // without repeating itself
+    let mut target = Vec::with_capacity(target_size.min(source.len().saturating_mul(2).saturating_add(patch.len())));
+    let mut source_offset = 0usize;
+    let mut target_offset = 0usize;
+    while reader.position < footer {
+        let data = reader.number()?;
+        let length = (data >> 2) as usize + 1;
+        let target_end = target.len().checked_add(length).ok_or("writes past the end of the target")?;
+        if target_end > target_size {
+            return Err("writes past the end of the target");
+        }
+        match data & 3 {
+            // SourceRead
+            0 => {
+                let start = target.len();
+                target.extend_from_slice(source.get(start..target_end).ok_or("reads past the end of the source")?);
+            }
+            // TargetRead
+            1 => target.extend_from_slice(reader.bytes(length)?),
+            // SourceCopy
+            2 => {
+                source_offset = reader.offset(source_offset)?;
+                let source_end = source_offset.checked_add(length).ok_or("reads past the end of the source")?;
+                target.extend_from_slice(source.get(source_offset..source_end).ok_or("reads past the end of the source")?);
+                source_offset = source_end;
+            }
+            // TargetCopy, byte by byte since it can read what it's writing
+            _ => {
+                target_offset = reader.offset(target_offset)?;
+                for _ in 0..length {
+                    let byte = *target.get(target_offset).ok_or("reads past the end of the target")?;
+                    target.push(byte);
+                    target_offset += 1;
+                }
+            }
+        }
+    }
+
+    if target.len() != target_size {
+        return Err("the target is truncated");
+    }
+    if crc32fast::hash(&target) != crc_at(patch, footer + 4) {
+        return Err("the target CRC32 doesn't match");
+    }
+    Ok(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn number(patch: &mut Vec<u8>, mut value: u64) {
+        loop {
+            let low = (value & 0x7F) as u8;
+            value >>= 7;
+            if value == 0 {
+                patch.push(0x80 | low);
+                return;
+            }
+            patch.push(low);
+            value -= 1;
+        }
+    }
+
+    /// `abcdefgh` to `abXYZfghghgh` with one action of each kind, the TargetCopy reading
+    /// what it writes
+    fn make_patch(source: &[u8], target: &[u8]) -> Vec<u8> {
+        let mut patch = MAGIC.to_vec();
+        for value in [source.len() as u64, target.len() as u64, 0] {
+            number(&mut patch, value);
+        }
+        // SourceRead of 2
+        number(&mut patch, 1 << 2);
+        // TargetRead of 3
+        number(&mut patch, (2 << 2) | 1);
+        patch.extend_from_slice(b"XYZ");
+        // SourceCopy of 3 from 5
+        number(&mut patch, (2 << 2) | 2);
+        number(&mut patch, 5 << 1);
+        // TargetCopy of 4 from 6
+        number(&mut patch, (3 << 2) | 3);
+        number(&mut patch, 6 << 1);
+        patch.extend_from_slice(&crc32fast::hash(source).to_le_bytes());
+        patch.extend_from_slice(&crc32fast::hash(target).to_le_bytes());
+        let crc = crc32fast::hash(&patch);
+        patch.extend_from_slice(&crc.to_le_bytes());
+        patch
+    }
+
+    #[test]
+    fn every_action() {
+        let patch = make_patch(b"abcdefgh", b"abXYZfghghgh");
+        assert_eq!(apply(&patch, b"abcdefgh").unwrap(), b"abXYZfghghgh");
+    }
+
+    #[test]
+    fn crc_mismatch() {
+        let patch = make_patch(b"abcdefgh", b"abXYZfghghgh");
+        assert!(apply(&patch, b"abcdefgi").is_err());
+
+        let wrong_target = make_patch(b"abcdefgh", b"abXYZfghghgi");
+        assert_eq!(apply(&wrong_target, b"abcdefgh"), Err("the target CRC32 doesn't match"));
+
+        let mut corrupt = patch;
+        corrupt[5] ^= 1;
+        assert_eq!(apply(&corrupt, b"abcdefgh"), Err("the patch is corrupt"));
+    }
+}
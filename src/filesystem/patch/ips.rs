@@ -0,0 +1,64 @@
+pub const MAGIC: &[u8] = b"PATCH";
+
+const EOF_MARKER: usize = 0x454F46;
+
+fn read_be(patch: &[u8], position: &mut usize, size: usize) -> Result<usize, &'static str> {
+    let bytes = patch.get(*position..*position + size).ok_or("truncated record")?;
+    *position += size;
+    Ok(bytes.iter().fold(0, |value, byte| value << 8 | *byte as usize))
+}
+
+/// IPS: records writing bytes (or a run of one byte) at 24-bit offsets, up to `EOF`. The
+/// output grows when a record writes past its end, and an offset after `EOF` truncates it
+pub fn apply(patch: &[u8], source: &[u8]) -> Result<Vec<u8>, &'static str> {
+    let mut target = source.to_vec();
+    let mut position = MAGIC.len();
+    loop {
+        let offset = read_be(patch, &mut position, 3)?;
+        if offset == EOF_MARKER {
+            break;
+        }
+        let size = read_be(patch, &mut position, 2)?;
+        let (count, bytes) = if size == 0 {
+            let count = read_be(patch, &mut position, 2)?;
+            let value = read_be(patch, &mut position, 1)? as u8;
+            (count, vec![value; count])
+        } else {
+            let bytes = patch.get(position..position + size).ok_or("truncated record")?;
+            position += size;
+            (size, bytes.to_vec())
+        };
+        if target.len() < offset + count {
+            target.resize(offset + count, 0);
+        }
+        target[offset..offset + count].copy_from_slice(&bytes);
+    }
+    if patch.len() == position + 3 {
+        target.truncate(read_be(patch, &mut position, 3)?);
+    }
+    Ok(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_runs_and_truncation() {
+        let mut patch = MAGIC.to_vec();
+        // 2 bytes at 1
+        patch.extend_from_slice(&[0, 0, 1, 0, 2, 0xAA, 0xBB]);
+        // a run of 3 0x11 at 6, past the end of the source
+        patch.extend_from_slice(&[0, 0, 6, 0, 0, 0, 3, 0x11]);
+        patch.extend_from_slice(b"EOF");
+        patch.extend_from_slice(&[0, 0, 8]);
+        assert_eq!(apply(&patch, &[0; 4]).unwrap(), vec![0, 0xAA, 0xBB, 0, 0, 0, 0x11, 0x11]);
+    }
+
+    #[test]
+    fn truncated_record() {
+        let mut patch = MAGIC.to_vec();
+        patch.extend_from_slice(&[0, 0, 1, 0, 2, 0xAA]);
+        assert!(apply(&patch, &[0; 4]).is_err());
+    }
+}
@@ -0,0 +1,119 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use crate::error::EmulatorError;
+
+mod ips;
+mod bps;
+mod vcdiff;
+
+/// Largest file a patch may produce, the drive addresses files with 32 bits
+const MAX_OUTPUT: usize = u32::MAX as usize;
+
+/// Binary patch formats, told apart by their magic
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatchFormat {
+    /// No checksums, only give it an expected CRC32 in its [PatchSpec]
+    Ips,
+    /// Carries the CRC32 of the source, the target and itself, all verified
+    Bps,
+    /// RFC 3284, as written by xdelta3. The Adler-32 of each target window is verified when
+    /// the patch has it
+    Vcdiff,
+}
+
+/// A patch and what it applies to, as given to `--patch`: `[target=]file[@crc32]`
+///
+/// Without a target the patch applies to the disc image file as a whole, with one it applies
+/// to the file at that path on the disc (`main.elf=fix.bps`). The optional CRC32 (8 hex
+/// digits) is checked against what the patch is applied to, for formats that don't verify it
+/// themselves.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PatchSpec {
+    pub target: Option<String>,
+    pub file: PathBuf,
+    pub source_crc32: Option<u32>,
+}
+
+impl PatchSpec {
+    pub fn parse(spec: &str) -> PatchSpec {
+        let (target, file) = match spec.split_once('=') {
+            Some((target, file)) => (Some(target.to_string()), file),
+            None => (None, spec),
+        };
+        let checksum = file.rsplit_once('@')
+            .filter(|(_, crc)| crc.len() == 8)
+            .and_then(|(file, crc)| u32::from_str_radix(crc, 16).ok().map(|crc| (file, crc)));
+        let (file, source_crc32) = match checksum {
+            Some((file, crc)) => (file, Some(crc)),
+            None => (file, None),
+        };
+        PatchSpec { target, file: PathBuf::from(file), source_crc32 }
+    }
+
+    /// Reads the patch file, see [Patch::apply]
+    pub fn load(&self) -> Result<Patch, EmulatorError> {
+        Patch::new(&self.file, fs::read(&self.file)?, self.source_crc32)
+    }
+}
+
+/// A patch file loaded in memory
+pub struct Patch {
+    name: String,
+    format: PatchFormat,
+    data: Vec<u8>,
+    source_crc32: Option<u32>,
+}
+
+pub(crate) fn invalid_patch(name: &str, reason: &str) -> EmulatorError {
+    EmulatorError::InvalidPatch(format!("{}: {}", name, reason))
+}
+
+impl Patch {
+    pub fn new(path: &Path, data: Vec<u8>, source_crc32: Option<u32>) -> Result<Patch, EmulatorError> {
+        let name = path.display().to_string();
+        let format = if data.starts_with(ips::MAGIC) {
+            PatchFormat::Ips
+        } else if data.starts_with(bps::MAGIC) {
+            PatchFormat::Bps
+        } else if data.starts_with(vcdiff::MAGIC) {
+            PatchFormat::Vcdiff
+        } else {
+            return Err(invalid_patch(&name, "not an IPS, BPS or VCDIFF patch"));
+        };
+        Ok(Patch { name, format, data, source_crc32 })
+    }
+
+    pub fn format(&self) -> PatchFormat {
+        self.format
+    }
+
+    /// The patched copy of `source`
+    ///
+    /// Fails without producing anything if `source` isn't the file the patch was made for, as
+    /// far as the format (and the CRC32 of the spec) can tell.
+    pub fn apply(&self, source: &[u8]) -> Result<Vec<u8>, EmulatorError> {
+        if let Some(expected) = self.source_crc32 {
+            let actual = crc32fast::hash(source);
+            if actual != expected {
+                return Err(invalid_patch(&self.name, &format!(
+                    "made for a file with CRC32 {:08x}, this one is {:08x}", expected, actual)));
+            }
+        }
+        let invalid = |reason: &str| invalid_patch(&self.name, reason);
+        match self.format {
+            PatchFormat::Ips => ips::apply(&self.data, source).map_err(invalid),
+            PatchFormat::Bps => bps::apply(&self.data, source).map_err(invalid),
+            PatchFormat::Vcdiff => vcdiff::apply(&self.data, source).map_err(invalid),
+        }
+    }
+}
+
+/// Reads the patches, for applying them in order
+pub fn load_all<'a>(specs: impl IntoIterator<Item = &'a PatchSpec>) -> Result<Vec<Patch>, EmulatorError> {
+    specs.into_iter().map(PatchSpec::load).collect()
+}
+
+/// Applies `patches` one after the other
+pub fn apply_all(patches: &[Patch], source: Vec<u8>) -> Result<Vec<u8>, EmulatorError> {
+    patches.iter().try_fold(source, |data, patch| patch.apply(&data))
+}
@@ -0,0 +1,280 @@
+pub const MAGIC: &[u8] = &[0xD6, 0xC3, 0xC4, 0x00];
+
+// Hdr_Indicator
+const VCD_DECOMPRESS: u8 = 0x01;
+const VCD_CODETABLE: u8 = 0x02;
+/// xdelta3's application header
+const VCD_APPHEADER: u8 = 0x04;
+
+// Win_Indicator
+const VCD_SOURCE: u8 = 0x01;
+const VCD_TARGET: u8 = 0x02;
+/// xdelta3's checksum of the target window
+const VCD_ADLER32: u8 = 0x04;
+
+const NEAR_SIZE: usize = 4;
+const SAME_SIZE: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Noop,
+    Add,
+    Run,
+    Copy,
+}
+
+/// Half of a code table entry: an instruction, its size (0 when it follows in the instruction
+/// section) and its address mode
+#[derive(Debug, Clone, Copy)]
+struct Instruction {
+    kind: Kind,
+    size: u8,
+    mode: u8,
+}
+
+const NOOP: Instruction = Instruction { kind: Kind::Noop, size: 0, mode: 0 };
+
+/// The default code table of RFC 3284 section 5.6
+fn default_code_table() -> Vec<(Instruction, Instruction)> {
+    let add = |size| Instruction { kind: Kind::Add, size, mode: 0 };
+    let copy = |size, mode| Instruction { kind: Kind::Copy, size, mode };
+    let mut table = vec![(Instruction { kind: Kind::Run, size: 0, mode: 0 }, NOOP)];
+    table.extend((0..=17).map(|size| (add(size), NOOP)));
+    for mode in 0..=8 {
+        table.push((copy(0, mode), NOOP));
+        table.extend((4..=18).map(|size| (copy(size, mode), NOOP)));
+    }
+    for mode in 0..=5 {
+        for add_size in 1..=4 {
+            table.extend((4..=6).map(|size| (add(add_size), copy(size, mode))));
+        }
+    }
+    for mode in 6..=8 {
+        table.extend((1..=4).map(|add_size| (add(add_size), copy(4, mode))));
+    }
+    table.extend((0..=8).map(|mode| (copy(4, mode), add(1))));
+    table
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Reader<'a> {
+        Reader { data, position: 0 }
+    }
+
+    fn byte(&mut self) -> Result<u8, &'static str> {
+        let byte = *self.data.get(self.position).ok_or("truncated patch")?;
+        self.position += 1;
+        Ok(byte)
+    }
+
+    fn bytes(&mut self, count: usize) -> Result<&'a [u8], &'static str> {
+        let end = self.position.checked_add(count).ok_or("truncated patch")?;
+        let bytes = self.data.get(self.position..end).ok_or("truncated patch")?;
+        self.position = end;
+        Ok(bytes)
+    }
+
+    /// Big-endian base 128, the high bit set on every byte but the last
+    fn integer(&mut self) -> Result<usize, &'static str> {
+        let mut value = 0usize;
+        loop {
+            let byte = self.byte()?;
+            value = value.checked_mul(128).ok_or("integer overflow")? | (byte & 0x7F) as usize;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn done(&self) -> bool {
+        self.position >= self.data.len()
+    }
+}
+
+/// Recently used addresses, for the near and same address modes
+struct AddressCache {
+    near: [usize; NEAR_SIZE],
+    next_slot: usize,
+    same: [usize; SAME_SIZE * 256],
+}
+
+impl AddressCache {
+    fn new() -> AddressCache {
+        AddressCache { near: [0; NEAR_SIZE], next_slot: 0, same: [0; SAME_SIZE * 256] }
+    }
+
+    fn decode(&mut self, addresses: &mut Reader, here: usize, mode: u8) -> Result<usize, &'static str> {
+        let mode = mode as usize;
+        let address = match mode {
+            0 => addresses.integer()?,
+            1 => here.checked_sub(addresses.integer()?).ok_or("bad address")?,
+            m if m < 2 + NEAR_SIZE => self.near[m - 2].checked_add(addresses.integer()?).ok_or("bad address")?,
+            m if m < 2 + NEAR_SIZE + SAME_SIZE => self.same[(m - 2 - NEAR_SIZE) * 256 + addresses.byte()? as usize],
+            _ => return Err("bad address mode"),
+        };
+        self.near[self.next_slot] = address;
+        self.next_slot = (self.next_slot + 1) % NEAR_SIZE;
+        self.same[address % (SAME_SIZE * 256)] = address;
+        Ok(address)
+    }
+}
+
+/// Adler-32, what xdelta3 checksums target windows with
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in data.chunks(5552) {
+        for byte in chunk {
+            a += *byte as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    b << 16 | a
+}
+
+/// VCDIFF: windows of add, run and copy instructions over a segment of the source (or of the
+/// target written so far). Secondary compression and custom code tables aren't supported
+pub fn apply(patch: &[u8], source: &[u8]) -> Result<Vec<u8>, &'static str> {
+    let mut reader = Reader::new(patch);
+    reader.bytes(MAGIC.len())?;
+    let indicator = reader.byte()?;
+    if indicator & VCD_DECOMPRESS != 0 {
+        return Err("secondary compression isn't supported, create it with xdelta3 -S none");
+    }
+    if indicator & VCD_CODETABLE != 0 {
+        return Err("custom code tables aren't supported");
+    }
+    if indicator & VCD_APPHEADER != 0 {
+        let length = reader.integer()?;
+        reader.bytes(length)?;
+    }
+
+    let table = default_code_table();
+    let mut target = Vec::new();
+    while !reader.done() {
+        let window = reader.byte()?;
+        let segment = if window & (VCD_SOURCE | VCD_TARGET) != 0 {
+            let length = reader.integer()?;
+            let position = reader.integer()?;
+            let end = position.checked_add(length).ok_or("bad source segment")?;
+            let base = if window & VCD_SOURCE != 0 { source } else { target.as_slice() };
+            // Copied out since the window is appended to `target`
+            base.get(position..end).ok_or("made for another file, the source segment is out of bounds")?.to_vec()
+        } else {
+            Vec::new()
+        };
+
+        reader.integer()?;
+        let window_size = reader.integer()?;
+        if window_size > super::MAX_OUTPUT - target.len() {
+            return Err("the target would be larger than 4GB");
+        }
+        if reader.byte()? != 0 {
+            return Err("compressed sections aren't supported");
+        }
+        let data_length = reader.integer()?;
+        let instructions_length = reader.integer()?;
+        let addresses_length = reader.integer()?;
+        let checksum = if window & VCD_ADLER32 != 0 {
+            Some(u32::from_be_bytes(reader.bytes(4)?.try_into().unwrap()))
+        } else {
+            None
+        };
+        let mut data = Reader::new(reader.bytes(data_length)?);
+        let mut instructions = Reader::new(reader.bytes(instructions_length)?);
+        let mut addresses = Reader::new(reader.bytes(addresses_length)?);
+
+        // The size comes from the patch, don't trust it for more than the patch could produce
+        // without repeating itself
+        let mut output = Vec::with_capacity(window_size.min(source.len().saturating_mul(2).saturating_add(patch.len())));
+        let mut cache = AddressCache::new();
+        while !instructions.done() {
+            let (first, second) = table[instructions.byte()? as usize];
+            for instruction in [first, second] {
+                if instruction.kind == Kind::Noop {
+                    continue;
+                }
+                let size = match instruction.size {
+                    0 => instructions.integer()?,
+                    size => size as usize,
+                };
+                let output_end = output.len().checked_add(size).ok_or("writes past the end of the window")?;
+                if output_end > window_size {
+                    return Err("writes past the end of the window");
+                }
+                match instruction.kind {
+                    Kind::Add => output.extend_from_slice(data.bytes(size)?),
+                    Kind::Run => {
+                        let byte = data.byte()?;
+                        output.resize(output_end, byte);
+                    }
+                    Kind::Copy => {
+                        let here = segment.len() + output.len();
+                        let address = cache.decode(&mut addresses, here, instruction.mode)?;
+                        if address >= here {
+                            return Err("copies from the future");
+                        }
+                        let end = address.checked_add(size).ok_or("copies from the future")?;
+                        // Copies from the window can overlap what they write
+                        for index in address..end {
+                            let byte = match segment.get(index) {
+                                Some(byte) => *byte,
+                                None => output[index - segment.len()],
+                            };
+                            output.push(byte);
+                        }
+                    }
+                    Kind::Noop => {}
+                }
+            }
+        }
+
+        if output.len() != window_size {
+            return Err("a window is truncated");
+        }
+        if checksum.map_or(false, |checksum| checksum != adler32(&output)) {
+            return Err("the target checksum doesn't match, the patch was made for another file");
+        }
+        target.extend_from_slice(&output);
+    }
+    Ok(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_code_table_window() {
+        let source = b"abcdefgh";
+        // COPY 4 from the source, ADD "XY", then COPY 6 from the window overlapping itself
+        let instructions = [20, 3, 22];
+        let data = b"XY";
+        let addresses = [2, 8 + 4];
+
+        let mut patch = MAGIC.to_vec();
+        patch.push(0);
+        patch.extend_from_slice(&[VCD_SOURCE, source.len() as u8, 0]);
+        let mut delta = vec![12, 0, data.len() as u8, instructions.len() as u8, addresses.len() as u8];
+        delta.extend_from_slice(data);
+        delta.extend_from_slice(&instructions);
+        delta.extend_from_slice(&addresses);
+        patch.push(delta.len() as u8);
+        patch.extend_from_slice(&delta);
+
+        assert_eq!(apply(&patch, source).unwrap(), b"cdefXYXYXYXY");
+    }
+
+    #[test]
+    fn copy_from_the_future() {
+        let mut patch = MAGIC.to_vec();
+        patch.extend_from_slice(&[0, 0, 6, 4, 0, 0, 1, 1, 20, 0]);
+        assert!(apply(&patch, b"").is_err());
+    }
+}
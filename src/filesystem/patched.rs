@@ -0,0 +1,96 @@
+use std::rc::Rc;
+use crate::error::EmulatorError;
+use super::base::{components, same_path, DriveBackend, DriveEntry, DriveFile};
+use super::patch::Patch;
+
+/// A file replaced by its patched copy
+struct PatchedFile {
+    /// Path components, for comparing paths the way the drive does
+    path: Vec<String>,
+    data: Rc<Vec<u8>>,
+}
+
+/// A backend serving some of its files patched
+///
+/// The patches are applied (and their checksums verified) when the backend is created, so a
+/// wrong base file fails the boot instead of a read in the middle of the game.
+pub struct PatchedBackend {
+    inner: Box<dyn DriveBackend>,
+    files: Vec<PatchedFile>,
+}
+
+impl PatchedBackend {
+    /// Applies each patch to the file of `inner` it targets, in order
+    pub fn new(inner: Box<dyn DriveBackend>, patches: Vec<(String, Patch)>) -> Result<PatchedBackend, EmulatorError> {
+        let mut backend = PatchedBackend { inner, files: Vec::new() };
+        for (target, patch) in patches {
+            let source = backend.open(target.as_str())?;
+            let patched = patch.apply(&source.read_at(0, source.size())?)?;
+            if patched.len() > u32::MAX as usize {
+                return Err(EmulatorError::InvalidPatch(format!("{} would be larger than 4GB", target)));
+            }
+            let data = Rc::new(patched);
+            match backend.files.iter_mut().find(|file| same_path(&file.path, target.as_str())) {
+                Some(file) => file.data = data,
+                None => backend.files.push(PatchedFile {
+                    path: components(target.as_str()).map(String::from).collect(),
+                    data,
+                }),
+            }
+        }
+        Ok(backend)
+    }
+
+    fn patched(&self, path: &str) -> Option<&Rc<Vec<u8>>> {
+        self.files.iter().find(|file| same_path(&file.path, path)).map(|file| &file.data)
+    }
+}
+
+impl DriveBackend for PatchedBackend {
+    fn stat(&self, path: &str) -> Result<DriveEntry, EmulatorError> {
+        let mut entry = self.inner.stat(path)?;
+        if let Some(data) = self.patched(path) {
+            entry.size = data.len() as u32;
+        }
+        Ok(entry)
+    }
+
+    fn list_directory(&self, path: &str) -> Result<Vec<DriveEntry>, EmulatorError> {
+        let mut entries = self.inner.list_directory(path)?;
+        for entry in entries.iter_mut() {
+            if let Some(data) = self.patched(format!("{}/{}", path, entry.name).as_str()) {
+                entry.size = data.len() as u32;
+            }
+        }
+        Ok(entries)
+    }
+
+    fn open(&self, path: &str) -> Result<Box<dyn DriveFile>, EmulatorError> {
+        match self.patched(path) {
+            // Where the original was, the patched copy is read from the same place
+            Some(data) => Ok(Box::new(MemoryFile { data: data.clone(), sector: self.inner.stat(path)?.sector })),
+            None => self.inner.open(path),
+        }
+    }
+}
+
+struct MemoryFile {
+    data: Rc<Vec<u8>>,
+    sector: Option<u64>,
+}
+
+impl DriveFile for MemoryFile {
+    fn size(&self) -> u32 {
+        self.data.len() as u32
+    }
+
+    fn read_at(&self, offset: u32, count: u32) -> Result<Vec<u8>, EmulatorError> {
+        let start = (offset as usize).min(self.data.len());
+        let end = start.saturating_add(count as usize).min(self.data.len());
+        Ok(self.data[start..end].to_vec())
+    }
+
+    fn sector(&self) -> Option<u64> {
+        self.sector
+    }
+}
@@ -0,0 +1,116 @@
+use crate::config::DriveConfig;
+
+/// Length of a frame in microseconds, at 60 frames a second
+pub const FRAME_US: u64 = 1_000_000 / 60;
+
+/// Seek and transfer time of the optical drive
+///
+/// A seek costs `seek_ms`, plus up to `full_seek_ms` in proportion to the distance the head
+/// travels across the disc. Reading the sector right after the last one read needs no seek.
+/// Transfers cost `1 / sectors_per_second` a sector.
+pub struct DriveTiming {
+    config: DriveConfig,
+    /// Sector after the last one read
+    head: u64,
+    /// Charged but not turned into stalled frames yet
+    pending_us: u64,
+    /// Charged since the guest last asked
+    reported_us: u64,
+}
+
+impl DriveTiming {
+    pub fn new(config: DriveConfig) -> DriveTiming {
+        DriveTiming { config, head: 0, pending_us: 0, reported_us: 0 }
+    }
+
+    /// Charges reading `sectors` sectors from `start` on a disc of `disc_sectors` sectors,
+    /// returns the time it takes in microseconds
+    ///
+    /// [MachineConfig::validate](crate::config::MachineConfig::validate) rejects the drive
+    /// settings this can't make sense of, the arithmetic saturates anyway so a configuration
+    /// built by hand can't make it panic.
+    pub fn read(&mut self, start: u64, sectors: u64, disc_sectors: u64) -> u64 {
+        let config = &self.config;
+        let distance = if start > self.head { start - self.head } else { self.head - start };
+        let seek_us = match distance {
+            0 => 0,
+            _ => {
+                let travel_ms = config.full_seek_ms.saturating_sub(config.seek_ms)
+                    .saturating_mul(distance.min(disc_sectors)) / disc_sectors.max(1);
+                config.seek_ms.saturating_add(travel_ms).saturating_mul(1000)
+            }
+        };
+        let transfer_us = sectors.saturating_mul(1_000_000) / config.sectors_per_second.max(1);
+        self.head = start.saturating_add(sectors);
+        let total = seek_us.saturating_add(transfer_us);
+        self.pending_us = self.pending_us.saturating_add(total);
+        self.reported_us = self.reported_us.saturating_add(total);
+        total
+    }
+
+    /// Whole frames charged since the last call, the remainder is carried over
+    pub fn take_frames(&mut self) -> u64 {
+        let frames = self.pending_us / FRAME_US;
+        self.pending_us %= FRAME_US;
+        frames
+    }
+
+    /// Microseconds charged since the last call
+    pub fn take_reported(&mut self) -> u64 {
+        std::mem::take(&mut self.reported_us)
+    }
+
+    /// Where the head is and what wasn't turned into frames yet, for save states
+    pub fn state(&self) -> (u64, u64) {
+        (self.head, self.pending_us)
+    }
+
+    pub fn restore(&mut self, head: u64, pending_us: u64) {
+        self.head = head;
+        self.pending_us = pending_us;
+        self.reported_us = 0;
+    }
+
+    pub fn reset(&mut self) {
+        self.restore(0, 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timing() -> DriveTiming {
+        DriveTiming::new(DriveConfig { accurate_timing: true, seek_ms: 80, full_seek_ms: 300, sectors_per_second: 150 })
+    }
+
+    #[test]
+    fn sequential_reads_only_transfer() {
+        let mut timing = timing();
+        assert_eq!(timing.read(0, 150, 1000), 1_000_000);
+        assert_eq!(timing.read(150, 15, 1000), 100_000);
+    }
+
+    #[test]
+    fn seeks_grow_with_the_distance() {
+        let mut timing = timing();
+        // 80ms, plus 220ms across the whole disc in proportion to the 500 sectors travelled
+        assert_eq!(timing.read(500, 0, 1000), 190_000);
+        assert_eq!(timing.read(499, 0, 1000), 80_000);
+        // past the end of the disc costs a full seek, not more
+        assert_eq!(timing.read(5000, 0, 1000), 300_000);
+    }
+
+    #[test]
+    fn frames_carry_the_remainder_over() {
+        let mut timing = timing();
+        timing.read(0, 3, 1000);
+        assert_eq!(timing.take_frames(), 1);
+        assert_eq!(timing.state(), (3, 20_000 - FRAME_US));
+        timing.read(3, 3, 1000);
+        assert_eq!(timing.take_frames(), 1);
+        assert_eq!(timing.state(), (6, 40_000 - 2 * FRAME_US));
+        assert_eq!(timing.take_reported(), 40_000);
+        assert_eq!(timing.take_reported(), 0);
+    }
+}
@@ -0,0 +1,378 @@
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use unicorn::{RegisterARM, UnicornHandle};
+use unicorn::unicorn_const::uc_error;
+use crate::breakpoints::Breakpoints;
+
+/// Registers in the order GDB numbers them, as described by [TARGET_XML]
+static GDB_REGISTERS: [RegisterARM; 17] = [
+    RegisterARM::R0,
+    RegisterARM::R1,
+    RegisterARM::R2,
+    RegisterARM::R3,
+    RegisterARM::R4,
+    RegisterARM::R5,
+    RegisterARM::R6,
+    RegisterARM::R7,
+    RegisterARM::R8,
+    RegisterARM::R9,
+    RegisterARM::R10,
+    RegisterARM::R11,
+    RegisterARM::R12,
+    RegisterARM::SP,
+    RegisterARM::LR,
+    RegisterARM::PC,
+    RegisterARM::CPSR,
+];
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target>
+  <architecture>arm</architecture>
+  <feature name="org.gnu.gdb.arm.core">
+    <reg name="r0" bitsize="32"/>
+    <reg name="r1" bitsize="32"/>
+    <reg name="r2" bitsize="32"/>
+    <reg name="r3" bitsize="32"/>
+    <reg name="r4" bitsize="32"/>
+    <reg name="r5" bitsize="32"/>
+    <reg name="r6" bitsize="32"/>
+    <reg name="r7" bitsize="32"/>
+    <reg name="r8" bitsize="32"/>
+    <reg name="r9" bitsize="32"/>
+    <reg name="r10" bitsize="32"/>
+    <reg name="r11" bitsize="32"/>
+    <reg name="r12" bitsize="32"/>
+    <reg name="sp" bitsize="32" type="data_ptr"/>
+    <reg name="lr" bitsize="32"/>
+    <reg name="pc" bitsize="32" type="code_ptr"/>
+    <reg name="cpsr" bitsize="32"/>
+  </feature>
+</target>
+"#;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+/// Largest packet GDB may send and the stub replies with, as advertised in `qSupported`
+const PACKET_SIZE: usize = 0x4000;
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len()).step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn parse_hex(value: &str) -> Option<u64> {
+    u64::from_str_radix(value, 16).ok()
+}
+
+/// Parses the `addr,length` part of m/M/Z/z packets
+fn parse_address_length(args: &str) -> Option<(u64, u64)> {
+    let (address, length) = args.split_once(',')?;
+    Some((parse_hex(address)?, parse_hex(length)?))
+}
+
+/// GDB remote serial protocol stub
+///
+/// Listens on a local TCP port so `arm-none-eabi-gdb` can attach with `target remote :<port>`.
+/// The machine starts halted. While halted, [GdbStub::serve] answers register/memory
+/// reads and writes and breakpoint changes until GDB asks to continue or step; the main loop
+/// then runs the CPU and reports back through [GdbStub::after_run].
+pub struct GdbStub {
+    stream: TcpStream,
+    /// Bytes [GdbStub::poll_interrupt] read that weren't a Ctrl-C, read again before the stream
+    received: VecDeque<u8>,
+    breakpoints: Breakpoints,
+    halted: bool,
+    stepping: bool,
+    detached: bool,
+    signal: u8,
+}
+
+impl GdbStub {
+    /// Waits for GDB to connect on `port` and installs the breakpoint hook
+    pub fn listen(emu: &mut UnicornHandle, port: u16) -> std::io::Result<GdbStub> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        println!("Waiting for GDB on port {}", port);
+        let (stream, address) = listener.accept()?;
+        println!("GDB connected from {}", address);
+        stream.set_nodelay(true)?;
+
+        let breakpoints = Breakpoints::install(emu)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, format!("{:?}", e)))?;
+
+        Ok(GdbStub {
+            stream,
+            received: VecDeque::new(),
+            breakpoints,
+            halted: true,
+            stepping: false,
+            detached: false,
+            signal: SIGTRAP,
+        })
+    }
+
+    /// Instruction count to pass to `emu_start`: 1 when single-stepping, unlimited otherwise
+    pub fn instruction_count(&self) -> usize {
+        if self.stepping { 1 } else { 0 }
+    }
+
+    /// Handles GDB packets while the machine is halted
+    ///
+    /// Returns once GDB continues or steps. Returns false if GDB killed the machine or the
+    /// connection dropped.
+    pub fn serve(&mut self, emu: &mut UnicornHandle) -> bool {
+        while self.halted && !self.detached {
+            let packet = match self.read_packet() {
+                Some(packet) => packet,
+                None => return false,
+            };
+            let reply = match self.handle_packet(emu, packet.as_str()) {
+                Some(reply) => reply,
+                None => return false,
+            };
+            if let Some(reply) = reply {
+                if self.send_packet(reply.as_str()).is_err() {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    /// Reports why the CPU stopped after an `emu_start` call, if GDB has to know
+    ///
+    /// Returns true if the machine is now halted waiting for GDB.
+    pub fn after_run(&mut self, result: Result<(), uc_error>) -> bool {
+        if self.detached {
+            return false;
+        }
+        let signal = if result.is_err() {
+            SIGSEGV
+        } else if self.stepping || self.breakpoints.take_hit() {
+            SIGTRAP
+        } else if self.poll_interrupt() {
+            SIGINT
+        } else {
+            return false;
+        };
+
+        self.stepping = false;
+        self.halted = true;
+        self.signal = signal;
+        let _ = self.send_packet(format!("S{:02x}", signal).as_str());
+        true
+    }
+
+    /// Stops the machine if GDB sent a Ctrl-C while it was running
+    ///
+    /// Anything else GDB sent is kept for [GdbStub::read_packet].
+    fn poll_interrupt(&mut self) -> bool {
+        let mut bytes = [0u8; 64];
+        if self.stream.set_nonblocking(true).is_err() {
+            return false;
+        }
+        let mut interrupted = false;
+        if let Ok(count) = self.stream.read(&mut bytes) {
+            for &byte in &bytes[..count] {
+                match byte {
+                    0x03 => interrupted = true,
+                    byte => self.received.push_back(byte),
+                }
+            }
+        }
+        self.stream.set_nonblocking(false).unwrap();
+        interrupted
+    }
+
+    /// Returns None to stop serving, Some(None) when no reply must be sent
+    fn handle_packet(&mut self, emu: &mut UnicornHandle, packet: &str) -> Option<Option<String>> {
+        let (command, args) = packet.split_at(packet.len().min(1));
+        let reply = match command {
+            "?" => format!("S{:02x}", self.signal),
+            "g" => {
+                let mut bytes = Vec::new();
+                for register in GDB_REGISTERS {
+                    let value = emu.reg_read(register as i32).unwrap_or(0) as u32;
+                    bytes.extend_from_slice(&value.to_le_bytes());
+                }
+                to_hex(bytes.as_slice())
+            }
+            "G" => match from_hex(args) {
+                Some(bytes) if bytes.len() >= GDB_REGISTERS.len() * 4 => {
+                    for (register, value) in GDB_REGISTERS.iter().zip(bytes.chunks(4)) {
+                        let value = u32::from_le_bytes([value[0], value[1], value[2], value[3]]);
+                        emu.reg_write(*register as i32, value as u64).unwrap();
+                    }
+                    String::from("OK")
+                }
+                _ => String::from("E01"),
+            },
+            "p" => match parse_hex(args).and_then(|n| GDB_REGISTERS.get(n as usize)) {
+                Some(register) => {
+                    let value = emu.reg_read(*register as i32).unwrap_or(0) as u32;
+                    to_hex(&value.to_le_bytes())
+                }
+                None => String::from("E01"),
+            },
+            "P" => {
+                let register = args.split_once('=')
+                    .and_then(|(n, value)| Some((GDB_REGISTERS.get(parse_hex(n)? as usize)?, from_hex(value)?)));
+                match register {
+                    Some((register, value)) if value.len() == 4 => {
+                        let value = u32::from_le_bytes([value[0], value[1], value[2], value[3]]);
+                        emu.reg_write(*register as i32, value as u64).unwrap();
+                        String::from("OK")
+                    }
+                    _ => String::from("E01"),
+                }
+            }
+            "m" => match parse_address_length(args) {
+                // two hex digits a byte have to fit in a packet
+                Some((address, length)) => match emu.mem_read_as_vec(address, (length as usize).min(PACKET_SIZE / 2)) {
+                    Ok(bytes) => to_hex(bytes.as_slice()),
+                    Err(_) => String::from("E14"),
+                },
+                None => String::from("E01"),
+            },
+            "M" => {
+                let write = args.split_once(':')
+                    .and_then(|(range, data)| Some((parse_address_length(range)?, from_hex(data)?)));
+                match write {
+                    Some(((address, length), data)) if data.len() as u64 == length => {
+                        match emu.mem_write(address, data.as_slice()) {
+                            Ok(_) => String::from("OK"),
+                            Err(_) => String::from("E14"),
+                        }
+                    }
+                    _ => String::from("E01"),
+                }
+            }
+            "Z" | "z" => {
+                // software and hardware breakpoints are both implemented with the code hook
+                let breakpoint = args.split_once(',')
+                    .filter(|(kind, _)| *kind == "0" || *kind == "1")
+                    .and_then(|(_, range)| parse_address_length(range));
+                match breakpoint {
+                    Some((address, _)) => {
+                        if command == "Z" {
+                            self.breakpoints.insert(address);
+                        } else {
+                            self.breakpoints.remove(address);
+                        }
+                        String::from("OK")
+                    }
+                    None => String::new(),
+                }
+            }
+            "c" | "s" => {
+                if let Some(address) = parse_hex(args) {
+                    emu.reg_write(RegisterARM::PC as i32, address).unwrap();
+                }
+                self.stepping = command == "s";
+                self.breakpoints.skip_next();
+                self.halted = false;
+                return Some(None);
+            }
+            "H" => String::from("OK"),
+            "k" => return None,
+            "D" => {
+                self.detached = true;
+                self.halted = false;
+                self.breakpoints.clear();
+                String::from("OK")
+            }
+            "q" => self.handle_query(args),
+            _ => String::new(),
+        };
+        Some(Some(reply))
+    }
+
+    fn handle_query(&self, query: &str) -> String {
+        if query.starts_with("Supported") {
+            format!("PacketSize={:x};qXfer:features:read+", PACKET_SIZE)
+        } else if query == "Attached" {
+            String::from("1")
+        } else if query == "fThreadInfo" {
+            String::from("m1")
+        } else if query == "sThreadInfo" {
+            String::from("l")
+        } else if let Some(range) = query.strip_prefix("Xfer:features:read:target.xml:") {
+            match parse_address_length(range) {
+                Some((offset, length)) => {
+                    let offset = (offset as usize).min(TARGET_XML.len());
+                    let end = offset.saturating_add(length as usize).min(TARGET_XML.len());
+                    let more = if end < TARGET_XML.len() { "m" } else { "l" };
+                    format!("{}{}", more, &TARGET_XML[offset..end])
+                }
+                None => String::from("E01"),
+            }
+        } else {
+            String::new()
+        }
+    }
+
+    fn read_byte(&mut self) -> Option<u8> {
+        if let Some(byte) = self.received.pop_front() {
+            return Some(byte);
+        }
+        let mut byte = [0u8; 1];
+        match self.stream.read(&mut byte) {
+            Ok(1) => Some(byte[0]),
+            _ => None,
+        }
+    }
+
+    fn read_packet(&mut self) -> Option<String> {
+        loop {
+            // skip acks and stray Ctrl-C until the start of a packet
+            while self.read_byte()? != b'$' {}
+
+            // packets longer than advertised are read to their end and refused
+            let mut data = Vec::new();
+            let mut oversized = false;
+            loop {
+                match self.read_byte()? {
+                    b'#' => break,
+                    _ if data.len() >= PACKET_SIZE => oversized = true,
+                    byte => data.push(byte),
+                }
+            }
+            let checksum = [self.read_byte()?, self.read_byte()?];
+            let expected = std::str::from_utf8(&checksum).ok().and_then(|c| u8::from_str_radix(c, 16).ok());
+            let actual = data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+
+            if !oversized && expected == Some(actual) {
+                self.stream.write_all(b"+").ok()?;
+                return String::from_utf8(data).ok();
+            }
+            self.stream.write_all(b"-").ok()?;
+        }
+    }
+
+    fn send_packet(&mut self, data: &str) -> std::io::Result<()> {
+        let checksum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        loop {
+            self.stream.write_all(format!("${}#{:02x}", data, checksum).as_bytes())?;
+            match self.read_byte() {
+                Some(b'+') => return Ok(()),
+                Some(_) => continue,
+                None => return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "GDB disconnected")),
+            }
+        }
+    }
+
+    pub fn stop(&mut self, emu: &mut UnicornHandle) {
+        self.breakpoints.remove_hook(emu).unwrap();
+    }
+}
@@ -8,8 +8,38 @@ use crate::features::EmulatorFeature;
 
 pub type Vert = [f32; 8];
 
+/// Emulator (not guest) actions bound to keys on the host window, see [crate::config::KeyBindings]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Hotkey {
+    SaveState,
+    LoadState,
+    Reset,
+    SwapDisc,
+}
+
+/// Host keys hotkeys can be bound to, every backend understands these names
+pub const KEY_NAMES: [&str; 22] = [
+    "F1", "F2", "F3", "F4", "F5", "F6", "F7", "F8", "F9", "F10", "F11", "F12",
+    "Tab", "Space", "Enter", "Backspace", "Insert", "Delete", "Home", "End", "PageUp", "PageDown",
+];
+
+/// Copy of the last rendered frame
+#[derive(Debug, Clone)]
+pub struct Framebuffer {
+    pub width: usize,
+    pub height: usize,
+    /// Row-major 0xAARRGGBB pixels
+    pub pixels: Vec<u32>,
+}
+
 pub trait GPUBackend {
     fn update(&mut self);
     fn load_vertices(&mut self, vertices: Vec<Vert>, indexes: Vec<u16>);
     fn is_open(&self) -> bool;
+    /// Hotkeys pressed since the last call
+    fn take_hotkeys(&mut self) -> Vec<Hotkey>;
+    /// The last rendered frame, for backends that render on the CPU
+    fn framebuffer(&self) -> Option<Framebuffer> {
+        None
+    }
 }
@@ -1,8 +1,9 @@
 use euc::{Interpolate, Pipeline, rasterizer};
 use euc::buffer::Buffer2d;
-use minifb::{Window, WindowOptions};
+use minifb::{Key, KeyRepeat, Window, WindowOptions};
 
-use crate::gpu::base::{GPUBackend, Vert};
+use crate::config::KeyBindings;
+use crate::gpu::base::{Framebuffer, GPUBackend, Hotkey, Vert};
 
 struct Triangle;
 
@@ -109,15 +110,50 @@ pub struct EucGPUBackend {
     window: minifb::Window,
     triangles: Option<Vec<<Triangle as Pipeline>::Vertex>>,
     buffer: Buffer2d<u32>,
+    size: [usize; 2],
+    bindings: Vec<(Key, Hotkey)>,
+}
+
+/// minifb key for a name from [crate::gpu::KEY_NAMES]
+fn key_code(name: &str) -> Option<Key> {
+    Some(match name {
+        "F1" => Key::F1,
+        "F2" => Key::F2,
+        "F3" => Key::F3,
+        "F4" => Key::F4,
+        "F5" => Key::F5,
+        "F6" => Key::F6,
+        "F7" => Key::F7,
+        "F8" => Key::F8,
+        "F9" => Key::F9,
+        "F10" => Key::F10,
+        "F11" => Key::F11,
+        "F12" => Key::F12,
+        "Tab" => Key::Tab,
+        "Space" => Key::Space,
+        "Enter" => Key::Enter,
+        "Backspace" => Key::Backspace,
+        "Insert" => Key::Insert,
+        "Delete" => Key::Delete,
+        "Home" => Key::Home,
+        "End" => Key::End,
+        "PageUp" => Key::PageUp,
+        "PageDown" => Key::PageDown,
+        _ => return None,
+    })
 }
 
 impl EucGPUBackend {
-    pub fn new(window_label: &str, width: usize, height: usize) -> Self {
+    pub fn new(window_label: &str, width: usize, height: usize, keys: &KeyBindings) -> Self {
         let window = Window::new(window_label, width, height, WindowOptions::default()).unwrap();
         Self {
             buffer: Buffer2d::new([width, height], 0),
+            size: [width, height],
             window,
-            triangles: None
+            triangles: None,
+            bindings: keys.hotkeys().iter()
+                .filter_map(|(name, hotkey)| key_code(name).map(|key| (key, *hotkey)))
+                .collect(),
         }
     }
 
@@ -127,7 +163,7 @@ impl EucGPUBackend {
             Triangle.draw::<rasterizer::Triangles<(f32, )>, _>(vx,
                                                                &mut self.buffer,
                                                                None);
-            self.window.update_with_buffer(self.buffer.as_ref(), 800, 600).unwrap();
+            self.window.update_with_buffer(self.buffer.as_ref(), self.size[0], self.size[1]).unwrap();
             print!("Vertices: {}", vx.len());
             self.triangles = None;
         }
@@ -150,4 +186,16 @@ impl GPUBackend for EucGPUBackend {
     fn is_open(&self) -> bool {
         self.window.is_open()
     }
+
+    fn take_hotkeys(&mut self) -> Vec<Hotkey> {
+        self.bindings.iter()
+            .filter(|(key, _)| self.window.is_key_pressed(*key, KeyRepeat::No))
+            .map(|(_, hotkey)| *hotkey)
+            .collect()
+    }
+
+    fn framebuffer(&self) -> Option<Framebuffer> {
+        let [width, height] = self.size;
+        Some(Framebuffer { width, height, pixels: self.buffer.as_ref().to_vec() })
+    }
 }
@@ -1,42 +1,54 @@
-use std::ptr::null_mut;
 use unicorn::{RegisterARM, UnicornHandle};
 use std::any::Any;
 use std::mem::size_of;
-use unicorn::ffi::uc_hook;
-use crate::features::EmulatorFeature;
-use crate::gpu::base::{GPUBackend, Vert};
+use std::ops::Range;
+use crate::config::{KeyBindings, VideoConfig};
+use crate::features::{EmulatorFeature, FeatureDescriptor, FrameRequests};
+use crate::error::EmulatorError;
+use crate::syscalls::{read_guest_memory, SyscallDispatcher, SyscallError, SyscallResult};
+use crate::gpu::base::{Framebuffer, GPUBackend, Hotkey, Vert};
+use crate::savestate::{StateReader, StateWriter};
+
+const SYSCALLS: Range<u32> = 0x160..0x180;
 
 /// Video output
 ///
-/// This feature provides syscalls to draw 3D graphics on a 800x600 screen
+/// This feature provides syscalls to draw 3D graphics, on a 800x600 screen unless configured otherwise
 pub struct GPUFeature {
-    hook: uc_hook,
     backend: Box<dyn GPUBackend>,
+    vertices: Vec<Vert>,
+    indexes: Vec<u16>,
+    /// The guest called the present syscall since the last `on_frame`
+    presented: bool,
 }
 
 
 impl GPUFeature {
-    pub fn new<Backend: 'static + GPUBackend>(constructor: fn(label: &str, width: usize, height: usize) -> Backend) -> Box<GPUFeature> {
-        let backend = Box::new(constructor("ARMchine", 800, 600));
+    pub fn new<Backend: 'static + GPUBackend>(constructor: fn(label: &str, width: usize, height: usize, keys: &KeyBindings) -> Backend,
+                                              video: &VideoConfig, keys: &KeyBindings) -> Box<GPUFeature> {
+        let backend = Box::new(constructor("ARMchine", video.width, video.height, keys));
         Box::new(GPUFeature {
-            hook: null_mut(),
             backend,
+            vertices: Vec::new(),
+            indexes: Vec::new(),
+            presented: false,
         })
     }
 
-    pub fn is_open(&self) -> bool {
-        self.backend.is_open()
+    /// The last rendered frame, `None` if the backend renders on the GPU
+    pub fn framebuffer(&self) -> Option<Framebuffer> {
+        self.backend.framebuffer()
     }
 
-    pub fn update(&mut self) {
-        self.backend.update();
-    }
-
-    unsafe fn copy_vertex_from_memory(gpuptr: *mut GPUFeature, emu: UnicornHandle) {
-        let addr = emu.reg_read_i32(RegisterARM::R1 as i32).unwrap();
-        let vert_count = emu.reg_read_i32(RegisterARM::R2 as i32).unwrap();
+    unsafe fn copy_vertex_from_memory(gpuptr: *mut GPUFeature, emu: &UnicornHandle) -> SyscallResult {
+        let addr = emu.reg_read(RegisterARM::R1 as i32)?;
+        let vert_count = emu.reg_read(RegisterARM::R2 as i32)? as u32;
+        // indexes are 16 bits wide, more vertices can't be drawn anyway
+        if vert_count > u16::MAX as u32 + 1 {
+            return Err(SyscallError::InvalidArgument);
+        }
 
-        let vertex = emu.mem_read_as_vec(addr as u64, (vert_count as usize * size_of::<Vert>()) as usize).unwrap();
+        let vertex = read_guest_memory(emu, addr, vert_count as usize * size_of::<Vert>())?;
         let mut vx = Vec::<Vert>::new();
 
         for i in 0..vert_count {
@@ -52,51 +64,44 @@ impl GPUFeature {
 
         // println!("Vertex count: {}", vx.len());
 
-        let index_addr = emu.reg_read_i32(RegisterARM::R3 as i32).unwrap();
-        let index_count = emu.reg_read_i32(RegisterARM::R4 as i32).unwrap() as usize;
+        let index_addr = emu.reg_read(RegisterARM::R3 as i32)?;
+        let index_count = emu.reg_read(RegisterARM::R4 as i32)? as u32 as usize;
 
-        let index = emu.mem_read_as_vec(index_addr as u64, index_count as usize * size_of::<u16>()).unwrap();
+        let index = read_guest_memory(emu, index_addr, index_count * size_of::<u16>())?;
 
         let index: Vec<u16> = (0..index_count).map(|i| u16::from_le_bytes([index[i*2], index[i*2+1]])).collect();
+        if index.iter().any(|i| *i as u32 >= vert_count) {
+            return Err(SyscallError::InvalidArgument);
+        }
 
+        (*gpuptr).vertices = vx.clone();
+        (*gpuptr).indexes = index.clone();
         (*gpuptr).backend.load_vertices(vx, index);
+        Ok(())
     }
 }
 
 /// | Syscall | Parameters | Description |
 /// | ------- | ---------- | ----------- |
-/// | 0x160 | Vertex*: address of vertex list to copy, size_t: vertex count, uint16_t*: address of indexes to copy, size_t: index count | Copies vertices from array into the drawing backend |
-/// | 0x161 | None | Currently does nothing. It's supposed to use as a sort of vsync without having to copy new vertices |
+/// | 0x160 | Vertex*: address of vertex list to copy, size_t: vertex count, uint16_t*: address of indexes to copy, size_t: index count | Copies vertices from array into the drawing backend. Returns -2 if an index is past the vertex list |
+/// | 0x161 | None | Ends the frame: the emulator yields to the host, which presents the last draw list (and may wait for vsync) |
 impl EmulatorFeature for GPUFeature {
-    fn init(&mut self, emulator: &mut UnicornHandle) -> Result<(), String> {
+    fn init(&mut self, _emulator: &mut UnicornHandle, syscalls: &mut SyscallDispatcher) -> Result<(), EmulatorError> {
         let gpuptr: *mut GPUFeature = self;
 
-        let hook = emulator.add_intr_hook(move |emu, _syscall| unsafe {
-            let syscall = emu.reg_read_i32(RegisterARM::R7 as i32).unwrap();
-
+        syscalls.register(&self.name(), SYSCALLS, move |emu, syscall| unsafe {
             match syscall {
-                0x160 => {
-                    Self::copy_vertex_from_memory(gpuptr, emu);
+                0x160 => Self::copy_vertex_from_memory(gpuptr, emu),
+                0x161 => {
+                    (*gpuptr).presented = true;
+                    Ok(emu.emu_stop()?)
                 }
-                0x161 => {}
-                _ => {}
-            }
-        });
-
-        match hook {
-            Ok(hook) => {
-                self.hook = hook;
-                Ok(())
+                _ => Err(SyscallError::Unknown),
             }
-            Err(err) => {
-                Err(format!("{:?}", err))
-            }
-        }
+        })
     }
 
-    fn stop(&mut self, _emulator: &mut UnicornHandle) -> Result<(), String> {
-        _emulator.remove_hook(self.hook).unwrap();
-        self.hook = null_mut();
+    fn stop(&mut self, _emulator: &mut UnicornHandle) -> Result<(), EmulatorError> {
         Ok(())
     }
 
@@ -107,4 +112,75 @@ impl EmulatorFeature for GPUFeature {
     fn name(&self) -> String {
         String::from("GPUFeature")
     }
+
+    fn descriptor(&self) -> FeatureDescriptor {
+        FeatureDescriptor { syscalls: vec![SYSCALLS], mmio: Vec::new() }
+    }
+
+    /// Drops the last draw list
+    fn reset(&mut self, _emulator: &mut UnicornHandle) -> Result<(), EmulatorError> {
+        self.vertices.clear();
+        self.indexes.clear();
+        self.presented = false;
+        self.backend.load_vertices(Vec::new(), Vec::new());
+        Ok(())
+    }
+
+    /// Presents the last draw list and forwards window events to the main loop
+    fn on_frame(&mut self, _emulator: &mut UnicornHandle, requests: &mut FrameRequests) -> Result<(), EmulatorError> {
+        self.backend.update();
+        requests.presented |= std::mem::take(&mut self.presented);
+        requests.quit |= !self.backend.is_open();
+
+        for hotkey in self.backend.take_hotkeys() {
+            match hotkey {
+                Hotkey::SaveState => requests.save_state = true,
+                Hotkey::LoadState => requests.load_state = true,
+                Hotkey::Reset => requests.reset = true,
+                Hotkey::SwapDisc => requests.swap_disc = true,
+            }
+        }
+        Ok(())
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut writer = StateWriter::new();
+        writer.write_u32(self.vertices.len() as u32);
+        for vertex in &self.vertices {
+            for component in vertex {
+                writer.write_u32(component.to_bits());
+            }
+        }
+        writer.write_u32(self.indexes.len() as u32);
+        for index in &self.indexes {
+            writer.write_u32(*index as u32);
+        }
+        writer.into_bytes()
+    }
+
+    fn load_state(&mut self, _emulator: &mut UnicornHandle, state: &[u8]) -> Result<(), EmulatorError> {
+        let mut reader = StateReader::new(state);
+        let mut vertices = Vec::new();
+        for _ in 0..reader.read_u32()? {
+            let mut vertex: Vert = [0f32; 8];
+            for component in vertex.iter_mut() {
+                *component = f32::from_bits(reader.read_u32()?);
+            }
+            vertices.push(vertex);
+        }
+        let mut indexes = Vec::new();
+        for _ in 0..reader.read_u32()? {
+            // checked like the draw syscall does, the renderer trusts them
+            let index = reader.read_u32()?;
+            if index as usize >= vertices.len() || index > u16::MAX as u32 {
+                return Err(EmulatorError::InvalidState(format!("vertex index {} out of bounds", index)));
+            }
+            indexes.push(index as u16);
+        }
+
+        self.vertices = vertices.clone();
+        self.indexes = indexes.clone();
+        self.backend.load_vertices(vertices, indexes);
+        Ok(())
+    }
 }
@@ -1,7 +1,10 @@
+use crate::config::{KeyBindings, VideoConfig};
 use crate::gpu::base::GPUBackend;
 
 mod base;
 
+pub use base::{Framebuffer, Hotkey, KEY_NAMES};
+
 #[cfg(feature = "euc-backend")]
 pub mod euc;
 
@@ -11,23 +14,24 @@ pub mod wgpu;
 pub mod feature;
 
 #[cfg(feature = "gpu-feature")]
-pub(crate) fn create_feature(preference: &Option<String>) -> Box<feature::GPUFeature> {
+pub(crate) fn create_feature(video: &VideoConfig, keys: &KeyBindings) -> Box<feature::GPUFeature> {
+    let preference = &video.backend;
     if *preference == None {
         #[cfg(feature = "wgpu-backend")] {
-            return feature::GPUFeature::new(wgpu::WgpuBackend::new);
+            return feature::GPUFeature::new(wgpu::WgpuBackend::new, video, keys);
         }
         #[cfg(feature = "euc-backend")] {
-            return feature::GPUFeature::new(euc::EucGPUBackend::new);
+            return feature::GPUFeature::new(euc::EucGPUBackend::new, video, keys);
         }
         panic!("No backends available!");
     } else if preference.as_ref().unwrap().eq("euc") {
         #[cfg(feature = "euc-backend")] {
-            return feature::GPUFeature::new(euc::EucGPUBackend::new);
+            return feature::GPUFeature::new(euc::EucGPUBackend::new, video, keys);
         }
         panic!("Requested euc which is unavailable!");
     } else if preference.as_ref().unwrap().eq("wgpu") {
         #[cfg(feature = "wgpu-backend")] {
-            return feature::GPUFeature::new(wgpu::WgpuBackend::new);
+            return feature::GPUFeature::new(wgpu::WgpuBackend::new, video, keys);
         }
         panic!("Requested wgpu which is unavailable!");
     }
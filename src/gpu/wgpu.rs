@@ -6,7 +6,8 @@ use winit::event::{ElementState, Event, KeyboardInput, WindowEvent, VirtualKeyCo
 use winit::event_loop::{ControlFlow, EventLoop};
 use winit::platform::run_return::EventLoopExtRunReturn;
 use winit::window::{Window, WindowBuilder};
-use crate::gpu::base::{GPUBackend, Vert};
+use crate::config::KeyBindings;
+use crate::gpu::base::{GPUBackend, Hotkey, Vert};
 
 pub struct WgpuBackend {
     event_loop: EventLoop<()>,
@@ -21,10 +22,41 @@ pub struct WgpuBackend {
     vertex_buffer: wgpu::Buffer,
     index_buffer: wgpu::Buffer,
     index_count: usize,
+    hotkeys: Vec<Hotkey>,
+    bindings: Vec<(VirtualKeyCode, Hotkey)>,
+}
+
+/// winit key for a name from [crate::gpu::KEY_NAMES]
+fn key_code(name: &str) -> Option<VirtualKeyCode> {
+    Some(match name {
+        "F1" => VirtualKeyCode::F1,
+        "F2" => VirtualKeyCode::F2,
+        "F3" => VirtualKeyCode::F3,
+        "F4" => VirtualKeyCode::F4,
+        "F5" => VirtualKeyCode::F5,
+        "F6" => VirtualKeyCode::F6,
+        "F7" => VirtualKeyCode::F7,
+        "F8" => VirtualKeyCode::F8,
+        "F9" => VirtualKeyCode::F9,
+        "F10" => VirtualKeyCode::F10,
+        "F11" => VirtualKeyCode::F11,
+        "F12" => VirtualKeyCode::F12,
+        "Tab" => VirtualKeyCode::Tab,
+        "Space" => VirtualKeyCode::Space,
+        "Enter" => VirtualKeyCode::Return,
+        "Backspace" => VirtualKeyCode::Back,
+        "Insert" => VirtualKeyCode::Insert,
+        "Delete" => VirtualKeyCode::Delete,
+        "Home" => VirtualKeyCode::Home,
+        "End" => VirtualKeyCode::End,
+        "PageUp" => VirtualKeyCode::PageUp,
+        "PageDown" => VirtualKeyCode::PageDown,
+        _ => return None,
+    })
 }
 
 impl WgpuBackend {
-    pub fn new(window_label: &str, width: usize, height: usize) -> Self {
+    pub fn new(window_label: &str, width: usize, height: usize, keys: &KeyBindings) -> Self {
         env_logger::init();
 
         let event_loop = EventLoop::new();
@@ -139,6 +171,10 @@ impl WgpuBackend {
             vertex_buffer,
             index_buffer,
             index_count,
+            hotkeys: Vec::new(),
+            bindings: keys.hotkeys().iter()
+                .filter_map(|(name, hotkey)| key_code(name).map(|key| (key, *hotkey)))
+                .collect(),
         }
     }
 
@@ -237,6 +273,19 @@ impl GPUBackend for WgpuBackend {
                         self.is_open = false;
                         *control_flow = ControlFlow::Exit;
                     }
+                    WindowEvent::KeyboardInput {
+                        input: KeyboardInput {
+                            state: ElementState::Pressed,
+                            virtual_keycode: Some(key),
+                            ..
+                        }, ..
+                    } => {
+                        for (bound, hotkey) in &self.bindings {
+                            if bound == key {
+                                self.hotkeys.push(*hotkey);
+                            }
+                        }
+                    }
                     _ => {}
                 }
                 _ => (),
@@ -255,4 +304,8 @@ impl GPUBackend for WgpuBackend {
     fn is_open(&self) -> bool {
         self.is_open
     }
+
+    fn take_hotkeys(&mut self) -> Vec<Hotkey> {
+        std::mem::take(&mut self.hotkeys)
+    }
 }
\ No newline at end of file
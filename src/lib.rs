@@ -0,0 +1,51 @@
+//! ARMChine_rs is a fantasy 5gen-ish console based on an ARM946 CPU. The emulator
+//! (which is the reference implementation... for now 😊 ) is developed in a
+//! [Modular manner](features::EmulatorFeature).
+//!
+//! There is an [Optical disk-like Filesystem](filesystem::EmulatorDrive) (a directory of the
+//! host can stand in for the disc while developing),
+//! a [3D Rasterizer](gpu::feature::GPUFeature) with multiple backends,
+//! and of course [Dynamic memory](dynmemory::DynamicMemoryAllocations)!
+//!
+//! The console can be embedded in other tools through [Machine]:
+//!
+//! ```no_run
+//! use armchine_rs::{Machine, MachineConfig};
+//!
+//! let mut machine = Machine::load_disc("game.iso".as_ref(), MachineConfig::default()).unwrap();
+//! let requests = machine.run_frame().unwrap();
+//! let pixels = machine.framebuffer();
+//! machine.shutdown().unwrap();
+//! ```
+//!
+//! All of these are subject to change over the course of the initial development. Have fun!
+
+
+#![allow(unused_imports)]
+
+pub mod emulator;
+pub mod filesystem;
+pub mod features;
+pub mod console;
+pub mod dynmemory;
+pub mod gpu;
+pub mod input;
+pub mod system;
+pub mod savestate;
+pub mod gdb;
+pub mod breakpoints;
+pub mod debugger;
+pub mod symbols;
+pub mod trace;
+pub mod profiler;
+pub mod crash;
+pub mod syscalls;
+pub mod error;
+pub mod machine;
+pub mod config;
+pub mod semihosting;
+pub mod newlib;
+
+pub use features::EmulatorFeature;
+pub use config::MachineConfig;
+pub use machine::Machine;
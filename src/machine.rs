@@ -0,0 +1,253 @@
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use unicorn::{RegisterARM, Unicorn, UnicornHandle};
+use unicorn::unicorn_const::uc_error;
+use crate::{console, dynmemory, emulator, filesystem, gpu, semihosting, system};
+use crate::config::MachineConfig;
+use crate::error::EmulatorError;
+use crate::features::{EmulatorFeature, FrameRequests};
+use crate::filesystem::{DiscLayers, Drive, EmulatorDrive};
+use crate::filesystem::patch::PatchSpec;
+use crate::gpu::Framebuffer;
+use crate::gpu::feature::GPUFeature;
+use crate::savestate::SaveState;
+use crate::symbols::SymbolTable;
+use crate::syscalls::{SyscallDispatcher, TrapInstruction};
+
+fn disc_layers(config: &MachineConfig) -> DiscLayers {
+    DiscLayers {
+        overlays: config.overlays.iter().map(PathBuf::from).collect(),
+        patches: config.patches.iter().map(|spec| PatchSpec::parse(spec)).collect(),
+    }
+}
+
+fn create_features(config: &MachineConfig, disc: &Path, mem_sz: u64) -> Vec<Box<dyn EmulatorFeature>> {
+    let enabled = &config.features;
+    let mut features = Vec::<Box<dyn EmulatorFeature>>::new();
+    if enabled.console {
+        features.push(Box::new(console::ConsoleIO::new(config.memory.console_address)));
+    }
+    if enabled.drive {
+        let mut discs = vec![disc.to_string_lossy().to_string()];
+        discs.extend(config.discs.iter().cloned());
+        features.push(Box::new(filesystem::EmulatorDrive::with_discs(discs, disc_layers(config))
+            .with_timing(config.drive.clone())));
+    }
+    if enabled.dynamic_memory {
+        features.push(Box::new(match config.memory.heap_base {
+            Some(base) => dynmemory::DynamicMemoryAllocations::with_base(base),
+            None => dynmemory::DynamicMemoryAllocations::new(mem_sz),
+        }));
+    }
+    if enabled.system {
+        features.push(Box::new(system::SystemControl::new()));
+    }
+    if enabled.semihosting {
+        let root = match &config.semihosting.root {
+            Some(directory) => semihosting::SemihostingRoot::Directory(PathBuf::from(directory)),
+            None => semihosting::SemihostingRoot::Disc(disc.to_path_buf(), disc_layers(config)),
+        };
+        features.push(Box::new(semihosting::Semihosting::new(root, config.semihosting.cmdline.clone())));
+    }
+    #[cfg(feature = "gpu-feature")] {
+        if enabled.gpu {
+            features.push(gpu::create_feature(&config.video, &config.keys));
+        }
+    }
+    features
+}
+
+/// A console with a disc in it
+///
+/// Owns the CPU, the features and the syscall dispatcher. Front-ends drive it one frame at a
+/// time with [Machine::run_frame], or with [Machine::run] and [Machine::end_frame] when they
+/// need to look at the CPU in between (debuggers, crash reports). Debugging tools install
+/// their hooks through [Machine::emulator].
+pub struct Machine {
+    unicorn: Unicorn,
+    features: Vec<Box<dyn EmulatorFeature>>,
+    syscalls: SyscallDispatcher,
+    symbols: Rc<SymbolTable>,
+    mem_sz: u64,
+    entry: u64,
+    stack: Range<u64>,
+    time_slice: u64,
+    /// Frames left waiting on the drive, see [FrameRequests::stall_frames]
+    stalled_frames: u64,
+    /// Set by [Machine::run] when the dispatcher trapped, see [Machine::take_trap_reason]
+    trap_reason: Option<String>,
+    boot_state: SaveState,
+}
+
+impl Machine {
+    /// Boots `main.elf` from the disc image at `path`, or from the directory at `path`, with the
+    /// overlays and patches of `config` over it
+    ///
+    /// `path` is the boot disc, the executable is never loaded from the other discs of
+    /// `config`.
+    pub fn load_disc(path: &Path, config: MachineConfig) -> Result<Machine, EmulatorError> {
+        config.validate()?;
+        let mut unicorn = emulator::create_emulator();
+        let (features, syscalls, symbols, mem_sz, entry, stack, boot_state) = {
+            let mut emu = unicorn.borrow();
+            let (mem_sz, entry, symbols) = {
+                let drive = Drive::with_layers(path, &disc_layers(&config))?;
+                emulator::load_executable(&mut emu, &drive)?
+            };
+            let stack = emulator::map_stack(&mut emu, config.memory.stack_top, config.memory.stack_size)?;
+
+            let mut features = create_features(&config, path, mem_sz);
+            let mut syscalls = SyscallDispatcher::new(config.unknown_syscall);
+            emulator::initialize_all_features(&mut emu, &mut features, &mut syscalls)?;
+
+            emu.reg_write(RegisterARM::SP as i32, stack.end)?;
+            emu.reg_write(RegisterARM::PC as i32, entry)?;
+            let boot_state = SaveState::capture(&mut emu, &mut features)?;
+            (features, syscalls, symbols, mem_sz, entry, stack, boot_state)
+        };
+
+        Ok(Machine {
+            unicorn,
+            features,
+            syscalls,
+            symbols: Rc::new(symbols),
+            mem_sz,
+            entry,
+            stack,
+            time_slice: config.time_slice,
+            stalled_frames: 0,
+            trap_reason: None,
+            boot_state,
+        })
+    }
+
+    /// Direct access to the CPU, for hooks and debugging tools
+    pub fn emulator(&mut self) -> UnicornHandle {
+        self.unicorn.borrow()
+    }
+
+    pub fn features(&mut self) -> &mut Vec<Box<dyn EmulatorFeature>> {
+        &mut self.features
+    }
+
+    pub fn symbols(&self) -> Rc<SymbolTable> {
+        self.symbols.clone()
+    }
+
+    /// Syscall ranges registered by the features and the feature owning each of them
+    pub fn syscall_ranges(&self) -> Vec<(Range<u32>, String)> {
+        self.syscalls.ranges()
+    }
+
+    /// Trap instructions registered by the features and the feature owning each of them
+    pub fn syscall_traps(&self) -> Vec<(TrapInstruction, String)> {
+        self.syscalls.traps()
+    }
+
+    /// Entry point of the executable
+    pub fn entry(&self) -> u64 {
+        self.entry
+    }
+
+    /// Memory mapped for the stack, the guard page below it excluded
+    pub fn stack(&self) -> Range<u64> {
+        self.stack.clone()
+    }
+
+    /// End of the highest executable segment, execution stops if it gets there
+    pub fn executable_end(&self) -> u64 {
+        self.mem_sz
+    }
+
+    /// Runs the guest until it presents a frame, exits, crashes or runs out of time slice, or
+    /// until `count` instructions have executed (0 for no limit)
+    ///
+    /// Errors are guest crashes. Unknown syscalls and instructions trapped by the dispatcher are
+    /// reported as [uc_error::EXCEPTION].
+    ///
+    /// While the guest waits on the drive (with accurate drive timing), running without a
+    /// `count` executes nothing and only lets a frame go by.
+    pub fn run(&mut self, count: usize) -> Result<(), uc_error> {
+        if count == 0 && self.stalled_frames > 0 {
+            self.stalled_frames -= 1;
+            return Ok(());
+        }
+        let mut emu = self.unicorn.borrow();
+        let pc = emu.reg_read(RegisterARM::PC as i32)?;
+        let result = emu.emu_start(pc, self.mem_sz, self.time_slice * 1000, count);
+        if let Some(reason) = self.syscalls.take_trap() {
+            self.trap_reason = Some(reason);
+            return Err(uc_error::EXCEPTION);
+        }
+        result
+    }
+
+    /// Why the last [Machine::run] stopped with [uc_error::EXCEPTION], when it was an unknown
+    /// syscall or a trapped instruction
+    pub fn take_trap_reason(&mut self) -> Option<String> {
+        self.trap_reason.take()
+    }
+
+    /// Executes a single instruction
+    pub fn step(&mut self) -> Result<(), uc_error> {
+        self.run(1)
+    }
+
+    /// Runs `on_frame` on every feature, presenting the frame on the GPU
+    pub fn end_frame(&mut self) -> Result<FrameRequests, EmulatorError> {
+        let mut emu = self.unicorn.borrow();
+        let requests = emulator::frame_all_features(&mut emu, &mut self.features)?;
+        self.stalled_frames += requests.stall_frames;
+        Ok(requests)
+    }
+
+    /// Runs the guest up to its next frame and presents it
+    pub fn run_frame(&mut self) -> Result<FrameRequests, EmulatorError> {
+        self.run(0)?;
+        self.end_frame()
+    }
+
+    pub fn read_memory(&mut self, address: u64, size: usize) -> Result<Vec<u8>, EmulatorError> {
+        Ok(self.emulator().mem_read_as_vec(address, size)?)
+    }
+
+    /// The drive, for swapping discs. `None` if the drive feature is disabled
+    pub fn drive(&mut self) -> Option<&mut EmulatorDrive> {
+        self.features.iter_mut().find_map(|feat| feat.as_any().downcast_mut::<EmulatorDrive>())
+    }
+
+    /// The last rendered frame. `None` when headless or when the backend renders on the GPU
+    pub fn framebuffer(&mut self) -> Option<Framebuffer> {
+        self.features.iter_mut()
+            .find_map(|feat| feat.as_any().downcast_mut::<GPUFeature>().map(|gpu| gpu.framebuffer()))
+            .flatten()
+    }
+
+    pub fn save_state(&mut self) -> Result<SaveState, EmulatorError> {
+        let mut emu = self.unicorn.borrow();
+        SaveState::capture(&mut emu, &mut self.features)
+    }
+
+    pub fn load_state(&mut self, state: &SaveState) -> Result<(), EmulatorError> {
+        let mut emu = self.unicorn.borrow();
+        self.stalled_frames = 0;
+        state.restore(&mut emu, &mut self.features)
+    }
+
+    /// Soft reset: every feature is reset and memory and registers go back to how they were
+    /// right after booting
+    pub fn reset(&mut self) -> Result<(), EmulatorError> {
+        let mut emu = self.unicorn.borrow();
+        emulator::reset_all_features(&mut emu, &mut self.features)?;
+        self.stalled_frames = 0;
+        self.boot_state.restore_machine(&mut emu)
+    }
+
+    /// Stops every feature and the syscall dispatcher
+    pub fn shutdown(mut self) -> Result<(), EmulatorError> {
+        let mut emu = self.unicorn.borrow();
+        emulator::stop_all_features(&mut emu, &mut self.features)?;
+        self.syscalls.stop(&mut emu)
+    }
+}
@@ -1,102 +1,223 @@
-//! ARMChine_rs is a fantasy 5gen-ish console based on an ARM946 CPU. The emulator
-//! (which is the reference implementation... for now 😊 ) is developed in a
-//! [Modular manner](features::EmulatorFeature).
-//!
-//! There is an [Optical disk-like Filesystem](filesystem::EmulatorDrive),
-//! a [3D Rasterizer](gpu::feature::GPUFeature) with multiple backends,
-//! and of course [Dynamic memory](dynmemory::DynamicMemoryAllocations)!
-//!
-//! All of these are subject to change over the course of the initial development. Have fun!
+//! Command line front-end for [armchine_rs], see `--help` for the options.
 
 
 #![allow(unused_imports)]
 
-use strum::IntoEnumIterator;
-use strum_macros::EnumIter;
-
-use std::borrow::{Borrow, BorrowMut};
-use std::cmp::max;
-use std::collections::HashMap;
-use std::fmt::format;
-use unicorn::{RegisterARM, Unicorn, UnicornHandle};
-use unicorn::unicorn_const::Arch::ARM;
-use unicorn::unicorn_const::{HookType, Mode, Permission, uc_error};
-use xmas_elf::{ElfFile, header, sections};
+use std::fmt::Display;
 use std::fs;
 use std::io::Write;
-use std::ops::DerefMut;
-use std::ptr::{null, null_mut};
-use capstone::arch::arm::ArchMode;
-use capstone::arch::BuildsCapstone;
-use capstone::Capstone;
-use libc::{c_void, link, size_t};
-use unicorn::ffi::uc_hook;
-use xmas_elf::symbol_table::{Entry, Entry32};
-use configuration::Arguments;
-use features::EmulatorFeature;
+use std::path::Path;
 use clap::Parser;
-use crate::filesystem::Drive;
-use crate::gpu::feature::GPUFeature;
-
-mod emulator;
-mod filesystem;
-mod features;
-mod console;
-mod dynmemory;
-mod gpu;
-mod input;
-mod configuration;
-
-fn main() {
-
-    let args: Arguments = Arguments::parse();
+use armchine_rs::{crash, debugger, emulator, gdb, profiler, trace, Machine};
+use armchine_rs::error::EmulatorError;
+use armchine_rs::features::FrameRequests;
+use armchine_rs::savestate::SaveState;
+use configuration::Arguments;
 
-    let mut unicorn = emulator::create_emulator();
-    let mut unicorn_handle: UnicornHandle = Unicorn::borrow(
-        &mut unicorn);
+mod configuration;
 
+/// Reports an error that keeps the emulator from running at all and exits
+fn fail(error: impl Display) -> ! {
+    eprintln!("{}", error);
+    std::process::exit(1);
+}
 
-    let (mem_sz, main_idx) = {
-        let drive = Drive::new(args.iso.as_ref());
+/// Handles the save state, load state, reset and swap disc hotkeys
+///
+/// Saving keeps the snapshot in `quick_state` and writes it to `state_path`, loading restores
+/// `quick_state` or, if nothing was saved this session, the snapshot in `state_path`.
+fn handle_requests(machine: &mut Machine, requests: &FrameRequests, quick_state: &mut Option<SaveState>, state_path: &Path) {
+    if requests.save_state {
+        match machine.save_state() {
+            Ok(state) => {
+                if let Err(err) = state.write_to_file(state_path) {
+                    println!("couldn't write save state: {}", err);
+                }
+                *quick_state = Some(state);
+            }
+            Err(err) => println!("couldn't save state: {}", err),
+        }
+    }
+    if requests.load_state {
+        if quick_state.is_none() {
+            *quick_state = SaveState::read_from_file(state_path).ok();
+        }
+        if let Some(state) = quick_state {
+            if let Err(err) = machine.load_state(state) {
+                println!("couldn't load state: {}", err);
+            }
+        }
+    }
+    if requests.reset {
+        if let Err(err) = machine.reset() {
+            println!("couldn't reset: {}", err);
+        }
+    }
+    if requests.swap_disc {
+        if let Some(drive) = machine.drive() {
+            match drive.swap_disc() {
+                Ok(Some(disc)) => println!("inserted disc {} of {}", disc + 1, drive.disc_count()),
+                Ok(None) => println!("drive tray open"),
+                Err(err) => println!("couldn't insert the disc: {}", err),
+            }
+        }
+    }
+}
 
-        emulator::load_executable(&mut unicorn_handle, &drive).unwrap()
-    };
-    let mut features = configuration::get_features(&args, mem_sz);
+fn main() {
 
-    emulator::initialize_all_features(&mut unicorn_handle, &mut features);
+    let args: Arguments = Arguments::parse();
 
+    let config = configuration::machine_config(&args).unwrap_or_else(|e| fail(e));
+    let mut machine = Machine::load_disc(args.iso.as_ref(), config).unwrap_or_else(|e| fail(e));
+    let symbols = machine.symbols();
 
-    unicorn_handle.add_intr_hook(|mut _emu, _syscall| _emu.emu_stop().unwrap()).unwrap();
+    let executed = args.max_instructions
+        .map(|max_instructions| emulator::add_instruction_budget(&mut machine.emulator(), max_instructions));
 
+    let mut exit_code = 0;
     {
-        unicorn_handle.reg_write(RegisterARM::SP as i32, 0x10000).unwrap();
-        unicorn_handle.reg_write(RegisterARM::PC as i32, main_idx).unwrap();
         let mut must_loop = true;
+        let mut frames = 0u64;
+
+        let state_path = args.save_state.clone().unwrap_or_else(|| format!("{}.state", args.iso));
+        let mut quick_state: Option<SaveState> = None;
+        if let Some(path) = &args.load_state {
+            let state = SaveState::read_from_file(path.as_ref()).unwrap_or_else(|e| fail(e));
+            machine.load_state(&state).unwrap_or_else(|e| fail(e));
+        }
+
+        let mut gdb = args.gdb.map(|port| gdb::GdbStub::listen(&mut machine.emulator(), port).unwrap_or_else(|e| fail(e)));
+        let mut debugger = if args.debugger {
+            Some(debugger::Debugger::new(&mut machine.emulator(), symbols.clone()).unwrap_or_else(|e| fail(EmulatorError::from(e))))
+        } else {
+            None
+        };
+        let mut tracer = args.trace.as_ref().map(|path| {
+            let filter = trace::filter_ranges(&args.trace_range, &args.trace_function, &symbols).unwrap_or_else(|e| fail(e));
+            trace::Tracer::install(&mut machine.emulator(), path.as_ref(), args.trace_blocks, filter, symbols.clone()).unwrap_or_else(|e| fail(e))
+        });
+        let syscall_ranges = machine.syscall_ranges();
+        let syscall_traps = machine.syscall_traps();
+        let mut profiler = args.profile.as_ref()
+            .map(|_| profiler::Profiler::install(&mut machine.emulator(), symbols.clone(), syscall_ranges, syscall_traps).unwrap_or_else(|e| fail(e)));
+        let mut faults = crash::FaultRecorder::install(&mut machine.emulator()).unwrap_or_else(|e| fail(EmulatorError::from(e)));
 
         while must_loop {
-            let pc = unicorn_handle.reg_read(RegisterARM::PC as i32).unwrap();
+            if let Some(stub) = &mut gdb {
+                if !stub.serve(&mut machine.emulator()) {
+                    break;
+                }
+            }
+            if let Some(debugger) = &mut debugger {
+                if !debugger.prompt(&mut machine.emulator()) {
+                    break;
+                }
+            }
+            let count = gdb.as_ref().map(|stub| stub.instruction_count())
+                .or_else(|| debugger.as_ref().map(|debugger| debugger.instruction_count()))
+                .unwrap_or(0);
 
+            if let Some(profiler) = &mut profiler {
+                profiler.resume();
+            }
+            // forget faults the debuggers already reported
+            faults.take();
             let t1 = std::time::Instant::now();
-            let e = unicorn_handle.emu_start(pc, mem_sz, 0, 0);
+            let e = machine.run(count);
             let t2 = std::time::Instant::now();
+            if let Some(reason) = machine.take_trap_reason() {
+                println!("\n{}", reason);
+            }
+            if let Some(profiler) = &mut profiler {
+                profiler.suspend();
+            }
             if args.debug {
-                emulator::print_disassembly(&mut unicorn_handle, mem_sz, main_idx, e);
+                let (mem_sz, entry) = (machine.executable_end(), machine.entry());
+                emulator::print_disassembly(&mut machine.emulator(), mem_sz, entry, e);
+            }
+            if let Some(stub) = &mut gdb {
+                // crashes and breakpoints halt the machine and are reported to GDB instead
+                if stub.after_run(e) {
+                    continue;
+                }
             }
-            if e.is_err() {
+            if let Some(debugger) = &mut debugger {
+                if debugger.after_run(&mut machine.emulator(), e) {
+                    continue;
+                }
+            }
+            if let Err(error) = e {
+                let stack = machine.stack();
+                let report = crash::crash_report(&machine.emulator(), error, faults.take(), &stack, &symbols);
+                println!("\n{}", report);
+                if let Some(path) = &args.crash_report {
+                    if let Err(error) = fs::write(path, report) {
+                        eprintln!("couldn't write the crash report: {}", error);
+                    }
+                }
+                exit_code = 1;
                 break;
             }
 
             let dt = t2.duration_since(t1).as_millis();
             print!("Execution time: {}; ", dt);
 
-            #[cfg(feature = "gpu-feature")] {
-                emulator::video_update(&mut features, &mut must_loop)
+            let t3 = std::time::Instant::now();
+            let requests = match machine.end_frame() {
+                Ok(requests) => requests,
+                Err(error) => {
+                    eprintln!("\n{}", error);
+                    exit_code = 1;
+                    break;
+                }
+            };
+            print!("Rendering and update time: {};", t3.elapsed().as_millis());
+            if let Some(code) = requests.exit_code {
+                exit_code = code;
+                break;
             }
+            must_loop = !requests.quit;
+            handle_requests(&mut machine, &requests, &mut quick_state, state_path.as_ref());
             print!("\r");
             std::io::stdout().flush().unwrap();
+
+            if requests.presented {
+                frames += 1;
+            }
+            if args.max_frames.map_or(false, |max_frames| frames >= max_frames) {
+                break;
+            }
+            if let (Some(executed), Some(max_instructions)) = (&executed, args.max_instructions) {
+                if executed.get() >= max_instructions {
+                    break;
+                }
+            }
+        }
+
+        if let Some(stub) = &mut gdb {
+            stub.stop(&mut machine.emulator());
+        }
+        if let Some(tracer) = &mut tracer {
+            tracer.stop(&mut machine.emulator());
+        }
+        if let Err(error) = faults.stop(&mut machine.emulator()) {
+            eprintln!("{}", EmulatorError::from(error));
+            exit_code = 1;
+        }
+        if let (Some(profiler), Some(path)) = (&mut profiler, &args.profile) {
+            if let Err(error) = profiler.stop(&mut machine.emulator(), path.as_ref()) {
+                eprintln!("couldn't write the profile: {}", error);
+                exit_code = 1;
+            }
         }
     }
 
 
-    emulator::stop_all_features(&mut unicorn_handle, &mut features)
+    if let Err(error) = machine.shutdown() {
+        eprintln!("{}", error);
+        exit_code = 1;
+    }
+
+    std::process::exit(exit_code);
 }
@@ -0,0 +1,59 @@
+use std::ops::Range;
+use unicorn::{RegisterARM, UnicornHandle};
+use crate::syscalls::{SyscallError, SyscallResult};
+
+/// Syscalls of the newlib personality
+///
+/// They back the libgloss functions newlib (and anything else expecting a POSIX-ish C runtime)
+/// needs, see `code/cpp/stdlib/newlib.cpp`. Each one is registered by the feature it is layered
+/// on: console writes by [crate::console::ConsoleIO], files by [crate::filesystem::EmulatorDrive],
+/// `sbrk` by [crate::dynmemory::DynamicMemoryAllocations] and `exit` by
+/// [crate::system::SystemControl]. Unlike the native syscalls, failures return a negative errno.
+pub const EXIT: u32 = 0x200;
+pub const OPEN: u32 = 0x201;
+pub const CLOSE: u32 = 0x202;
+pub const READ: u32 = 0x203;
+pub const WRITE: u32 = 0x204;
+pub const LSEEK: u32 = 0x205;
+pub const FSIZE: u32 = 0x206;
+pub const SBRK: u32 = 0x207;
+
+/// Descriptors 0 through 2 are the console, files opened on the drive start here
+pub const FIRST_FILE: u32 = 3;
+
+/// newlib errno values
+pub const ENOENT: i32 = 2;
+pub const EIO: i32 = 5;
+pub const EBADF: i32 = 9;
+pub const ENOMEM: i32 = 12;
+pub const EACCES: i32 = 13;
+pub const EFAULT: i32 = 14;
+pub const EINVAL: i32 = 22;
+pub const ESPIPE: i32 = 29;
+pub const EROFS: i32 = 30;
+pub const ENOSYS: i32 = 88;
+
+/// The range a feature registers for a single personality syscall
+pub fn syscall(number: u32) -> Range<u32> {
+    number..number + 1
+}
+
+/// The errno matching a native syscall error
+pub fn errno(error: SyscallError) -> i32 {
+    match error {
+        SyscallError::Unknown => ENOSYS,
+        SyscallError::InvalidArgument | SyscallError::InvalidString => EINVAL,
+        SyscallError::BadAddress => EFAULT,
+        SyscallError::NotFound => ENOENT,
+        SyscallError::Io | SyscallError::NoDisc | SyscallError::DiscChanged => EIO,
+        SyscallError::OutOfMemory => ENOMEM,
+        SyscallError::BadHandle => EBADF,
+    }
+}
+
+/// Writes `result` to R0, errors as a negative errno
+pub fn set_result(em: &mut UnicornHandle, result: Result<u32, i32>) -> SyscallResult {
+    let value = result.unwrap_or_else(|errno| -errno as u32);
+    em.reg_write(RegisterARM::R0 as i32, value as u64)?;
+    Ok(())
+}
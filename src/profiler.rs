@@ -0,0 +1,221 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use unicorn::{RegisterARM, UnicornHandle};
+use unicorn::ffi::uc_hook;
+use crate::emulator::{is_breakpoint, is_call, is_return, is_syscall};
+use crate::symbols::SymbolTable;
+use crate::syscalls::{decode_trap_at, TrapInstruction, EXCP_BKPT, EXCP_SWI};
+
+#[derive(Copy, Clone)]
+struct BlockInfo {
+    function: u32,
+    last_address: u64,
+    last_word: u32,
+}
+
+struct ProfileState {
+    symbols: Rc<SymbolTable>,
+    /// Syscall ranges registered with the dispatcher and the feature owning each of them
+    syscall_owners: Vec<(Range<u32>, String)>,
+    /// Trap instructions registered with the dispatcher and the feature owning each of them
+    trap_owners: Vec<(TrapInstruction, String)>,
+    names: Vec<String>,
+    name_ids: HashMap<String, u32>,
+    blocks: HashMap<u64, BlockInfo>,
+    /// Callers of the current function, with the address each one returns to
+    stack: Vec<(u32, u64)>,
+    previous: Option<BlockInfo>,
+    key: Vec<u32>,
+    block_counts: HashMap<Vec<u32>, u64>,
+    syscall_time: HashMap<Vec<u32>, Duration>,
+    syscall_start: Option<(Instant, Duration)>,
+    host_time: Duration,
+    suspended_at: Option<Instant>,
+}
+
+impl ProfileState {
+    fn intern(&mut self, name: String) -> u32 {
+        if let Some(id) = self.name_ids.get(&name) {
+            return *id;
+        }
+        let id = self.names.len() as u32;
+        self.names.push(name.clone());
+        self.name_ids.insert(name, id);
+        id
+    }
+
+    fn syscall_owner(&self, syscall: u32) -> &str {
+        self.syscall_owners.iter()
+            .find(|(range, _)| range.contains(&syscall))
+            .map_or("unknown syscall", |(_, owner)| owner.as_str())
+    }
+
+    /// Feature that handled the SVC or BKPT ending `block`, picked like the dispatcher does: by
+    /// the registered immediate first, by R7 for any other SVC
+    fn trap_owner(&self, emu: &UnicornHandle, block: &BlockInfo) -> &str {
+        let intno = if is_syscall(block.last_word) { EXCP_SWI } else { EXCP_BKPT };
+        let instruction = decode_trap_at(emu, intno, block.last_address, false);
+        if let Some((_, owner)) = self.trap_owners.iter().find(|(trap, _)| Some(*trap) == instruction) {
+            return owner.as_str();
+        }
+        match instruction {
+            Some(TrapInstruction::Svc(_)) => {
+                let syscall = emu.reg_read(RegisterARM::R7 as i32).unwrap_or(0) as u32;
+                self.syscall_owner(syscall)
+            }
+            _ => "unhandled trap",
+        }
+    }
+
+    fn block_info(&mut self, emu: &UnicornHandle, address: u64, size: u32) -> BlockInfo {
+        if let Some(info) = self.blocks.get(&address) {
+            return *info;
+        }
+        let name = match self.symbols.lookup(address) {
+            Some((symbol, _)) => symbol.name.clone(),
+            None => String::from("[unknown]"),
+        };
+        let last_address = address + (size.max(4) as u64) - 4;
+        let mut word = [0u8; 4];
+        emu.mem_read(last_address, &mut word).unwrap_or(());
+        let info = BlockInfo {
+            function: self.intern(name),
+            last_address,
+            last_word: u32::from_le_bytes(word),
+        };
+        self.blocks.insert(address, info);
+        info
+    }
+
+    fn set_key(&mut self, leaf: &[u32]) {
+        self.key.clear();
+        self.key.extend(self.stack.iter().map(|(function, _)| *function));
+        self.key.extend_from_slice(leaf);
+    }
+
+    fn on_block(&mut self, emu: &UnicornHandle, address: u64, size: u32) {
+        if let Some(previous) = self.previous.take() {
+            let taken = address != previous.last_address + 4;
+            if taken && is_call(previous.last_word) {
+                self.stack.push((previous.function, previous.last_address + 4));
+            } else if taken && is_return(previous.last_word) {
+                // unwind to the caller we returned to, tail calls may have skipped some frames
+                match self.stack.iter().rposition(|(_, return_address)| *return_address == address) {
+                    Some(depth) => self.stack.truncate(depth),
+                    None => { self.stack.pop(); }
+                }
+            }
+
+            if let Some((start, host_time)) = self.syscall_start.take() {
+                let elapsed = start.elapsed().saturating_sub(self.host_time - host_time);
+                let owner = format!("[{}]", self.trap_owner(emu, &previous));
+                let owner = self.intern(owner);
+                self.set_key(&[previous.function, owner]);
+                match self.syscall_time.get_mut(self.key.as_slice()) {
+                    Some(time) => *time += elapsed,
+                    None => { self.syscall_time.insert(self.key.clone(), elapsed); }
+                }
+            }
+        }
+
+        let info = self.block_info(emu, address, size);
+        self.set_key(&[info.function]);
+        match self.block_counts.get_mut(self.key.as_slice()) {
+            Some(count) => *count += 1,
+            None => { self.block_counts.insert(self.key.clone(), 1); }
+        }
+
+        if is_syscall(info.last_word) || is_breakpoint(info.last_word) {
+            self.syscall_start = Some((Instant::now(), self.host_time));
+        }
+        self.previous = Some(info);
+    }
+
+    fn folded_name(&self, stack: &[u32]) -> String {
+        let names: Vec<&str> = stack.iter().map(|id| self.names[*id as usize].as_str()).collect();
+        names.join(";")
+    }
+}
+
+/// Guest-side profiler
+///
+/// Counts executed basic blocks per function using the ELF symbols, following the call stack
+/// through BL/BLX calls and BX LR / POP {PC} returns. Time spent handling syscalls is charged
+/// to the feature owning the syscall (or the SVC or BKPT immediate, for semihosting), on top of
+/// the stack that called it.
+///
+/// [Profiler::stop] writes block counts in folded-stack format (`main;draw;mul 1234`, ready for
+/// `flamegraph.pl` or inferno) and syscall time in microseconds to a second file with a
+/// `.syscalls` suffix, in the same format.
+pub struct Profiler {
+    hook: uc_hook,
+    state: Rc<RefCell<ProfileState>>,
+}
+
+impl Profiler {
+    pub fn install(emu: &mut UnicornHandle, symbols: Rc<SymbolTable>, syscall_owners: Vec<(Range<u32>, String)>,
+                   trap_owners: Vec<(TrapInstruction, String)>) -> Result<Profiler, String> {
+        let state = Rc::new(RefCell::new(ProfileState {
+            symbols,
+            syscall_owners,
+            trap_owners,
+            names: Vec::new(),
+            name_ids: HashMap::new(),
+            blocks: HashMap::new(),
+            stack: Vec::new(),
+            previous: None,
+            key: Vec::new(),
+            block_counts: HashMap::new(),
+            syscall_time: HashMap::new(),
+            syscall_start: None,
+            host_time: Duration::ZERO,
+            suspended_at: None,
+        }));
+
+        let hook_state = state.clone();
+        match emu.add_block_hook(move |emu, address, size| {
+            hook_state.borrow_mut().on_block(&emu, address, size);
+        }) {
+            Ok(hook) => Ok(Profiler { hook, state }),
+            Err(err) => Err(format!("{:?}", err)),
+        }
+    }
+
+    /// Called when the main loop gets control back, so host-side work (rendering, save states...)
+    /// isn't charged to the syscall that stopped the emulator
+    pub fn suspend(&mut self) {
+        self.state.borrow_mut().suspended_at = Some(Instant::now());
+    }
+
+    /// Called right before the main loop resumes the emulator
+    pub fn resume(&mut self) {
+        let mut state = self.state.borrow_mut();
+        if let Some(suspended_at) = state.suspended_at.take() {
+            state.host_time += suspended_at.elapsed();
+        }
+    }
+
+    pub fn stop(&mut self, emu: &mut UnicornHandle, path: &Path) -> Result<(), String> {
+        emu.remove_hook(self.hook).map_err(|e| format!("{:?}", e))?;
+        let state = self.state.borrow();
+
+        let mut output = BufWriter::new(File::create(path).map_err(|e| format!("{}", e))?);
+        for (stack, count) in &state.block_counts {
+            writeln!(output, "{} {}", state.folded_name(stack), count).map_err(|e| format!("{}", e))?;
+        }
+
+        let mut syscall_path = path.as_os_str().to_owned();
+        syscall_path.push(".syscalls");
+        let mut output = BufWriter::new(File::create(syscall_path).map_err(|e| format!("{}", e))?);
+        for (stack, time) in &state.syscall_time {
+            writeln!(output, "{} {}", state.folded_name(stack), time.as_micros()).map_err(|e| format!("{}", e))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,416 @@
+use std::convert::TryInto;
+use std::fs;
+use std::path::Path;
+use libc::size_t;
+use unicorn::{Context, RegisterARM, UnicornHandle};
+use unicorn::unicorn_const::Permission;
+use crate::error::EmulatorError;
+use crate::features::EmulatorFeature;
+
+const MAGIC: &[u8; 8] = b"ARMSTATE";
+const VERSION: u32 = 2;
+
+/// Registers of the current mode stored in a snapshot, CPSR first so the banked registers
+/// restored after it land in the right bank. The VFP registers follow
+const SAVED_REGISTERS: [RegisterARM; 52] = [
+    RegisterARM::CPSR,
+    RegisterARM::SPSR,
+    RegisterARM::R0,
+    RegisterARM::R1,
+    RegisterARM::R2,
+    RegisterARM::R3,
+    RegisterARM::R4,
+    RegisterARM::R5,
+    RegisterARM::R6,
+    RegisterARM::R7,
+    RegisterARM::R8,
+    RegisterARM::R9,
+    RegisterARM::R10,
+    RegisterARM::R11,
+    RegisterARM::R12,
+    RegisterARM::SP,
+    RegisterARM::LR,
+    RegisterARM::PC,
+    RegisterARM::FPEXC,
+    RegisterARM::FPSCR,
+    RegisterARM::D0,
+    RegisterARM::D1,
+    RegisterARM::D2,
+    RegisterARM::D3,
+    RegisterARM::D4,
+    RegisterARM::D5,
+    RegisterARM::D6,
+    RegisterARM::D7,
+    RegisterARM::D8,
+    RegisterARM::D9,
+    RegisterARM::D10,
+    RegisterARM::D11,
+    RegisterARM::D12,
+    RegisterARM::D13,
+    RegisterARM::D14,
+    RegisterARM::D15,
+    RegisterARM::D16,
+    RegisterARM::D17,
+    RegisterARM::D18,
+    RegisterARM::D19,
+    RegisterARM::D20,
+    RegisterARM::D21,
+    RegisterARM::D22,
+    RegisterARM::D23,
+    RegisterARM::D24,
+    RegisterARM::D25,
+    RegisterARM::D26,
+    RegisterARM::D27,
+    RegisterARM::D28,
+    RegisterARM::D29,
+    RegisterARM::D30,
+    RegisterARM::D31,
+];
+
+/// Processor modes with their own register bank: system (sharing user's), FIQ, IRQ,
+/// supervisor, abort and undefined
+const BANKED_MODES: [u64; 6] = [0x1F, 0x11, 0x12, 0x13, 0x17, 0x1B];
+
+/// Registers stored for each of [BANKED_MODES]. System mode has no SPSR, its slot is unused
+const BANKED_REGISTERS: [RegisterARM; 8] = [
+    RegisterARM::R8,
+    RegisterARM::R9,
+    RegisterARM::R10,
+    RegisterARM::R11,
+    RegisterARM::R12,
+    RegisterARM::SP,
+    RegisterARM::LR,
+    RegisterARM::SPSR,
+];
+
+const REGISTER_COUNT: usize = SAVED_REGISTERS.len() + BANKED_MODES.len() * BANKED_REGISTERS.len();
+
+const PAGE_SIZE: u64 = 0x1000;
+
+/// Reads the registers of every bank, switching modes through CPSR, then the ones of the
+/// current mode
+fn read_registers(emu: &mut UnicornHandle) -> Result<Vec<u64>, EmulatorError> {
+    let cpsr = emu.reg_read(RegisterARM::CPSR as i32)?;
+    let mut registers = Vec::with_capacity(REGISTER_COUNT);
+    for mode in BANKED_MODES {
+        emu.reg_write(RegisterARM::CPSR as i32, (cpsr & !0x1F) | mode)?;
+        for register in BANKED_REGISTERS {
+            registers.push(emu.reg_read(register as i32)?);
+        }
+    }
+    emu.reg_write(RegisterARM::CPSR as i32, cpsr)?;
+    let mut current = Vec::with_capacity(SAVED_REGISTERS.len());
+    for register in SAVED_REGISTERS {
+        current.push(emu.reg_read(register as i32)?);
+    }
+    current.extend(registers);
+    Ok(current)
+}
+
+/// Writes back what [read_registers] read
+fn write_registers(emu: &mut UnicornHandle, registers: &[u64]) -> Result<(), EmulatorError> {
+    let (current, banked) = registers.split_at(SAVED_REGISTERS.len());
+    let cpsr = current[0];
+    for (mode, bank) in BANKED_MODES.iter().zip(banked.chunks(BANKED_REGISTERS.len())) {
+        emu.reg_write(RegisterARM::CPSR as i32, (cpsr & !0x1F) | mode)?;
+        for (register, value) in BANKED_REGISTERS.iter().zip(bank) {
+            if *mode == 0x1F && *register == RegisterARM::SPSR {
+                continue;
+            }
+            emu.reg_write(*register as i32, *value)?;
+        }
+    }
+    for (register, value) in SAVED_REGISTERS.iter().zip(current) {
+        emu.reg_write(*register as i32, *value)?;
+    }
+    Ok(())
+}
+
+/// Little-endian byte buffer used to serialise snapshots, features use it for their own state too
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        StateWriter { data: Vec::new() }
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// Writes a length-prefixed byte string
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.write_u64(bytes.len() as u64);
+        self.data.extend_from_slice(bytes);
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+/// Reads back what a [StateWriter] wrote
+pub struct StateReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> StateReader<'a> {
+        StateReader { data }
+    }
+
+    fn take(&mut self, count: usize) -> Result<&'a [u8], EmulatorError> {
+        if self.data.len() < count {
+            return Err(EmulatorError::InvalidState(String::from("truncated")));
+        }
+        let (head, tail) = self.data.split_at(count);
+        self.data = tail;
+        Ok(head)
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, EmulatorError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, EmulatorError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn read_bytes(&mut self) -> Result<&'a [u8], EmulatorError> {
+        let count = self.read_u64()? as usize;
+        self.take(count)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+}
+
+struct RegionState {
+    begin: u64,
+    end: u64,
+    perms: Permission,
+    data: Vec<u8>,
+}
+
+/// Snapshot of a running machine
+///
+/// Covers the CPU registers, every mapped memory region (with its permissions) and whatever
+/// each [EmulatorFeature] returns from `save_state`. Snapshots can be kept in memory or
+/// written to disk with [SaveState::to_bytes].
+///
+/// Snapshots kept in memory also hold the whole unicorn CPU context and restore through it.
+/// Contexts hold host pointers and can't be written to disk, so snapshots read back from a
+/// file restore the banked, VFP and current registers one by one instead.
+pub struct SaveState {
+    registers: Vec<u64>,
+    context: Option<Context>,
+    regions: Vec<RegionState>,
+    features: Vec<(String, Vec<u8>)>,
+}
+
+impl SaveState {
+    pub fn capture(emu: &mut UnicornHandle, features: &mut Vec<Box<dyn EmulatorFeature>>) -> Result<SaveState, EmulatorError> {
+        let mut state = Self::capture_machine(emu)?;
+        state.features = features.iter()
+            .map(|feat| (feat.name(), feat.save_state()))
+            .collect();
+        Ok(state)
+    }
+
+    /// Captures the memory and registers only
+    fn capture_machine(emu: &mut UnicornHandle) -> Result<SaveState, EmulatorError> {
+        let registers = read_registers(emu)?;
+        let context = Some(emu.context_init()?);
+
+        let mut regions = Vec::new();
+        for region in emu.mem_regions()? {
+            let data = emu.mem_read_as_vec(region.begin, (region.end - region.begin + 1) as usize)
+                ?;
+            regions.push(RegionState {
+                begin: region.begin,
+                end: region.end,
+                perms: region.perms,
+                data,
+            });
+        }
+
+        Ok(SaveState { registers, context, regions, features: Vec::new() })
+    }
+
+    /// Checks the snapshot has a state for every feature
+    fn check_features(&self, features: &[Box<dyn EmulatorFeature>]) -> Result<(), EmulatorError> {
+        for feat in features {
+            let name = feat.name();
+            if !self.features.iter().any(|(feat_name, _)| *feat_name == name) {
+                return Err(EmulatorError::InvalidState(format!("no state for {}", name)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the regions are page aligned and don't overlap, so mapping them can't fail
+    /// halfway for a reason known up front
+    fn check_regions(&self) -> Result<(), EmulatorError> {
+        let mut regions: Vec<&RegionState> = self.regions.iter().collect();
+        regions.sort_by_key(|region| region.begin);
+        let mut previous_end = None;
+        for region in regions {
+            let size = region.end.checked_sub(region.begin).and_then(|size| size.checked_add(1));
+            let aligned = region.begin % PAGE_SIZE == 0 && size.map_or(false, |size| size % PAGE_SIZE == 0);
+            let complete = size == Some(region.data.len() as u64);
+            if !aligned || !complete || previous_end.map_or(false, |end| region.begin <= end) {
+                return Err(EmulatorError::InvalidState(format!("bad region at {:#x}", region.begin)));
+            }
+            previous_end = Some(region.end);
+        }
+        if self.registers.len() != REGISTER_COUNT {
+            return Err(EmulatorError::InvalidState(format!("{} registers, expected {}", self.registers.len(), REGISTER_COUNT)));
+        }
+        Ok(())
+    }
+
+    /// Puts the machine back in the captured state
+    ///
+    /// All currently mapped memory is unmapped and replaced with the snapshot's regions before
+    /// the registers and the feature state are restored. The snapshot is checked first, and if
+    /// restoring still fails the machine is put back how it was before the call.
+    pub fn restore(&self, emu: &mut UnicornHandle, features: &mut Vec<Box<dyn EmulatorFeature>>) -> Result<(), EmulatorError> {
+        self.check_features(features)?;
+        self.check_regions()?;
+        let rollback = Self::capture(emu, features)?;
+        let result = self.apply(emu, features);
+        if result.is_err() {
+            // the error that matters is the first one
+            let _ = rollback.apply(emu, features);
+        }
+        result
+    }
+
+    /// Restores only the memory and registers, leaving the features alone
+    pub fn restore_machine(&self, emu: &mut UnicornHandle) -> Result<(), EmulatorError> {
+        self.check_regions()?;
+        let rollback = Self::capture_machine(emu)?;
+        let result = self.apply_machine(emu);
+        if result.is_err() {
+            let _ = rollback.apply_machine(emu);
+        }
+        result
+    }
+
+    fn apply(&self, emu: &mut UnicornHandle, features: &mut Vec<Box<dyn EmulatorFeature>>) -> Result<(), EmulatorError> {
+        self.apply_machine(emu)?;
+        for feat in &mut *features {
+            let name = feat.name();
+            if let Some((_, state)) = self.features.iter().find(|(feat_name, _)| *feat_name == name) {
+                feat.load_state(emu, state.as_slice())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn apply_machine(&self, emu: &mut UnicornHandle) -> Result<(), EmulatorError> {
+        for region in emu.mem_regions()? {
+            emu.mem_unmap(region.begin, (region.end - region.begin + 1) as size_t)
+                ?;
+        }
+        for region in &self.regions {
+            emu.mem_map(region.begin, (region.end - region.begin + 1) as size_t, region.perms)
+                ?;
+            emu.mem_write(region.begin, region.data.as_slice())
+                ?;
+        }
+
+        match &self.context {
+            Some(context) => emu.context_restore(context)?,
+            None => write_registers(emu, &self.registers)?,
+        }
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = StateWriter::new();
+        writer.write_bytes(MAGIC);
+        writer.write_u32(VERSION);
+
+        writer.write_u32(self.registers.len() as u32);
+        for value in &self.registers {
+            writer.write_u64(*value);
+        }
+
+        writer.write_u32(self.regions.len() as u32);
+        for region in &self.regions {
+            writer.write_u64(region.begin);
+            writer.write_u64(region.end);
+            writer.write_u32(region.perms.bits());
+            writer.write_bytes(region.data.as_slice());
+        }
+
+        writer.write_u32(self.features.len() as u32);
+        for (name, state) in &self.features {
+            writer.write_bytes(name.as_bytes());
+            writer.write_bytes(state.as_slice());
+        }
+        writer.into_bytes()
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<SaveState, EmulatorError> {
+        let mut reader = StateReader::new(data);
+        if reader.read_bytes()? != MAGIC {
+            return Err(EmulatorError::InvalidState(String::from("not a save state")));
+        }
+        let version = reader.read_u32()?;
+        if version != VERSION {
+            return Err(EmulatorError::InvalidState(format!("unsupported version {}", version)));
+        }
+
+        let register_count = reader.read_u32()? as usize;
+        if register_count != REGISTER_COUNT {
+            return Err(EmulatorError::InvalidState(format!("{} registers, expected {}", register_count, REGISTER_COUNT)));
+        }
+        let mut registers = Vec::new();
+        for _ in 0..register_count {
+            registers.push(reader.read_u64()?);
+        }
+
+        let region_count = reader.read_u32()?;
+        let mut regions = Vec::new();
+        for _ in 0..region_count {
+            let begin = reader.read_u64()?;
+            let end = reader.read_u64()?;
+            let perms = Permission::from_bits(reader.read_u32()?)
+                .ok_or_else(|| EmulatorError::InvalidState(String::from("invalid region permissions")))?;
+            let data = reader.read_bytes()?.to_vec();
+            if end < begin || data.len() as u64 != end - begin + 1 {
+                return Err(EmulatorError::InvalidState(format!("region at {:#x} has the wrong size", begin)));
+            }
+            regions.push(RegionState { begin, end, perms, data });
+        }
+
+        let feature_count = reader.read_u32()?;
+        let mut features = Vec::new();
+        for _ in 0..feature_count {
+            let name = String::from_utf8(reader.read_bytes()?.to_vec())
+                .map_err(|e| EmulatorError::InvalidState(format!("{}", e)))?;
+            let state = reader.read_bytes()?.to_vec();
+            features.push((name, state));
+        }
+
+        Ok(SaveState { registers, context: None, regions, features })
+    }
+
+    pub fn write_to_file(&self, path: &Path) -> Result<(), EmulatorError> {
+        Ok(fs::write(path, self.to_bytes())?)
+    }
+
+    pub fn read_from_file(path: &Path) -> Result<SaveState, EmulatorError> {
+        let data = fs::read(path)?;
+        Self::from_bytes(data.as_slice())
+    }
+}
@@ -0,0 +1,440 @@
+use std::any::Any;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::Instant;
+use unicorn::{RegisterARM, UnicornHandle};
+use crate::features::{EmulatorFeature, FrameRequests};
+use crate::error::EmulatorError;
+use crate::filesystem::{DiscLayers, Drive};
+use crate::newlib::{EACCES, EBADF, EFAULT, EINVAL, EIO, ENOENT, ENOSYS};
+use crate::syscalls::{read_guest_memory, SyscallDispatcher, SyscallResult, TrapInstruction};
+
+/// Operation numbers from the Arm semihosting specification, passed in R0
+const SYS_OPEN: u32 = 0x01;
+const SYS_CLOSE: u32 = 0x02;
+const SYS_WRITEC: u32 = 0x03;
+const SYS_WRITE0: u32 = 0x04;
+const SYS_WRITE: u32 = 0x05;
+const SYS_READ: u32 = 0x06;
+const SYS_ISTTY: u32 = 0x09;
+const SYS_SEEK: u32 = 0x0A;
+const SYS_FLEN: u32 = 0x0C;
+const SYS_CLOCK: u32 = 0x10;
+const SYS_ERRNO: u32 = 0x13;
+const SYS_GET_CMDLINE: u32 = 0x15;
+const SYS_HEAPINFO: u32 = 0x16;
+const SYS_EXIT: u32 = 0x18;
+
+/// SYS_EXIT reason of a program returning normally, any other reason is a failure
+const ADP_STOPPED_APPLICATION_EXIT: u64 = 0x20026;
+
+/// Instructions semihosting calls are made with: the ARM and Thumb SVCs, and the BKPT newer
+/// toolchains prefer
+const TRAPS: [TrapInstruction; 3] = [
+    TrapInstruction::Svc(0x123456),
+    TrapInstruction::Svc(0xAB),
+    TrapInstruction::Bkpt(0xAB),
+];
+
+/// Largest chunk SYS_READ copies at once, so a huge length doesn't allocate a huge buffer
+const READ_CHUNK: usize = 0x10000;
+
+/// Where SYS_OPEN finds files
+pub enum SemihostingRoot {
+    /// The disc image at this path with these layers over it, read-only
+    ///
+    /// It is opened on its own when the feature starts, apart from the drive feature's: files
+    /// are read from this disc even after the drive swapped it for another, and reads aren't
+    /// charged to the drive timing.
+    Disc(PathBuf, DiscLayers),
+    /// A host directory, read-write. Paths can't leave it
+    Directory(PathBuf),
+}
+
+enum Handle {
+    Stdin,
+    Stdout,
+    Stderr,
+    Disc { data: Vec<u8>, position: usize },
+    Host(File),
+}
+
+/// ARM semihosting, the debug I/O convention of stock toolchains
+///
+/// This optional feature lets programs linked against newlib's `rdimon` (`--specs=rdimon.specs`)
+/// use `printf`, `fopen`, `exit` and friends without the console's own runtime. Files are
+/// opened on the disc or in a host directory, `:tt` is the host terminal.
+///
+/// Results are returned in R0 the way the specification says, failures are -1 with the
+/// newlib errno available through SYS_ERRNO rather than [crate::syscalls::SyscallError] codes. Open
+/// files aren't part of save states.
+pub struct Semihosting {
+    root: SemihostingRoot,
+    drive: Option<Drive>,
+    cmdline: String,
+    handles: HashMap<u32, Handle>,
+    next_handle: u32,
+    errno: i32,
+    start: Instant,
+    exit_code: Option<i32>,
+}
+
+impl Semihosting {
+    /// `cmdline` is what SYS_GET_CMDLINE returns, the program name first
+    pub fn new(root: SemihostingRoot, cmdline: String) -> Semihosting {
+        Semihosting {
+            root,
+            drive: None,
+            cmdline,
+            handles: HashMap::new(),
+            next_handle: 1,
+            errno: 0,
+            start: Instant::now(),
+            exit_code: None,
+        }
+    }
+
+    fn call(&mut self, em: &mut UnicornHandle) -> SyscallResult {
+        let operation = em.reg_read(RegisterARM::R0 as i32)? as u32;
+        let parameter = em.reg_read(RegisterARM::R1 as i32)?;
+        let result = match self.operation(em, operation, parameter) {
+            Ok(value) => value,
+            Err(errno) => {
+                self.errno = errno;
+                -1
+            }
+        };
+        em.reg_write(RegisterARM::R0 as i32, result as u32 as u64)?;
+        Ok(())
+    }
+
+    fn operation(&mut self, em: &mut UnicornHandle, operation: u32, parameter: u64) -> Result<i32, i32> {
+        match operation {
+            SYS_OPEN => {
+                let name = read_guest_memory(em, argument(em, parameter, 0)? as u64, argument(em, parameter, 2)? as usize)
+                    .map_err(|_| EFAULT)?;
+                let name = String::from_utf8(name).map_err(|_| EINVAL)?;
+                let handle = self.open(name.as_str(), argument(em, parameter, 1)?)?;
+                let number = self.next_handle;
+                self.next_handle += 1;
+                self.handles.insert(number, handle);
+                Ok(number as i32)
+            }
+            SYS_CLOSE => {
+                self.handles.remove(&argument(em, parameter, 0)?).ok_or(EBADF)?;
+                Ok(0)
+            }
+            SYS_WRITEC => {
+                let mut byte = [0u8; 1];
+                em.mem_read(parameter, &mut byte).map_err(|_| EFAULT)?;
+                write_console(&mut std::io::stdout(), &byte)?;
+                Ok(0)
+            }
+            SYS_WRITE0 => {
+                let mut string = Vec::new();
+                let mut address = parameter;
+                loop {
+                    let mut byte = [0u8; 1];
+                    em.mem_read(address, &mut byte).map_err(|_| EFAULT)?;
+                    if byte[0] == 0 {
+                        break;
+                    }
+                    string.push(byte[0]);
+                    address += 1;
+                }
+                write_console(&mut std::io::stdout(), &string)?;
+                Ok(0)
+            }
+            SYS_WRITE => {
+                let length = argument(em, parameter, 2)?;
+                let data = read_guest_memory(em, argument(em, parameter, 1)? as u64, length as usize)
+                    .map_err(|_| EFAULT)?;
+                let handle = self.handles.get_mut(&argument(em, parameter, 0)?).ok_or(EBADF)?;
+                match handle {
+                    Handle::Stdout => write_console(&mut std::io::stdout(), &data)?,
+                    Handle::Stderr => write_console(&mut std::io::stderr(), &data)?,
+                    Handle::Host(file) => file.write_all(&data).map_err(|_| EIO)?,
+                    Handle::Stdin | Handle::Disc { .. } => return Err(EBADF),
+                }
+                // the number of bytes *not* written
+                Ok(0)
+            }
+            SYS_READ => {
+                let handle = argument(em, parameter, 0)?;
+                let mut address = argument(em, parameter, 1)? as u64;
+                let length = argument(em, parameter, 2)? as usize;
+                let handle = self.handles.get_mut(&handle).ok_or(EBADF)?;
+                let mut remaining = length;
+                while remaining > 0 {
+                    let mut chunk = vec![0u8; remaining.min(READ_CHUNK)];
+                    let count = match handle {
+                        Handle::Stdin => std::io::stdin().read(&mut chunk).map_err(|_| EIO)?,
+                        Handle::Host(file) => file.read(&mut chunk).map_err(|_| EIO)?,
+                        Handle::Disc { data, position } => {
+                            let available = data.get(*position..).unwrap_or(&[]);
+                            let count = chunk.len().min(available.len());
+                            chunk[..count].copy_from_slice(&available[..count]);
+                            *position += count;
+                            count
+                        }
+                        Handle::Stdout | Handle::Stderr => return Err(EBADF),
+                    };
+                    if count == 0 {
+                        break;
+                    }
+                    em.mem_write(address, &chunk[..count]).map_err(|_| EFAULT)?;
+                    address += count as u64;
+                    remaining -= count;
+                    if let Handle::Stdin = handle {
+                        // a line at a time, like a terminal
+                        break;
+                    }
+                }
+                // the number of bytes *not* read
+                Ok(remaining as i32)
+            }
+            SYS_ISTTY => match self.handles.get(&argument(em, parameter, 0)?).ok_or(EBADF)? {
+                Handle::Stdin | Handle::Stdout | Handle::Stderr => Ok(1),
+                Handle::Disc { .. } | Handle::Host(_) => Ok(0),
+            },
+            SYS_SEEK => {
+                let position = argument(em, parameter, 1)?;
+                match self.handles.get_mut(&argument(em, parameter, 0)?).ok_or(EBADF)? {
+                    Handle::Disc { position: current, .. } => *current = position as usize,
+                    Handle::Host(file) => { file.seek(SeekFrom::Start(position as u64)).map_err(|_| EIO)?; }
+                    Handle::Stdin | Handle::Stdout | Handle::Stderr => return Err(EINVAL),
+                }
+                Ok(0)
+            }
+            SYS_FLEN => match self.handles.get(&argument(em, parameter, 0)?).ok_or(EBADF)? {
+                Handle::Disc { data, .. } => Ok(data.len() as i32),
+                Handle::Host(file) => Ok(file.metadata().map_err(|_| EIO)?.len() as i32),
+                Handle::Stdin | Handle::Stdout | Handle::Stderr => Err(EINVAL),
+            },
+            SYS_CLOCK => Ok((self.start.elapsed().as_millis() / 10) as i32),
+            SYS_ERRNO => Ok(self.errno),
+            SYS_GET_CMDLINE => {
+                let buffer = argument(em, parameter, 0)? as u64;
+                let size = argument(em, parameter, 1)? as usize;
+                if self.cmdline.len() + 1 > size {
+                    return Err(EINVAL);
+                }
+                let mut cmdline = self.cmdline.clone().into_bytes();
+                cmdline.push(0);
+                em.mem_write(buffer, &cmdline).map_err(|_| EFAULT)?;
+                em.mem_write(parameter + 4, &(self.cmdline.len() as u32).to_le_bytes()).map_err(|_| EFAULT)?;
+                Ok(0)
+            }
+            SYS_HEAPINFO => {
+                // all zeroes: the C runtime keeps its linker defined heap and the stack it was given
+                let block = argument(em, parameter, 0)? as u64;
+                em.mem_write(block, &[0u8; 16]).map_err(|_| EFAULT)?;
+                Ok(0)
+            }
+            SYS_EXIT => {
+                self.exit_code = Some(if parameter == ADP_STOPPED_APPLICATION_EXIT { 0 } else { 1 });
+                em.emu_stop().map_err(|_| EIO)?;
+                Ok(0)
+            }
+            _ => Err(ENOSYS),
+        }
+    }
+
+    /// Opens `name` with one of the 12 fopen modes of the specification, `r` through `a+b`
+    fn open(&mut self, name: &str, mode: u32) -> Result<Handle, i32> {
+        if name == ":tt" {
+            return match mode {
+                0..=3 => Ok(Handle::Stdin),
+                4..=7 => Ok(Handle::Stdout),
+                8..=11 => Ok(Handle::Stderr),
+                _ => Err(EINVAL),
+            };
+        }
+        match &self.root {
+            SemihostingRoot::Disc(..) => {
+                if mode > 1 {
+                    return Err(EACCES);
+                }
+                let drive = self.drive.as_ref().ok_or(ENOENT)?;
+                let data = drive.read_file(name).map_err(|_| ENOENT)?;
+                Ok(Handle::Disc { data, position: 0 })
+            }
+            SemihostingRoot::Directory(root) => {
+                let path = sandboxed(root, name)?;
+                let mut options = OpenOptions::new();
+                match mode {
+                    0..=1 => options.read(true),
+                    2..=3 => options.read(true).write(true),
+                    4..=5 => options.write(true).create(true).truncate(true),
+                    6..=7 => options.read(true).write(true).create(true).truncate(true),
+                    8..=9 => options.append(true).create(true),
+                    10..=11 => options.read(true).append(true).create(true),
+                    _ => return Err(EINVAL),
+                };
+                let file = options.open(path).map_err(|e| match e.kind() {
+                    std::io::ErrorKind::NotFound => ENOENT,
+                    std::io::ErrorKind::PermissionDenied => EACCES,
+                    _ => EIO,
+                })?;
+                Ok(Handle::Host(file))
+            }
+        }
+    }
+}
+
+/// Word `index` of the parameter block at `block`
+fn argument(em: &UnicornHandle, block: u64, index: u64) -> Result<u32, i32> {
+    let mut word = [0u8; 4];
+    em.mem_read(block + index * 4, &mut word).map_err(|_| EFAULT)?;
+    Ok(u32::from_le_bytes(word))
+}
+
+fn write_console(stream: &mut dyn Write, data: &[u8]) -> Result<(), i32> {
+    stream.write_all(data).and_then(|_| stream.flush()).map_err(|_| EIO)
+}
+
+/// `name` inside `root`
+///
+/// Fails with `EACCES` if it is absolute, goes through `..`, its directory resolves (through
+/// symlinks) to somewhere outside of `root`, or it is a symlink itself: opening one could
+/// create or write its target wherever it points. Fails with `ENOENT` if its directory
+/// doesn't exist.
+fn sandboxed(root: &Path, name: &str) -> Result<PathBuf, i32> {
+    let mut path = root.to_path_buf();
+    for component in Path::new(name).components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => return Err(EACCES),
+        }
+    }
+    let (parent, file_name) = match (path.parent(), path.file_name()) {
+        (Some(parent), Some(file_name)) => (parent, file_name),
+        _ => return Err(EACCES),
+    };
+    let parent = parent.canonicalize().map_err(|e| match e.kind() {
+        std::io::ErrorKind::NotFound => ENOENT,
+        _ => EACCES,
+    })?;
+    if !parent.starts_with(root) {
+        return Err(EACCES);
+    }
+    let path = parent.join(file_name);
+    match fs::symlink_metadata(&path) {
+        Ok(metadata) if metadata.file_type().is_symlink() => Err(EACCES),
+        _ => Ok(path),
+    }
+}
+
+/// | Instruction | R0 | R1 | Description |
+/// | ----------- | -- | -- | ----------- |
+/// | `svc #0x123456` (ARM), `svc #0xab` (Thumb), `bkpt #0xab` | operation | parameter or parameter block | See the table below |
+///
+/// | Operation | Name | Parameters | Result |
+/// | --------- | ---- | ---------- | ------ |
+/// | 0x01 | SYS_OPEN | [char*: path, int: mode, int: path length] | Handle, `:tt` is the terminal |
+/// | 0x02 | SYS_CLOSE | [int: handle] | 0 |
+/// | 0x03 | SYS_WRITEC | char*: character | Writes the character to stdout |
+/// | 0x04 | SYS_WRITE0 | char*: string | Writes the NUL-terminated string to stdout |
+/// | 0x05 | SYS_WRITE | [int: handle, void*: data, int: length] | Bytes not written |
+/// | 0x06 | SYS_READ | [int: handle, void*: buffer, int: length] | Bytes not read |
+/// | 0x09 | SYS_ISTTY | [int: handle] | 1 for the terminal, 0 for files |
+/// | 0x0A | SYS_SEEK | [int: handle, int: position] | 0 |
+/// | 0x0C | SYS_FLEN | [int: handle] | File length |
+/// | 0x10 | SYS_CLOCK | None | Centiseconds since the machine started |
+/// | 0x13 | SYS_ERRNO | None | errno of the last failed operation |
+/// | 0x15 | SYS_GET_CMDLINE | [char*: buffer, int: buffer size] | 0, the length is written back to the block |
+/// | 0x16 | SYS_HEAPINFO | [void*: 4 words] | 0, the words are zeroed |
+/// | 0x18 | SYS_EXIT | int: reason | Stops the emulator, exiting with 0 for ADP_Stopped_ApplicationExit and 1 otherwise |
+///
+/// Failures return -1. Files on the disc can only be opened for reading.
+impl EmulatorFeature for Semihosting {
+    fn init(&mut self, _emulator: &mut UnicornHandle, syscalls: &mut SyscallDispatcher) -> Result<(), EmulatorError> {
+        match &mut self.root {
+            SemihostingRoot::Disc(path, layers) => self.drive = Some(Drive::with_layers(path, layers)?),
+            SemihostingRoot::Directory(root) => *root = root.canonicalize()?,
+        }
+
+        let semihosting: *mut Semihosting = self;
+        for instruction in TRAPS {
+            syscalls.register_trap(&self.name(), instruction, move |emu| unsafe {
+                (*semihosting).call(emu)
+            })?;
+        }
+        Ok(())
+    }
+
+    fn stop(&mut self, emulator: &mut UnicornHandle) -> Result<(), EmulatorError> {
+        self.reset(emulator)
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn name(&self) -> String {
+        String::from("Semihosting")
+    }
+
+    /// Closes every handle
+    fn reset(&mut self, _emulator: &mut UnicornHandle) -> Result<(), EmulatorError> {
+        self.handles.clear();
+        self.next_handle = 1;
+        self.errno = 0;
+        self.start = Instant::now();
+        self.exit_code = None;
+        Ok(())
+    }
+
+    fn on_frame(&mut self, _emulator: &mut UnicornHandle, requests: &mut FrameRequests) -> Result<(), EmulatorError> {
+        requests.exit_code = requests.exit_code.or(self.exit_code);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An empty directory with a `sub` directory in it, and another one outside of it
+    fn directories(name: &str) -> (PathBuf, PathBuf) {
+        let base = std::env::temp_dir().join(format!("armchine-semihosting-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&base);
+        fs::create_dir_all(base.join("root").join("sub")).unwrap();
+        fs::create_dir_all(base.join("outside")).unwrap();
+        let base = base.canonicalize().unwrap();
+        (base.join("root"), base.join("outside"))
+    }
+
+    #[test]
+    fn paths_stay_inside() {
+        let (root, _) = directories("inside");
+        assert_eq!(sandboxed(&root, "new.txt"), Ok(root.join("new.txt")));
+        assert_eq!(sandboxed(&root, "./sub/new.txt"), Ok(root.join("sub").join("new.txt")));
+        assert_eq!(sandboxed(&root, "../new.txt"), Err(EACCES));
+        assert_eq!(sandboxed(&root, "sub/../../new.txt"), Err(EACCES));
+        assert_eq!(sandboxed(&root, "/etc/passwd"), Err(EACCES));
+        assert_eq!(sandboxed(&root, "missing/new.txt"), Err(ENOENT));
+        fs::remove_dir_all(root.parent().unwrap()).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_stay_inside() {
+        use std::os::unix::fs::symlink;
+        let (root, outside) = directories("symlinks");
+        symlink(&outside, root.join("out")).unwrap();
+        symlink(outside.join("file"), root.join("dangling")).unwrap();
+        fs::write(root.join("sub").join("file"), b"").unwrap();
+        symlink(root.join("sub").join("file"), root.join("inner")).unwrap();
+
+        assert_eq!(sandboxed(&root, "out/new.txt"), Err(EACCES));
+        assert_eq!(sandboxed(&root, "out"), Err(EACCES));
+        // would create the file outside of the root
+        assert_eq!(sandboxed(&root, "dangling"), Err(EACCES));
+        assert_eq!(sandboxed(&root, "inner"), Err(EACCES));
+        fs::remove_dir_all(root.parent().unwrap()).unwrap();
+    }
+}
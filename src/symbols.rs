@@ -0,0 +1,79 @@
+use xmas_elf::ElfFile;
+use xmas_elf::sections::SectionData;
+use xmas_elf::symbol_table::{Entry, Type};
+
+pub struct Symbol {
+    pub name: String,
+    pub address: u64,
+    pub size: u64,
+    pub is_function: bool,
+}
+
+/// Symbols from the executable's `.symtab`, sorted by address
+///
+/// Empty for stripped executables, every lookup then just fails.
+pub struct SymbolTable {
+    symbols: Vec<Symbol>,
+}
+
+impl SymbolTable {
+    pub fn empty() -> SymbolTable {
+        SymbolTable { symbols: Vec::new() }
+    }
+
+    pub fn from_elf(elf_file: &ElfFile) -> SymbolTable {
+        let mut symbols = Vec::new();
+        let data = elf_file.find_section_by_name(".symtab")
+            .and_then(|header| header.get_data(elf_file).ok());
+
+        if let Some(SectionData::SymbolTable32(entries)) = data {
+            for entry in entries {
+                let name = match entry.get_name(elf_file) {
+                    Ok(name) if !name.is_empty() && !name.starts_with('$') => name,
+                    _ => continue,
+                };
+                let is_function = matches!(entry.get_type(), Ok(Type::Func));
+                if !is_function && !matches!(entry.get_type(), Ok(Type::Object) | Ok(Type::NoType)) {
+                    continue;
+                }
+                // the low bit of a function address only marks it as Thumb code
+                let address = if is_function { entry.value() & !1 } else { entry.value() };
+                symbols.push(Symbol {
+                    name: name.to_string(),
+                    address,
+                    size: entry.size(),
+                    is_function,
+                });
+            }
+        }
+        symbols.sort_by_key(|s| s.address);
+        SymbolTable { symbols }
+    }
+
+    pub fn find(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|s| s.name == name)
+    }
+
+    pub fn functions(&self) -> impl Iterator<Item=&Symbol> {
+        self.symbols.iter().filter(|s| s.is_function)
+    }
+
+    /// Finds the symbol containing `address`, returning it along with the offset into it
+    ///
+    /// Symbols without a size are assumed to extend up to the next symbol.
+    pub fn lookup(&self, address: u64) -> Option<(&Symbol, u64)> {
+        let index = self.symbols.partition_point(|s| s.address <= address);
+        self.symbols[..index].iter().rev()
+            .find(|s| s.size == 0 || address < s.address + s.size)
+            .map(|s| (s, address - s.address))
+    }
+
+    /// Formats `address` as `0x1234 <symbol+0x10>`, or just the address when it has no symbol
+    pub fn describe(&self, address: u64) -> String {
+        match self.lookup(address) {
+            Some((symbol, 0)) => format!("{:#x} <{}>", address, symbol.name),
+            Some((symbol, offset)) => format!("{:#x} <{}+{:#x}>", address, symbol.name, offset),
+            None => format!("{:#x}", address),
+        }
+    }
+}
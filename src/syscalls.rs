@@ -0,0 +1,400 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::ops::Range;
+use std::ptr::null_mut;
+use std::rc::Rc;
+use unicorn::{RegisterARM, UnicornHandle};
+use unicorn::ffi::uc_hook;
+use unicorn::unicorn_const::uc_error;
+use serde::Deserialize;
+use crate::error::EmulatorError;
+
+/// Failed syscalls, returned to the guest as a negative code in R0
+///
+/// The codes are listed on [crate::features::EmulatorFeature].
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SyscallError {
+    /// No feature handles the syscall
+    Unknown,
+    /// An argument is out of range, e.g. a file index past the listing
+    InvalidArgument,
+    /// A pointer argument points to unmapped memory
+    BadAddress,
+    /// The path doesn't exist on the drive
+    NotFound,
+    /// A string argument isn't valid UTF-8
+    InvalidString,
+    /// The drive couldn't be read
+    Io,
+    /// There's no room left to map an allocation
+    OutOfMemory,
+    /// The handle isn't open
+    BadHandle,
+    /// The drive tray is open
+    NoDisc,
+    /// Another disc was inserted and the guest hasn't checked the drive status yet
+    DiscChanged,
+}
+
+impl SyscallError {
+    pub fn code(&self) -> i32 {
+        match self {
+            SyscallError::Unknown => -1,
+            SyscallError::InvalidArgument => -2,
+            SyscallError::BadAddress => -3,
+            SyscallError::NotFound => -4,
+            SyscallError::InvalidString => -5,
+            SyscallError::Io => -6,
+            SyscallError::OutOfMemory => -7,
+            SyscallError::BadHandle => -8,
+            SyscallError::NoDisc => -9,
+            SyscallError::DiscChanged => -10,
+        }
+    }
+}
+
+impl From<uc_error> for SyscallError {
+    fn from(error: uc_error) -> Self {
+        match error {
+            uc_error::NOMEM => SyscallError::OutOfMemory,
+            _ => SyscallError::BadAddress,
+        }
+    }
+}
+
+impl From<EmulatorError> for SyscallError {
+    fn from(error: EmulatorError) -> Self {
+        match error {
+            EmulatorError::FileNotFound(_) => SyscallError::NotFound,
+            EmulatorError::Unicorn(error) => error.into(),
+            _ => SyscallError::Io,
+        }
+    }
+}
+
+pub type SyscallResult = Result<(), SyscallError>;
+
+/// Reads `size` bytes of guest memory, checking the whole range is mapped before allocating
+/// anything so a bogus size from the guest can't exhaust host memory
+pub fn read_guest_memory(emu: &UnicornHandle, address: u64, size: usize) -> Result<Vec<u8>, SyscallError> {
+    let end = address.checked_add(size as u64).ok_or(SyscallError::BadAddress)?;
+    let regions = emu.mem_regions()?;
+    let mut covered = address;
+    while covered < end {
+        match regions.iter().find(|r| r.begin <= covered && covered <= r.end) {
+            Some(region) => covered = region.end + 1,
+            None => return Err(SyscallError::BadAddress),
+        }
+    }
+    Ok(emu.mem_read_as_vec(address, size)?)
+}
+
+/// Reads the NUL-terminated UTF-8 string at `address`
+pub fn read_guest_string(emu: &UnicornHandle, mut address: u64) -> Result<String, SyscallError> {
+    let mut string = Vec::new();
+    loop {
+        let mut byte = [0u8; 1];
+        emu.mem_read(address, &mut byte)?;
+        if byte[0] == 0 {
+            break;
+        }
+        string.push(byte[0]);
+        address += 1;
+    }
+    String::from_utf8(string).map_err(|_| SyscallError::InvalidString)
+}
+
+/// What the dispatcher does with syscalls no feature has registered
+#[derive(Debug, Copy, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UnknownSyscall {
+    /// Return [SyscallError::Unknown] in R0 and keep running
+    Error,
+    /// Stop the emulator, the main loop treats it as a guest crash
+    Trap,
+}
+
+/// Unicorn interrupt numbers, from qemu's target-arm/cpu.h
+pub(crate) const EXCP_SWI: u32 = 2;
+pub(crate) const EXCP_BKPT: u32 = 7;
+/// CPSR bit set while executing Thumb code
+const CPSR_THUMB: u64 = 1 << 5;
+
+/// An SVC or BKPT instruction with a specific immediate, see [SyscallDispatcher::register_trap]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum TrapInstruction {
+    Svc(u32),
+    Bkpt(u32),
+}
+
+impl fmt::Display for TrapInstruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrapInstruction::Svc(immediate) => write!(f, "svc #{:#x}", immediate),
+            TrapInstruction::Bkpt(immediate) => write!(f, "bkpt #{:#x}", immediate),
+        }
+    }
+}
+
+/// Decodes the instruction that raised interrupt `intno` and its size
+///
+/// Unicorn reports SVCs with PC already past the instruction, but BKPTs with PC still on it.
+fn decode_trap(em: &UnicornHandle, intno: u32) -> Option<(TrapInstruction, u64)> {
+    let pc = em.reg_read(RegisterARM::PC as i32).ok()?;
+    let thumb = em.reg_read(RegisterARM::CPSR as i32).ok()? & CPSR_THUMB != 0;
+    let size = if thumb { 2 } else { 4 };
+    let address = if intno == EXCP_SWI { pc.checked_sub(size)? } else { pc };
+    decode_trap_at(em, intno, address, thumb).map(|instruction| (instruction, size))
+}
+
+/// Decodes the SVC (`intno` [EXCP_SWI]) or BKPT ([EXCP_BKPT]) instruction at `address`
+pub(crate) fn decode_trap_at(em: &UnicornHandle, intno: u32, address: u64, thumb: bool) -> Option<TrapInstruction> {
+    let read = |size: usize| {
+        let mut bytes = [0u8; 4];
+        em.mem_read(address, &mut bytes[..size]).ok().map(|_| u32::from_le_bytes(bytes))
+    };
+    match (intno, thumb) {
+        (EXCP_SWI, false) => Some(TrapInstruction::Svc(read(4)? & 0xFF_FFFF)),
+        (EXCP_SWI, true) => Some(TrapInstruction::Svc(read(2)? & 0xFF)),
+        (EXCP_BKPT, false) => {
+            let word = read(4)?;
+            Some(TrapInstruction::Bkpt((word >> 4) & 0xFFF0 | word & 0xF))
+        }
+        (EXCP_BKPT, true) => Some(TrapInstruction::Bkpt(read(2)? & 0xFF)),
+        _ => None,
+    }
+}
+
+struct Registration {
+    owner: String,
+    range: Range<u32>,
+    handler: Box<dyn FnMut(&mut UnicornHandle, u32) -> SyscallResult>,
+}
+
+struct TrapRegistration {
+    owner: String,
+    instruction: TrapInstruction,
+    handler: Box<dyn FnMut(&mut UnicornHandle) -> SyscallResult>,
+}
+
+struct DispatcherState {
+    registrations: Vec<Registration>,
+    traps: Vec<TrapRegistration>,
+    unknown: UnknownSyscall,
+    trapped: Option<String>,
+}
+
+impl DispatcherState {
+    /// Stops the emulator, the main loop reports `reason` as a guest crash
+    fn trap(&mut self, em: &mut UnicornHandle, reason: String) {
+        self.trapped = Some(reason);
+        em.emu_stop().unwrap();
+    }
+}
+
+/// Owner of the single interrupt hook
+///
+/// Features register a handler for each range of syscall numbers they reserve (see the table on
+/// [crate::features::EmulatorFeature]) and the dispatcher calls the one whose range contains R7.
+/// Overlapping ranges are rejected when they are registered. Handlers write their results to R0
+/// themselves, errors they return are written to R0 by the dispatcher as [SyscallError::code].
+///
+/// Syscalls don't stop the emulator, handlers that need the host to act (presenting a frame,
+/// exiting) call `emu_stop` themselves.
+///
+/// Conventions that select the call by the SVC or BKPT immediate instead of R7 (e.g. ARM
+/// semihosting) register those instructions with [SyscallDispatcher::register_trap]. Any other
+/// SVC is a syscall, any other BKPT or interrupt stops the emulator.
+pub struct SyscallDispatcher {
+    state: Rc<RefCell<DispatcherState>>,
+    hook: uc_hook,
+}
+
+impl SyscallDispatcher {
+    pub fn new(unknown: UnknownSyscall) -> SyscallDispatcher {
+        SyscallDispatcher {
+            state: Rc::new(RefCell::new(DispatcherState {
+                registrations: Vec::new(),
+                traps: Vec::new(),
+                unknown,
+                trapped: None,
+            })),
+            hook: null_mut(),
+        }
+    }
+
+    /// Registers `handler` for the syscalls in `range`. The handler gets the syscall number
+    pub fn register<F: 'static>(&mut self, owner: &str, range: Range<u32>, handler: F) -> Result<(), EmulatorError>
+        where F: FnMut(&mut UnicornHandle, u32) -> SyscallResult
+    {
+        let mut state = self.state.borrow_mut();
+        let overlapping = state.registrations.iter()
+            .find(|r| r.range.start < range.end && range.start < r.range.end);
+        if let Some(other) = overlapping {
+            return Err(EmulatorError::SyscallConflict {
+                owner: owner.to_string(),
+                range,
+                other: other.owner.clone(),
+                other_range: other.range.clone(),
+            });
+        }
+        state.registrations.push(Registration {
+            owner: owner.to_string(),
+            range,
+            handler: Box::new(handler),
+        });
+        Ok(())
+    }
+
+    /// Registers `handler` for every `instruction`, whatever R7 holds
+    ///
+    /// PC is moved past BKPT instructions after the handler ran, like it already is for SVCs.
+    pub fn register_trap<F: 'static>(&mut self, owner: &str, instruction: TrapInstruction, handler: F) -> Result<(), EmulatorError>
+        where F: FnMut(&mut UnicornHandle) -> SyscallResult
+    {
+        let mut state = self.state.borrow_mut();
+        if let Some(other) = state.traps.iter().find(|t| t.instruction == instruction) {
+            return Err(EmulatorError::TrapConflict {
+                owner: owner.to_string(),
+                instruction,
+                other: other.owner.clone(),
+            });
+        }
+        state.traps.push(TrapRegistration {
+            owner: owner.to_string(),
+            instruction,
+            handler: Box::new(handler),
+        });
+        Ok(())
+    }
+
+    /// Registered ranges and the feature owning each of them
+    pub fn ranges(&self) -> Vec<(Range<u32>, String)> {
+        self.state.borrow().registrations.iter()
+            .map(|r| (r.range.clone(), r.owner.clone()))
+            .collect()
+    }
+
+    /// Registered trap instructions and the feature owning each of them
+    pub fn traps(&self) -> Vec<(TrapInstruction, String)> {
+        self.state.borrow().traps.iter()
+            .map(|t| (t.instruction, t.owner.clone()))
+            .collect()
+    }
+
+    pub fn install(&mut self, emulator: &mut UnicornHandle) -> Result<(), EmulatorError> {
+        let state = self.state.clone();
+        self.hook = emulator.add_intr_hook(move |mut em, intno| {
+            let state = &mut *state.borrow_mut();
+
+            let trap = decode_trap(&em, intno);
+            let registered = trap.and_then(|(instruction, _)| {
+                state.traps.iter().position(|t| t.instruction == instruction)
+            });
+            let result = match (trap, registered) {
+                (Some((instruction, size)), Some(index)) => {
+                    let result = (state.traps[index].handler)(&mut em);
+                    if let TrapInstruction::Bkpt(_) = instruction {
+                        let pc = em.reg_read(RegisterARM::PC as i32).unwrap();
+                        em.reg_write(RegisterARM::PC as i32, pc + size).unwrap();
+                    }
+                    result
+                }
+                (Some((TrapInstruction::Svc(_), _)), None) => {
+                    let syscall = em.reg_read_i32(RegisterARM::R7 as i32).unwrap() as u32;
+                    match state.registrations.iter_mut().find(|r| r.range.contains(&syscall)) {
+                        Some(registration) => (registration.handler)(&mut em, syscall),
+                        None if state.unknown == UnknownSyscall::Trap => {
+                            state.trap(&mut em, format!("unknown syscall {:#x}", syscall));
+                            Ok(())
+                        }
+                        None => Err(SyscallError::Unknown),
+                    }
+                }
+                (Some((instruction, _)), None) => {
+                    state.trap(&mut em, format!("unhandled {}", instruction));
+                    Ok(())
+                }
+                (None, _) => {
+                    state.trap(&mut em, format!("unhandled interrupt {}", intno));
+                    Ok(())
+                }
+            };
+            if let Err(error) = result {
+                em.reg_write(RegisterARM::R0 as i32, error.code() as u32 as u64).unwrap();
+            }
+        })?;
+        Ok(())
+    }
+
+    /// Why the dispatcher stopped the emulator: an unknown syscall if [UnknownSyscall::Trap] is
+    /// configured, or an instruction nothing handles
+    pub fn take_trap(&self) -> Option<String> {
+        self.state.borrow_mut().trapped.take()
+    }
+
+    pub fn stop(&mut self, emulator: &mut UnicornHandle) -> Result<(), EmulatorError> {
+        let r = emulator.remove_hook(self.hook);
+        self.hook = null_mut();
+        Ok(r?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use unicorn::unicorn_const::Permission;
+    use crate::emulator;
+    use super::*;
+
+    #[test]
+    fn overlapping_ranges_conflict() {
+        let mut syscalls = SyscallDispatcher::new(UnknownSyscall::Error);
+        syscalls.register("first", 0x0..0x10, |_, _| Ok(())).unwrap();
+        let conflict = syscalls.register("second", 0x8..0x18, |_, _| Ok(()));
+        assert!(matches!(conflict, Err(EmulatorError::SyscallConflict { other, .. }) if other == "first"));
+        assert!(syscalls.register("inside", 0x4..0x5, |_, _| Ok(())).is_err());
+        // touching ranges don't overlap
+        syscalls.register("third", 0x10..0x20, |_, _| Ok(())).unwrap();
+        assert_eq!(syscalls.ranges(), vec![(0x0..0x10, String::from("first")), (0x10..0x20, String::from("third"))]);
+    }
+
+    #[test]
+    fn duplicate_traps_conflict() {
+        let mut syscalls = SyscallDispatcher::new(UnknownSyscall::Error);
+        syscalls.register_trap("first", TrapInstruction::Svc(0xAB), |_| Ok(())).unwrap();
+        let conflict = syscalls.register_trap("second", TrapInstruction::Svc(0xAB), |_| Ok(()));
+        assert!(matches!(conflict, Err(EmulatorError::TrapConflict { other, .. }) if other == "first"));
+        // same immediate, different instruction
+        syscalls.register_trap("second", TrapInstruction::Bkpt(0xAB), |_| Ok(())).unwrap();
+        assert_eq!(syscalls.traps(), vec![(TrapInstruction::Svc(0xAB), String::from("first")), (TrapInstruction::Bkpt(0xAB), String::from("second"))]);
+    }
+
+    #[test]
+    fn decodes_svc_before_pc() {
+        let mut unicorn = emulator::create_emulator();
+        let mut emu = unicorn.borrow();
+        emu.mem_map(0, 0x1000, Permission::ALL).unwrap();
+        emu.mem_write(0x100, &0xEF12_3456u32.to_le_bytes()).unwrap();
+        emu.reg_write(RegisterARM::PC as i32, 0x104).unwrap();
+        assert_eq!(decode_trap(&emu, EXCP_SWI), Some((TrapInstruction::Svc(0x12_3456), 4)));
+    }
+
+    #[test]
+    fn svc_at_pc_zero() {
+        let mut unicorn = emulator::create_emulator();
+        let mut emu = unicorn.borrow();
+        emu.mem_map(0, 0x1000, Permission::ALL).unwrap();
+        emu.reg_write(RegisterARM::PC as i32, 0).unwrap();
+        assert_eq!(decode_trap(&emu, EXCP_SWI), None);
+    }
+
+    #[test]
+    fn decodes_bkpt_at_address() {
+        let mut unicorn = emulator::create_emulator();
+        let mut emu = unicorn.borrow();
+        emu.mem_map(0, 0x1000, Permission::ALL).unwrap();
+        // bkpt #0xab
+        emu.mem_write(0x200, &0xE120_0A7Bu32.to_le_bytes()).unwrap();
+        assert_eq!(decode_trap_at(&emu, EXCP_BKPT, 0x200, false), Some(TrapInstruction::Bkpt(0xAB)));
+    }
+}
@@ -0,0 +1,74 @@
+use std::any::Any;
+use std::ops::Range;
+use unicorn::{RegisterARM, UnicornHandle};
+use crate::features::{EmulatorFeature, FeatureDescriptor, FrameRequests};
+use crate::error::EmulatorError;
+use crate::newlib;
+use crate::syscalls::{SyscallDispatcher, SyscallError};
+
+const SYSCALLS: Range<u32> = 0x100..0x110;
+
+/// Guest control over the emulator itself
+///
+/// This feature provides syscalls that let the emulated system stop the emulator
+pub struct SystemControl {
+    exit_code: Option<i32>,
+}
+
+impl SystemControl {
+    pub fn new() -> SystemControl {
+        SystemControl {
+            exit_code: None,
+        }
+    }
+}
+
+/// | Syscall | Parameters | Description |
+/// | ------- | ---------- | ----------- |
+/// | 0x100 | int: exit code | Stops the emulator. The exit code becomes the exit status of the emulator process |
+/// | 0x200 | int: exit code | newlib `_exit`, same as 0x100 |
+impl EmulatorFeature for SystemControl {
+    fn init(&mut self, _emulator: &mut UnicornHandle, syscalls: &mut SyscallDispatcher) -> Result<(), EmulatorError> {
+        let sysptr: *mut SystemControl = self;
+
+        let exit = move |emu: &mut UnicornHandle, syscall: u32| unsafe {
+            match syscall {
+                0x100 | newlib::EXIT => {
+                    let code = emu.reg_read_i32(RegisterARM::R1 as i32)?;
+                    (*sysptr).exit_code = Some(code);
+                    emu.emu_stop()?;
+                    Ok(())
+                }
+                _ => Err(SyscallError::Unknown),
+            }
+        };
+        syscalls.register(&self.name(), SYSCALLS, exit)?;
+        syscalls.register(&self.name(), newlib::syscall(newlib::EXIT), exit)
+    }
+
+    fn stop(&mut self, _emulator: &mut UnicornHandle) -> Result<(), EmulatorError> {
+        Ok(())
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn name(&self) -> String {
+        String::from("SystemControl")
+    }
+
+    fn descriptor(&self) -> FeatureDescriptor {
+        FeatureDescriptor { syscalls: vec![SYSCALLS, newlib::syscall(newlib::EXIT)], mmio: Vec::new() }
+    }
+
+    fn reset(&mut self, _emulator: &mut UnicornHandle) -> Result<(), EmulatorError> {
+        self.exit_code = None;
+        Ok(())
+    }
+
+    fn on_frame(&mut self, _emulator: &mut UnicornHandle, requests: &mut FrameRequests) -> Result<(), EmulatorError> {
+        requests.exit_code = requests.exit_code.or(self.exit_code);
+        Ok(())
+    }
+}
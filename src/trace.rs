@@ -0,0 +1,151 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::rc::Rc;
+use capstone::Capstone;
+use unicorn::UnicornHandle;
+use unicorn::ffi::uc_hook;
+use crate::emulator;
+use crate::emulator::CORE_REGISTERS;
+use crate::symbols::SymbolTable;
+
+/// Parses a `start-end` address range, both ends in hex with an optional 0x prefix
+pub fn parse_range(range: &str) -> Result<(u64, u64), String> {
+    let parse = |value: &str| u64::from_str_radix(value.trim_start_matches("0x"), 16)
+        .map_err(|e| format!("invalid address {} in range {}: {}", value, range, e));
+    match range.split_once('-') {
+        Some((start, end)) => Ok((parse(start)?, parse(end)?)),
+        None => Err(format!("expected start-end, got {}", range)),
+    }
+}
+
+/// Turns the `--trace-range` and `--trace-function` arguments into address ranges
+///
+/// An empty result means everything is traced.
+pub fn filter_ranges(ranges: &[String], functions: &[String], symbols: &SymbolTable) -> Result<Vec<(u64, u64)>, String> {
+    let mut filter = Vec::new();
+    for range in ranges {
+        filter.push(parse_range(range)?);
+    }
+    for function in functions {
+        match symbols.find(function) {
+            Some(symbol) => filter.push((symbol.address, symbol.address + symbol.size.max(1))),
+            None => return Err(format!("no symbol named {}", function)),
+        }
+    }
+    Ok(filter)
+}
+
+fn in_filter(filter: &[(u64, u64)], address: u64) -> bool {
+    filter.is_empty() || filter.iter().any(|(start, end)| *start <= address && address < *end)
+}
+
+/// State for instruction traces
+///
+/// The code hook runs before an instruction executes, so each line is kept pending until
+/// the next hook can tell which registers the instruction changed.
+struct InstructionTrace {
+    output: BufWriter<File>,
+    symbols: Rc<SymbolTable>,
+    disassembler: Capstone,
+    disassembly: HashMap<u64, String>,
+    filter: Vec<(u64, u64)>,
+    registers: [u64; 17],
+    pending: Option<String>,
+}
+
+impl InstructionTrace {
+    fn read_registers(emu: &UnicornHandle) -> [u64; 17] {
+        let mut registers = [0u64; 17];
+        for (value, (_, register)) in registers.iter_mut().zip(CORE_REGISTERS) {
+            *value = emu.reg_read(register as i32).unwrap_or(0);
+        }
+        registers
+    }
+
+    fn disassemble(&mut self, emu: &UnicornHandle, address: u64, size: u32) -> String {
+        if let Some(text) = self.disassembly.get(&address) {
+            return text.clone();
+        }
+        let text = emu.mem_read_as_vec(address, size as usize).ok()
+            .and_then(|code| self.disassembler.disasm_count(code.as_slice(), address, 1).ok())
+            .and_then(|instructions| instructions.iter().next()
+                .map(|i| format!("{} {}", i.mnemonic().unwrap_or(""), i.op_str().unwrap_or(""))))
+            .unwrap_or_else(|| String::from("(bad)"));
+        self.disassembly.insert(address, text.clone());
+        text
+    }
+
+    fn on_instruction(&mut self, emu: &UnicornHandle, address: u64, size: u32) {
+        if let Some(line) = self.pending.take() {
+            let registers = Self::read_registers(emu);
+            let changes: Vec<String> = CORE_REGISTERS.iter().zip(registers.iter().zip(self.registers.iter()))
+                .filter(|((name, _), (new, old))| new != old && *name != "pc")
+                .map(|((name, _), (new, _))| format!("{}={:#x}", name, new))
+                .collect();
+            writeln!(self.output, "{:<64} ; {}", line, changes.join(" ")).unwrap();
+        }
+
+        if in_filter(&self.filter, address) {
+            self.registers = Self::read_registers(emu);
+            let text = self.disassemble(emu, address, size);
+            self.pending = Some(format!("{:<32} {}", self.symbols.describe(address), text));
+        }
+    }
+}
+
+impl Drop for InstructionTrace {
+    fn drop(&mut self) {
+        if let Some(line) = self.pending.take() {
+            writeln!(self.output, "{}", line).unwrap();
+        }
+        self.output.flush().unwrap();
+    }
+}
+
+/// Execution trace recorder
+///
+/// Logs every executed instruction (address, symbol+offset, disassembly and the registers it
+/// changed), or just every executed basic block, which is much cheaper. Traces can be limited
+/// to address ranges and functions.
+pub struct Tracer {
+    hook: uc_hook,
+}
+
+impl Tracer {
+    pub fn install(emu: &mut UnicornHandle, path: &Path, blocks: bool, filter: Vec<(u64, u64)>, symbols: Rc<SymbolTable>) -> Result<Tracer, String> {
+        let mut output = BufWriter::new(File::create(path).map_err(|e| format!("{}", e))?);
+
+        let hook = if blocks {
+            emu.add_block_hook(move |_emu, address, size| {
+                if in_filter(&filter, address) {
+                    writeln!(output, "{:<32} {} bytes", symbols.describe(address), size).unwrap();
+                }
+            })
+        } else {
+            let mut trace = InstructionTrace {
+                output,
+                symbols,
+                disassembler: emulator::create_disassembler(),
+                disassembly: HashMap::new(),
+                filter,
+                registers: [0u64; 17],
+                pending: None,
+            };
+            emu.add_code_hook(1, 0, move |emu, address, size| {
+                trace.on_instruction(&emu, address, size);
+            })
+        };
+
+        match hook {
+            Ok(hook) => Ok(Tracer { hook }),
+            Err(err) => Err(format!("{:?}", err)),
+        }
+    }
+
+    /// Removes the hook, which also flushes the trace file
+    pub fn stop(&mut self, emu: &mut UnicornHandle) {
+        emu.remove_hook(self.hook).unwrap();
+    }
+}
@@ -155,7 +155,7 @@ pub extern "C" fn mem_hook_proxy(
     size: u32,
     value: i64,
     user_data: *mut MemHook,
-) {
+) -> bool {
     let unicorn = unsafe { &mut *(*user_data).unicorn };
     let callback = &mut unsafe { &mut *(*user_data).callback };
     assert_eq!(uc, unicorn.uc);
@@ -168,6 +168,9 @@ pub extern "C" fn mem_hook_proxy(
         size as usize,
         value,
     );
+    // Unicorn reads the result of invalid memory hooks as "handled"; callbacks can't map
+    // the missing memory from here, so always let the access fail
+    false
 }
 
 pub extern "C" fn intr_hook_proxy(uc: uc_handle, value: u32, user_data: *mut InterruptHook) {